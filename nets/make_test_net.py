@@ -0,0 +1,19 @@
+#!/usr/bin/env python3
+# Writes the tiny randomly initialized network used to check the NNUE code (see src/eval/nnue.rs for
+# the file layout). It plays terribly; it only exists to test loading and inference.
+#   usage: make_test_net.py [output] [hidden size] [seed]
+import random, struct, sys
+
+out = sys.argv[1] if len(sys.argv) > 1 else "test-768x16.nnue"
+hidden = int(sys.argv[2]) if len(sys.argv) > 2 else 16
+random.seed(int(sys.argv[3]) if len(sys.argv) > 3 else 1)
+
+def i16s(n, bound):
+    return struct.pack(f"<{n}h", *(random.randrange(-bound, bound) for _ in range(n)))
+
+with open(out, "wb") as f:
+    f.write(b"DVNN" + struct.pack("<II", 1, hidden))
+    f.write(i16s(768 * hidden, 64))  # input weights
+    f.write(i16s(hidden, 64))        # hidden biases
+    f.write(i16s(2 * hidden, 64))    # output weights
+    f.write(struct.pack("<i", random.randrange(-1000, 1000)))  # output bias
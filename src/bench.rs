@@ -1,8 +1,12 @@
 use shakmaty::Chess;
 
-use crate::{search::search, time};
+use crate::{
+    position::Position,
+    search::{SearchControl, search},
+    time,
+};
 
-const POSITIONS: [(&str, isize); 7] = [
+pub const POSITIONS: [(&str, isize); 14] = [
     ("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", 8),
     ("r1bq1rk1/4ppbp/p1pp1np1/1P2n3/2B1PB2/2NP1N1P/1PP2PP1/R2QR1K1 b - - 0 11", 7),
     ("2r3r1/3R2pk/p1p1PB2/1pR2P2/2p1PK2/P1P5/8/5b2 w - - 9 19", 7),
@@ -10,6 +14,19 @@ const POSITIONS: [(&str, isize); 7] = [
     ("r1b1kb1r/ppp2ppp/4pn2/3q4/1n1P4/5NP1/PP2PP1P/RNBQKB1R b KQkq - 4 8", 6),
     ("8/k7/3p4/p2P1p2/P2P1P2/8/8/K7 w - ", 30),
     ("Q4QR1/1p5p/k1p5/p7/3K4/8/P7/8 b - - 2 56", 4),
+    // Mates at the horizon: only found if qsearch searches evasions instead of standing pat in check
+    ("6k1/5ppp/8/8/8/8/5PPP/3R2K1 w - - 0 1", 1),
+    ("r1bqkbnr/pppp1ppp/2n5/4p2Q/2B1P3/8/PPPP1PPP/RNB1K1NR w KQkq - 2 3", 1),
+    // Smothered mate: the final knight check is a quiet move, found by the qsearch checks
+    ("6rk/6pp/7N/8/8/1Q6/8/6K1 w - - 0 1", 1),
+    // Mate in 2, which should not be reported as a longer mate at higher depth
+    ("k7/8/2K5/8/8/8/8/7R w - - 0 1", 8),
+    // Bishops of the same color: a dead draw, which should be scored as 0
+    ("8/8/3k4/2b5/8/4B3/3K4/8 w - - 0 1", 6),
+    // Zugzwang: Rf1 only wins because black has to move, which null move pruning has to verify
+    ("8/8/p1p5/1p5p/1P5p/8/PPP2K1p/4R1rk w - - 0 1", 12),
+    // King hunt: a forced mate in 3 starting with a quiet-looking sacrifice, found through extensions
+    ("r1b1kb1r/pppp1ppp/5q2/4n3/3KP3/2N3PN/PPP4P/R1BQ1B1R b kq - 0 1", 8),
 ];
 
 pub fn bench() {
@@ -23,13 +40,17 @@ pub fn bench() {
             .into_position(shakmaty::CastlingMode::Standard)
             .unwrap();
         let tt = crate::search::tt::TT::new(1 << 24);
-        let (score, _pv, count) = search(
-            position,
-            Vec::new(),
+        let (score, _pv, count, stats) = search(
+            Position::new(position),
+            &[],
             time::Deadline::Depth(depth as usize),
+            None,
             &tt,
             &crate::DEFAULT_CONFIG,
-            &mut |_, _, _, _| {},
+            &mut crate::search::SearchTables::default(),
+            &SearchControl::default(),
+            &mut |_, _, _, _, _, _| {},
+            None,
         );
         println!("FEN: {}", fen);
         println!(
@@ -41,6 +62,9 @@ pub fn bench() {
             count.qnodes.load(std::sync::atomic::Ordering::Relaxed),
             count.count(),
         );
+        for line in stats.summary(&count) {
+            println!("{}", line);
+        }
         println!("Time elapsed: {:?}", start_this.elapsed());
         total_nodes += count.count();
     }
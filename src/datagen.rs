@@ -0,0 +1,286 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use shakmaty::{Chess, Color, EnPassantMode, Move, Outcome, Position as _, fen::Fen};
+
+use crate::{
+    position::Position,
+    search::{self, SearchControl, SearchTables, tt::TT},
+    time,
+    util::random::splitmix64,
+};
+
+// Self-play data generation, for training networks and tuning the evaluation
+// Every game starts with a number of random moves, then both sides play fixed node searches. Quiet
+// positions are written to the output as text, one per line, in the format most trainers read:
+//   <fen> | <score in centipawns, from White's point of view> | <result: 1.0, 0.5 or 0.0 for White>
+// Game i uses the random seed splitmix64(seed ^ i), and the searches are single threaded with a
+// cleared TT, so the same options always give the same games (written in a different order when
+// games run in parallel).
+
+struct Options {
+    games: u64,
+    nodes: usize,
+    threads: usize,
+    random_plies: usize,
+    output: String,
+    seed: u64,
+    hash_mb: usize,
+    win_score: i16, // adjudicate a win when the score is beyond this for win_plies plies in a row
+    win_plies: usize,
+    draw_score: i16, // adjudicate a draw when the score is within this for draw_plies plies in a row,
+    draw_plies: usize, // after draw_start plies
+    draw_start: usize,
+    max_plies: usize,
+}
+
+const DEFAULT_OPTIONS: Options = Options {
+    games: 100,
+    nodes: 5000,
+    threads: 1,
+    random_plies: 8,
+    output: String::new(),
+    seed: 0,
+    hash_mb: 16,
+    win_score: 1000,
+    win_plies: 8,
+    draw_score: 10,
+    draw_plies: 12,
+    draw_start: 80,
+    max_plies: 400,
+};
+
+const USAGE: &str = "usage: davies-chess datagen --output <file> [--games N] [--nodes N] [--threads N] \
+[--random-plies N] [--seed N] [--hash MB] [--win-score CP] [--win-plies N] [--draw-score CP] \
+[--draw-plies N] [--draw-start PLY] [--max-plies N]";
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut options = DEFAULT_OPTIONS;
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let value = args.next().ok_or_else(|| format!("missing value for {flag}"))?;
+        let number = || value.parse::<u64>().map_err(|e| format!("invalid value for {flag}: {e}"));
+        match flag.as_str() {
+            "--games" => options.games = number()?,
+            "--nodes" => options.nodes = number()? as usize,
+            "--threads" => options.threads = (number()? as usize).max(1),
+            "--random-plies" => options.random_plies = number()? as usize,
+            "--output" => options.output = value.clone(),
+            "--seed" => options.seed = number()?,
+            "--hash" => options.hash_mb = (number()? as usize).max(1),
+            "--win-score" => options.win_score = number()?.min(i16::MAX as u64) as i16,
+            "--win-plies" => options.win_plies = number()? as usize,
+            "--draw-score" => options.draw_score = number()?.min(i16::MAX as u64) as i16,
+            "--draw-plies" => options.draw_plies = number()? as usize,
+            "--draw-start" => options.draw_start = number()? as usize,
+            "--max-plies" => options.max_plies = number()? as usize,
+            _ => return Err(format!("unknown option {flag}")),
+        }
+    }
+    if options.output.is_empty() {
+        return Err("no output file given".to_string());
+    }
+    Ok(options)
+}
+
+pub fn datagen(args: &[String]) {
+    let options = match parse_options(args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{e}\n{USAGE}");
+            std::process::exit(1);
+        }
+    };
+    let output = match File::create(&options.output) {
+        Ok(file) => Mutex::new(BufWriter::new(file)),
+        Err(e) => {
+            eprintln!("could not create {}: {e}", options.output);
+            std::process::exit(1);
+        }
+    };
+
+    let start = std::time::Instant::now();
+    let next_game = AtomicU64::new(0);
+    let positions = AtomicU64::new(0);
+    std::thread::scope(|scope| {
+        for _ in 0..options.threads {
+            std::thread::Builder::new()
+                .stack_size(search::STACK_SIZE)
+                .spawn_scoped(scope, || {
+                    let tt = TT::with_megabytes(options.hash_mb);
+                    let mut tables = SearchTables::default();
+                    loop {
+                        let game = next_game.fetch_add(1, Ordering::Relaxed);
+                        if game >= options.games {
+                            break;
+                        }
+                        tt.clear(1);
+                        tables.clear();
+                        let lines = play_game(&options, splitmix64(options.seed ^ game), &tt, &mut tables);
+                        let mut output = output.lock().unwrap();
+                        for line in &lines {
+                            writeln!(output, "{line}").expect("failed to write output");
+                        }
+                        let total = positions.fetch_add(lines.len() as u64, Ordering::Relaxed) + lines.len() as u64;
+                        if (game + 1).is_multiple_of(100) {
+                            eprintln!("{} games, {} positions, {:?}", game + 1, total, start.elapsed());
+                        }
+                    }
+                })
+                .expect("failed to spawn datagen thread");
+        }
+    });
+    output.into_inner().unwrap().flush().expect("failed to write output");
+    eprintln!(
+        "Wrote {} positions from {} games to {} in {:?}",
+        positions.load(Ordering::Relaxed),
+        options.games,
+        options.output,
+        start.elapsed()
+    );
+}
+
+// Random legal moves from the start position; tries again if the game ends before the last one
+fn random_opening(plies: usize, mut seed: u64) -> Chess {
+    'retry: loop {
+        let mut position = Chess::new();
+        for _ in 0..plies {
+            let moves = position.legal_moves();
+            if moves.is_empty() {
+                continue 'retry;
+            }
+            seed = splitmix64(seed);
+            position.play_unchecked(&moves[(seed % moves.len() as u64) as usize]);
+        }
+        if !position.legal_moves().is_empty() {
+            return position;
+        }
+    }
+}
+
+// Plays one game, returning the output lines for its positions
+fn play_game(options: &Options, seed: u64, tt: &TT, tables: &mut SearchTables) -> Vec<String> {
+    let config = crate::Configuration { threads: 1, root_noise: 0, ..crate::DEFAULT_CONFIG };
+    let mut position = Position::new(random_opening(options.random_plies, seed));
+    let mut plies = 0;
+    // Quiet positions and their scores from White's point of view, until the result is known
+    let mut samples: Vec<(String, i16)> = Vec::new();
+    let (mut win_streak, mut draw_streak) = (0isize, 0);
+
+    let result = loop {
+        if let Some(outcome) = position.outcome() {
+            break match outcome {
+                Outcome::Decisive { winner: Color::White } => "1.0",
+                Outcome::Decisive { winner: Color::Black } => "0.0",
+                Outcome::Draw => "0.5",
+            };
+        }
+        if position.halfmoves() >= 100 || position.is_repetition(0) || plies >= options.max_plies {
+            break "0.5";
+        }
+
+        let control = SearchControl::default();
+        let (score, pv, _, _) = search::search(
+            position.clone(),
+            &[],
+            time::Deadline::Nodes(options.nodes),
+            None,
+            tt,
+            &config,
+            tables,
+            &control,
+            &mut |_, _, _, _, _, _| {},
+            None,
+        );
+        let Some(mv) = pv.first().cloned() else {
+            break "0.5";
+        };
+        let white = if position.turn() == Color::White { 1 } else { -1 };
+        let score = match score {
+            // Clamped to a range that can be negated, for scores from White's point of view
+            ruci::Score::Centipawns(cp) => cp.clamp(-(i16::MAX as isize), i16::MAX as isize) as i16,
+            // The game is decided: let the result speak for the remaining positions
+            ruci::Score::MateIn(n) => break if n as i16 * white > 0 { "1.0" } else { "0.0" },
+        };
+
+        if is_quiet(position.pos(), &mv) {
+            let fen = Fen::from_position(position.pos().clone(), EnPassantMode::Legal).to_string();
+            samples.push((fen, score * white));
+        }
+
+        // Adjudication, on the scores of both sides in a row; the win streak is negative for Black
+        win_streak = match score * white {
+            s if s >= options.win_score => win_streak.max(0) + 1,
+            s if s <= -options.win_score => win_streak.min(0) - 1,
+            _ => 0,
+        };
+        draw_streak = if score.abs() <= options.draw_score { draw_streak + 1 } else { 0 };
+        if win_streak.unsigned_abs() >= options.win_plies {
+            break if win_streak > 0 { "1.0" } else { "0.0" };
+        }
+        if plies >= options.draw_start && draw_streak >= options.draw_plies {
+            break "0.5";
+        }
+
+        plies += 1;
+        position.play_unchecked(&mv);
+    };
+
+    samples.into_iter().map(|(fen, score)| format!("{fen} | {score} | {result}")).collect()
+}
+
+// Positions where the score depends on a capture or check in progress are poor training targets
+fn is_quiet(position: &Chess, best_move: &Move) -> bool {
+    !position.is_check() && !best_move.is_capture() && !best_move.is_promotion()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn play(seed: u64) -> Vec<String> {
+        let options = Options { nodes: 200, max_plies: 40, output: "test".to_string(), ..DEFAULT_OPTIONS };
+        let tt = TT::with_megabytes(1);
+        let mut tables = SearchTables::default();
+        std::thread::scope(|scope| {
+            std::thread::Builder::new()
+                .stack_size(search::STACK_SIZE)
+                .spawn_scoped(scope, || play_game(&options, splitmix64(seed), &tt, &mut tables))
+                .unwrap()
+                .join()
+                .unwrap()
+        })
+    }
+
+    #[test]
+    fn line_format() {
+        let lines = play(1);
+        assert!(!lines.is_empty());
+        let result = lines[0].rsplit(" | ").next().unwrap().to_string();
+        assert!(["1.0", "0.5", "0.0"].contains(&result.as_str()));
+        for line in &lines {
+            let fields: Vec<&str> = line.split(" | ").collect();
+            assert_eq!(fields.len(), 3, "{line}");
+            let position: Chess = fields[0]
+                .parse::<Fen>()
+                .unwrap()
+                .into_position(shakmaty::CastlingMode::Standard)
+                .unwrap();
+            assert!(!position.is_check(), "{line}");
+            fields[1].parse::<i16>().unwrap();
+            assert_eq!(fields[2], result, "{line}");
+        }
+    }
+
+    #[test]
+    fn deterministic() {
+        assert_eq!(play(2), play(2));
+        assert_ne!(play(2), play(3));
+    }
+}
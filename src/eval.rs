@@ -1,7 +1,93 @@
-use shakmaty::{Color, Position, Role, Square};
+use std::cell::RefCell;
+use std::ops::{Add, AddAssign, Mul, Neg, Sub, SubAssign};
+
+use shakmaty::{
+    Bitboard, Color, Piece, Position, Rank, Role, Square, variant::VariantPosition, zobrist::{Zobrist64, ZobristValue}
+};
+
+pub mod nnue;
+mod pawns;
+
+// Evaluation terms have a middlegame and an endgame value; the final score interpolates between
+// the two by the game phase, so e.g. the king can hide in the middlegame and centralize in the endgame
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Tapered {
+    pub mg: i16,
+    pub eg: i16,
+}
+
+impl Tapered {
+    pub const fn new(mg: i16, eg: i16) -> Self {
+        Tapered { mg, eg }
+    }
+
+    // Interpolates by the phase: MAX_PHASE is the opening, 0 a pawn ending
+    pub fn taper(self, phase: i32) -> i16 {
+        ((self.mg as i32 * phase + self.eg as i32 * (MAX_PHASE - phase)) / MAX_PHASE) as i16
+    }
+}
+
+impl Add for Tapered {
+    type Output = Tapered;
+    fn add(self, other: Tapered) -> Tapered {
+        Tapered::new(self.mg + other.mg, self.eg + other.eg)
+    }
+}
+
+impl Sub for Tapered {
+    type Output = Tapered;
+    fn sub(self, other: Tapered) -> Tapered {
+        Tapered::new(self.mg - other.mg, self.eg - other.eg)
+    }
+}
+
+impl Mul<i16> for Tapered {
+    type Output = Tapered;
+    fn mul(self, factor: i16) -> Tapered {
+        Tapered::new(self.mg * factor, self.eg * factor)
+    }
+}
+
+impl Neg for Tapered {
+    type Output = Tapered;
+    fn neg(self) -> Tapered {
+        Tapered::new(-self.mg, -self.eg)
+    }
+}
+
+impl AddAssign for Tapered {
+    fn add_assign(&mut self, other: Tapered) {
+        *self = *self + other;
+    }
+}
+
+impl SubAssign for Tapered {
+    fn sub_assign(&mut self, other: Tapered) {
+        *self = *self - other;
+    }
+}
+
+// Game phase: the non-pawn material left, counting minor pieces as 1, rooks as 2 and queens as 4
+const MAX_PHASE: i32 = 24;
+
+fn phase<P: Position>(position: &P) -> i32 {
+    let board = position.board();
+    let minors = (board.knights() | board.bishops()).count() as i32;
+    let phase = minors + 2 * board.rooks().count() as i32 + 4 * board.queens().count() as i32;
+    phase.min(MAX_PHASE)
+}
+
+const MATERIAL: [Tapered; 6] = [
+    Tapered::new(100, 120),
+    Tapered::new(320, 300),
+    Tapered::new(330, 330),
+    Tapered::new(500, 520),
+    Tapered::new(900, 920),
+    Tapered::new(20000, 20000), // both sides have 1 king always
+];
 
 // Taken from https://www.chessprogramming.org/Simplified_Evaluation_Function
-const PST: [[i16; 64]; 6] = [
+const PST_MG: [[i16; 64]; 6] = [
     // pawn
     [
          0,  0,  0,  0,  0,  0,  0,  0,
@@ -75,59 +161,997 @@ const PST: [[i16; 64]; 6] = [
     ],
 ];
 
+// Endgame tables: pawns are worth more the further they are, wherever they are, and the king
+// belongs in the center (also from the Simplified Evaluation Function)
+const PST_EG: [[i16; 64]; 6] = [
+    // pawn
+    [
+         0,  0,  0,  0,  0,  0,  0,  0,
+        80, 80, 80, 80, 80, 80, 80, 80,
+        50, 50, 50, 50, 50, 50, 50, 50,
+        30, 30, 30, 30, 30, 30, 30, 30,
+        15, 15, 15, 15, 15, 15, 15, 15,
+         5,  5,  5,  5,  5,  5,  5,  5,
+         0,  0,  0,  0,  0,  0,  0,  0,
+         0,  0,  0,  0,  0,  0,  0,  0
+    ],
+
+    // knight
+    PST_MG[1],
+
+    // bishop
+    PST_MG[2],
+
+    // rook: the seventh rank matters less without a king to trap on the back rank
+    [
+         0,  0,  0,  0,  0,  0,  0,  0,
+         5,  5,  5,  5,  5,  5,  5,  5,
+         0,  0,  0,  0,  0,  0,  0,  0,
+         0,  0,  0,  0,  0,  0,  0,  0,
+         0,  0,  0,  0,  0,  0,  0,  0,
+         0,  0,  0,  0,  0,  0,  0,  0,
+         0,  0,  0,  0,  0,  0,  0,  0,
+         0,  0,  0,  0,  0,  0,  0,  0
+    ],
+
+    // queen
+    PST_MG[4],
+
+    // king end game
+    [
+        -50,-40,-30,-20,-20,-30,-40,-50,
+        -30,-20,-10,  0,  0,-10,-20,-30,
+        -30,-10, 20, 30, 30, 20,-10,-30,
+        -30,-10, 30, 40, 40, 30,-10,-30,
+        -30,-10, 30, 40, 40, 30,-10,-30,
+        -30,-10, 20, 30, 30, 20,-10,-30,
+        -30,-30,  0,  0,  0,  0,-30,-30,
+        -50,-30,-30,-30,-30,-30,-30,-50
+    ],
+];
+
+// Material plus PST value of every piece on every square, indexed by color, role - 1 and square.
+// The tables above are written from White's side with a8 first, so White's squares are flipped.
+const PIECE_SQUARE: [[[Tapered; 64]; 6]; 2] = {
+    let mut table = [[[Tapered::new(0, 0); 64]; 6]; 2];
+    let mut color = 0;
+    while color < 2 {
+        let mut role = 0;
+        while role < 6 {
+            let mut sq = 0;
+            while sq < 64 {
+                let idx = if color == Color::White as usize { sq ^ 56 } else { sq };
+                table[color][role][sq] = Tapered::new(
+                    MATERIAL[role].mg + PST_MG[role][idx],
+                    MATERIAL[role].eg + PST_EG[role][idx],
+                );
+                sq += 1;
+            }
+            role += 1;
+        }
+        color += 1;
+    }
+    table
+};
+
+pub fn eval_piece_tapered(sq: Square, color: Color, role: Role) -> Tapered {
+    PIECE_SQUARE[color as usize][role as usize - 1][sq as usize]
+}
+
+// Middlegame value of a piece, for move ordering
 pub fn eval_piece(sq: Square, color: Color, role: Role) -> i16 {
-    let base_piece_value = match role {
-        shakmaty::Role::Pawn => 100,
-        shakmaty::Role::Knight => 320,
-        shakmaty::Role::Bishop => 330,
-        shakmaty::Role::Rook => 500,
-        shakmaty::Role::Queen => 900,
-        shakmaty::Role::King => 20000, // both sides have 1 king always
+    eval_piece_tapered(sq, color, role).mg
+}
+
+// Zobrist key of only the pawns on the board, for tables indexed by pawn structure
+pub fn pawn_key<P: Position>(position: &P) -> u64 {
+    let mut key = Zobrist64(0);
+    for color in Color::ALL {
+        let piece = Piece { color, role: Role::Pawn };
+        for sq in position.board().pawns() & position.board().by_color(color) {
+            key ^= Zobrist64::zobrist_for_piece(sq, piece);
+        }
+    }
+    key.0
+}
+
+// Knight mobility, by number of safe squares
+const KNIGHT_MOBILITY: [Tapered; 9] = [
+    Tapered::new(-30, -40), Tapered::new(-20, -25), Tapered::new(-10, -12), Tapered::new(-3, -4),
+    Tapered::new(3, 3), Tapered::new(8, 8), Tapered::new(12, 12), Tapered::new(16, 15),
+    Tapered::new(18, 17),
+];
+
+// Bishop mobility
+const BISHOP_MOBILITY: [Tapered; 14] = [
+    Tapered::new(-25, -35), Tapered::new(-15, -20), Tapered::new(-6, -8), Tapered::new(0, 0),
+    Tapered::new(5, 6), Tapered::new(10, 12), Tapered::new(14, 17), Tapered::new(18, 21),
+    Tapered::new(21, 25), Tapered::new(24, 28), Tapered::new(26, 30), Tapered::new(28, 32),
+    Tapered::new(30, 33), Tapered::new(31, 34),
+];
+
+// Rook mobility; more important in the endgame, when the rook has to be active
+const ROOK_MOBILITY: [Tapered; 15] = [
+    Tapered::new(-20, -35), Tapered::new(-12, -20), Tapered::new(-6, -10), Tapered::new(-2, -2),
+    Tapered::new(0, 5), Tapered::new(3, 12), Tapered::new(6, 18), Tapered::new(9, 24),
+    Tapered::new(11, 29), Tapered::new(13, 33), Tapered::new(15, 36), Tapered::new(17, 39),
+    Tapered::new(18, 41), Tapered::new(19, 43), Tapered::new(20, 44),
+];
+
+// Queen mobility
+const QUEEN_MOBILITY: [Tapered; 28] = [
+    Tapered::new(-15, -25), Tapered::new(-8, -13), Tapered::new(-5, -9), Tapered::new(-3, -5),
+    Tapered::new(-2, -2), Tapered::new(0, 1), Tapered::new(1, 3), Tapered::new(3, 6),
+    Tapered::new(4, 8), Tapered::new(5, 10), Tapered::new(6, 12), Tapered::new(7, 13),
+    Tapered::new(8, 15), Tapered::new(9, 17), Tapered::new(10, 18), Tapered::new(11, 20),
+    Tapered::new(12, 21), Tapered::new(13, 23), Tapered::new(14, 24), Tapered::new(14, 25),
+    Tapered::new(15, 27), Tapered::new(16, 28), Tapered::new(17, 29), Tapered::new(17, 30),
+    Tapered::new(18, 32), Tapered::new(19, 33), Tapered::new(19, 34), Tapered::new(20, 35),
+];
+
+// Mobility of one side: the squares its pieces attack, except those occupied by its own pawns or
+// king (which it will rarely be able to move out of the way) and those attacked by enemy pawns
+fn mobility(position: &shakmaty::Chess, color: Color, enemy_pawn_attacks: Bitboard) -> Tapered {
+    let board = position.board();
+    let own = board.by_color(color);
+    let area = !((board.pawns() | board.kings()) & own) & !enemy_pawn_attacks;
+    let mut score = Tapered::default();
+    for sq in own & !board.pawns() & !board.kings() {
+        let squares = (board.attacks_from(sq) & area).count();
+        score += match board.role_at(sq) {
+            Some(Role::Knight) => KNIGHT_MOBILITY[squares],
+            Some(Role::Bishop) => BISHOP_MOBILITY[squares],
+            Some(Role::Rook) => ROOK_MOBILITY[squares],
+            Some(Role::Queen) => QUEEN_MOBILITY[squares],
+            _ => unreachable!(),
+        };
+    }
+    score
+}
+
+// Rooks (and to a lesser extent queens) on files without friendly pawns (semi-open) or without any
+// pawns (open), where they can reach the enemy position
+const ROOK_OPEN_FILE: Tapered = Tapered::new(40, 20);
+const ROOK_SEMI_OPEN_FILE: Tapered = Tapered::new(20, 10);
+const QUEEN_OPEN_FILE: Tapered = Tapered::new(10, 5);
+const QUEEN_SEMI_OPEN_FILE: Tapered = Tapered::new(5, 3);
+
+fn open_files(position: &shakmaty::Chess, color: Color) -> Tapered {
+    let board = position.board();
+    let own = board.by_color(color);
+    let mut score = Tapered::default();
+    for sq in (board.rooks() | board.queens()) & own {
+        let file = Bitboard::from_file(sq.file());
+        if (board.pawns() & own & file).any() {
+            continue;
+        }
+        let open = (board.pawns() & file).is_empty();
+        score += match (board.role_at(sq), open) {
+            (Some(Role::Rook), true) => ROOK_OPEN_FILE,
+            (Some(Role::Rook), false) => ROOK_SEMI_OPEN_FILE,
+            (_, true) => QUEEN_OPEN_FILE,
+            (_, false) => QUEEN_SEMI_OPEN_FILE,
+        };
+    }
+    score
+}
+
+// Rook on the seventh rank, while the enemy king is stuck on its back rank or there are pawns to win
+const ROOK_ON_SEVENTH: Tapered = Tapered::new(20, 30);
+// Rook behind a passed pawn (its own, to support it, or the enemy's, to stop it) on the same file
+const ROOK_BEHIND_PASSER: Tapered = Tapered::new(10, 25);
+
+fn rook_placement(position: &shakmaty::Chess, color: Color, pawns: &pawns::PawnEntry) -> Tapered {
+    let board = position.board();
+    let enemy = board.by_color(color.other());
+    let seventh = Bitboard::from_rank(color.fold_wb(Rank::Seventh, Rank::Second));
+    let back_rank = Bitboard::from_rank(color.fold_wb(Rank::Eighth, Rank::First));
+    let seventh_matters = (board.kings() & enemy & back_rank).any() || (board.pawns() & enemy & seventh).any();
+    let passers = pawns.passed[Color::White as usize] | pawns.passed[Color::Black as usize];
+    let mut score = Tapered::default();
+    for sq in board.rooks() & board.by_color(color) {
+        if seventh.contains(sq) && seventh_matters {
+            score += ROOK_ON_SEVENTH;
+        }
+        // Behind as seen from the pawn's side, with nothing in between
+        for passer in passers & board.attacks_from(sq) & Bitboard::from_file(sq.file()) {
+            let owner = board.color_at(passer).unwrap();
+            if pawns::ranks_ahead(owner, sq.rank()).contains(passer) {
+                score += ROOK_BEHIND_PASSER;
+            }
+        }
+    }
+    score
+}
+
+// Bad bishop: per own central pawn in front of the bishop on squares of its color, where they block
+// its diagonals; extra if the pawn is fixed, so it will not get out of the way. A bishop that is
+// outside its pawn chain, in front of the pawns, is not hindered by them.
+const BAD_BISHOP_PAWN: Tapered = Tapered::new(-3, -5);
+const BAD_BISHOP_FIXED: Tapered = Tapered::new(-4, -6);
+const CENTRAL_FILES: Bitboard = Bitboard(0x3c3c_3c3c_3c3c_3c3c); // files c to f
+
+fn bad_bishops(position: &shakmaty::Chess, color: Color, pawns: &pawns::PawnEntry) -> Tapered {
+    let board = position.board();
+    let central = board.pawns() & board.by_color(color) & CENTRAL_FILES;
+    let mut score = Tapered::default();
+    for sq in board.bishops() & board.by_color(color) {
+        let same_color = if sq.is_light() { Bitboard::LIGHT_SQUARES } else { Bitboard::DARK_SQUARES };
+        let blockers = central & same_color & pawns::ranks_ahead(color, sq.rank());
+        score += BAD_BISHOP_PAWN * blockers.count() as i16;
+        score += BAD_BISHOP_FIXED * (blockers & pawns.fixed[color as usize]).count() as i16;
+    }
+    score
+}
+
+// King safety: enemy pieces attacking the king zone (the king and the squares around it) add attack
+// units by role for every zone square they attack, and the total is looked up in a table that grows
+// quadratically, as attacks become much more dangerous once several pieces join in
+const KING_ATTACK_UNITS: [i32; 6] = [0, 2, 2, 3, 5, 0]; // by role: pawn, knight, bishop, rook, queen, king
+const KING_DANGER: [i16; 100] = {
+    let mut table = [0; 100];
+    let mut units = 0;
+    while units < 100 {
+        let danger = units * units * 3 / 8;
+        table[units] = if danger > 500 { 500 } else { danger as i16 };
+        units += 1;
+    }
+    table
+};
+
+// Danger to the king of the given side (a penalty for it)
+fn king_danger(position: &shakmaty::Chess, color: Color) -> Tapered {
+    let board = position.board();
+    let Some(king) = board.king_of(color) else {
+        return Tapered::default();
     };
+    let zone = shakmaty::attacks::king_attacks(king).with(king);
+    let enemy = board.by_color(color.other());
+    let mut units = 0;
+    let mut attackers = 0;
+    for sq in enemy & !board.pawns() & !board.kings() {
+        let attacked = (board.attacks_from(sq) & zone).count() as i32;
+        if attacked > 0 {
+            attackers += 1;
+            units += KING_ATTACK_UNITS[usize::from(board.role_at(sq).unwrap()) - 1] * attacked;
+        }
+    }
+    // A lone attacker is rarely dangerous
+    if attackers < 2 {
+        return Tapered::default();
+    }
+    let mut danger = KING_DANGER[(units as usize).min(KING_DANGER.len() - 1)];
+    // Without a queen, attacks rarely lead to mate
+    if (board.queens() & enemy).is_empty() {
+        danger /= 2;
+    }
+    Tapered::new(-danger, -danger / 8)
+}
 
-    let piece_idx: usize = role.into();
-    let sq_idx: usize = if color == shakmaty::Color::White {
-        sq.flip_vertical().into()
-    } else {
-        sq.into()
+// King tropism: pieces close to the enemy king (by Chebyshev distance) can join an attack on it.
+// Middlegame only: in the endgame, closeness to the enemy king is left to the mop-up evaluation.
+const TROPISM: [i16; 6] = [0, 3, 1, 1, 4, 0]; // per square closer than 7, by role
+
+fn tropism(position: &shakmaty::Chess, color: Color) -> Tapered {
+    let board = position.board();
+    let Some(king) = board.king_of(color.other()) else {
+        return Tapered::default();
     };
+    let mut bonus = 0;
+    for sq in board.by_color(color) & !board.pawns() & !board.kings() {
+        let role = board.role_at(sq).unwrap();
+        bonus += TROPISM[usize::from(role) - 1] * (7 - sq.distance(king) as i16);
+    }
+    Tapered::new(bonus, 0)
+}
 
-    let pst_value = PST[piece_idx - 1][sq_idx];
-    base_piece_value + pst_value
+// Mop-up: with a decisive material edge against a bare king (or king and minor piece), drive the
+// enemy king to the edge and bring our own king closer, as needed to mate. Without it, the PSTs
+// give no direction and the winning side wanders around until the 50 move rule.
+const MOP_UP_MARGIN: i16 = 400; // material edge needed
+const MOP_UP_PAWN_MARGIN: i16 = 800; // material edge needed when we still have pawns to promote instead
+const MOP_UP_EDGE: i16 = 10; // per step of the enemy king away from the center
+const MOP_UP_KINGS: i16 = 4; // per step our king is closer to the enemy king
+
+// Steps away from the four center squares
+fn center_distance(sq: Square) -> i16 {
+    let file = sq.file() as i16;
+    let rank = sq.rank() as i16;
+    (3 - file).max(file - 4) + (3 - rank).max(rank - 4)
+}
+
+fn non_pawn_material(position: &shakmaty::Chess, color: Color) -> i16 {
+    let board = position.board();
+    let own = board.by_color(color);
+    let count = |pieces: Bitboard| (pieces & own).count() as i16;
+    count(board.knights()) * MATERIAL[1].eg
+        + count(board.bishops()) * MATERIAL[2].eg
+        + count(board.rooks()) * MATERIAL[3].eg
+        + count(board.queens()) * MATERIAL[4].eg
+}
+
+fn mop_up(position: &shakmaty::Chess, color: Color) -> Tapered {
+    let board = position.board();
+    let (Some(king), Some(enemy_king)) = (board.king_of(color), board.king_of(color.other())) else {
+        return Tapered::default();
+    };
+    let enemy_pieces = board.by_color(color.other()) & !board.kings();
+    if (enemy_pieces & board.pawns()).any() || enemy_pieces.count() > 1 {
+        return Tapered::default();
+    }
+    let edge = non_pawn_material(position, color) - non_pawn_material(position, color.other());
+    let has_pawns = (board.pawns() & board.by_color(color)).any();
+    if edge < if has_pawns { MOP_UP_PAWN_MARGIN } else { MOP_UP_MARGIN } {
+        return Tapered::default();
+    }
+    let king_distance = (king.file().distance(enemy_king.file()) + king.rank().distance(enemy_king.rank())) as i16;
+    Tapered::new(0, MOP_UP_EDGE * center_distance(enemy_king) + MOP_UP_KINGS * (14 - king_distance))
+}
+
+// Drawish endgames: the endgame score is scaled down (out of SCALE_NORMAL) in material
+// configurations where the stronger side usually cannot win, however large its edge looks
+const SCALE_NORMAL: i32 = 64;
+
+fn endgame_scale(position: &shakmaty::Chess, strong: Color) -> i32 {
+    let board = position.board();
+    let weak = strong.other();
+    let pawns = |color: Color| (board.pawns() & board.by_color(color)).count() as i32;
+    let pieces = |color: Color| board.by_color(color) & !board.pawns() & !board.kings();
+    let (strong_pieces, weak_pieces) = (pieces(strong), pieces(weak));
+
+    // Without pawns, an edge of a minor piece or less is usually not enough to mate (e.g. rook
+    // against minor piece, or rook and minor piece against rook), except for a queen against minor
+    // pieces, which can pick them off one at a time
+    let edge = non_pawn_material(position, strong) - non_pawn_material(position, weak);
+    let queen_against_minors =
+        (strong_pieces & board.queens()).any() && (weak_pieces & (board.rooks() | board.queens())).is_empty();
+    if pawns(strong) == 0 && edge <= MATERIAL[2].eg && !queen_against_minors {
+        return 4;
+    }
+    // Opposite-colored bishops: the defender blockades the pawns on the squares of its bishop
+    if strong_pieces == board.bishops() & board.by_color(strong)
+        && weak_pieces == board.bishops() & board.by_color(weak)
+        && strong_pieces.count() == 1
+        && weak_pieces.count() == 1
+        && ((strong_pieces | weak_pieces) & Bitboard::LIGHT_SQUARES).any()
+        && ((strong_pieces | weak_pieces) & Bitboard::DARK_SQUARES).any()
+    {
+        return match pawns(strong) - pawns(weak) {
+            ..=1 => 16,
+            2 => 32,
+            _ => 56,
+        };
+    }
+    // Rook endings a pawn up with all pawns on one wing
+    if strong_pieces == board.rooks() & board.by_color(strong)
+        && weak_pieces == board.rooks() & board.by_color(weak)
+        && strong_pieces.count() == 1
+        && weak_pieces.count() == 1
+        && pawns(strong) - pawns(weak) == 1
+        && (board.pawns().is_subset(Bitboard::WEST) || board.pawns().is_subset(Bitboard::EAST))
+    {
+        return 32;
+    }
+    SCALE_NORMAL
+}
+
+// Threats: pieces attacked by enemy pawns, rooks and queens attacked by enemy minor pieces, and
+// pieces left hanging (attacked and undefended) by the side that just moved. The side to move can
+// often deal with one threat, but not with several.
+const THREAT_BY_PAWN: Tapered = Tapered::new(-40, -30);
+const THREAT_BY_MINOR: Tapered = Tapered::new(-30, -20);
+const HANGING: Tapered = Tapered::new(-30, -20);
+
+// Squares attacked by all pieces of one side, and by its knights and bishops
+fn attack_maps(position: &shakmaty::Chess, color: Color) -> (Bitboard, Bitboard) {
+    let board = position.board();
+    let mut all = Bitboard::EMPTY;
+    let mut minors = Bitboard::EMPTY;
+    for sq in board.by_color(color) {
+        let attacks = board.attacks_from(sq);
+        all |= attacks;
+        if (board.knights() | board.bishops()).contains(sq) {
+            minors |= attacks;
+        }
+    }
+    (all, minors)
+}
+
+// Threats against the pieces of one side, given the attack maps of both sides (indexed by color)
+fn threats(
+    position: &shakmaty::Chess,
+    color: Color,
+    attacks: &[(Bitboard, Bitboard); 2],
+    enemy_pawn_attacks: Bitboard,
+    to_move: bool,
+) -> Tapered {
+    let board = position.board();
+    let own = board.by_color(color);
+    let pieces = own & !board.pawns() & !board.kings();
+    let (defended, _) = attacks[color as usize];
+    let (attacked, attacked_by_minors) = attacks[color.other() as usize];
+    let mut score = THREAT_BY_PAWN * (pieces & enemy_pawn_attacks).count() as i16;
+    score += THREAT_BY_MINOR * ((board.rooks() | board.queens()) & own & attacked_by_minors).count() as i16;
+    if !to_move {
+        score += HANGING * (own & !board.kings() & attacked & !defended).count() as i16;
+    }
+    score
+}
+
+// Material and piece-square values of one side, one bitboard per piece type
+fn material<P: Position>(position: &P, color: Color) -> Tapered {
+    let board = position.board();
+    let own = board.by_color(color);
+    let mut score = Tapered::default();
+    for role in Role::ALL {
+        for sq in own & board.by_role(role) {
+            score += eval_piece_tapered(sq, color, role);
+        }
+    }
+    score
+}
+
+// Bonus for the side to move, which can usually improve its position with its move; evaluating
+// both sides equally makes scores swing between odd and even depths
+const TEMPO: Tapered = Tapered::new(15, 5);
+
+// Cheap approximation of eval(), with only material, piece-square tables and tempo. The other terms
+// rarely add up to more than a few hundred centipawns, so when this is far enough outside the search
+// window, the full evaluation would not change the outcome.
+pub fn lazy_eval(position: &shakmaty::Chess) -> i16 {
+    let mut score = material(position, Color::White) - material(position, Color::Black);
+    score += if position.turn() == Color::White { TEMPO } else { -TEMPO };
+    from_side_to_move(position, score.taper(phase(position)))
+}
+
+// The variants use the standard piece values, except for the king. In antichess it is not royal and
+// pawns can promote to it, so a side can have several kings or none; at the standard king value,
+// two kings do not even fit in an i16. In three-check and atomic, both sides have one king until the
+// game ends, so its value cancels out anyway.
+const VARIANT_KING: Tapered = Tapered::new(300, 300);
+
+fn variant_material<P: Position>(position: &P, color: Color) -> Tapered {
+    let board = position.board();
+    let own = board.by_color(color);
+    let mut score = Tapered::default();
+    for role in Role::ALL {
+        let adjustment = if role == Role::King { VARIANT_KING - MATERIAL[5] } else { Tapered::default() };
+        for sq in own & board.by_role(role) {
+            score += eval_piece_tapered(sq, color, role) + adjustment;
+        }
+    }
+    score
+}
+
+// Material, piece-square tables and tempo, for the variants
+fn variant_eval<P: Position>(position: &P) -> i16 {
+    let mut score = variant_material(position, Color::White) - variant_material(position, Color::Black);
+    score += if position.turn() == Color::White { TEMPO } else { -TEMPO };
+    from_side_to_move(position, score.taper(phase(position)))
+}
+
+// The terms are computed from White's point of view; the search wants the score for the side to move
+fn from_side_to_move<P: Position>(position: &P, white_score: i16) -> i16 {
+    if position.turn() == Color::White { white_score } else { -white_score }
 }
 
 #[inline(never)]
 pub fn eval(position: &shakmaty::Chess) -> i16 {
-    // Simple material evaluation
-    let mut score = 0;
+    if nnue::is_loaded()
+        && let Some(score) = nnue::evaluate(position)
+    {
+        return score;
+    }
+    classical(position, None)
+}
 
-    for (sq, piece) in position.board() {
-        let piece_value = eval_piece(sq, piece.color, piece.role);
+// Evaluation of the position types the search runs on. The variants only get material and
+// piece-square tables for now, as their rules change what most other terms mean; NNUE is only used
+// for standard chess.
+pub trait Evaluate: Position {
+    fn evaluate(&self) -> i16;
+    // Cheap approximation of evaluate(), see lazy_eval()
+    fn lazy_evaluate(&self) -> i16;
+}
 
-        let atts = position.board().attacks_from(sq).count() as i16;
-        let att_bonus = if piece_value == 0 { 0 } else { 1200 * atts / piece_value };
-        // Bonus if piece can attack; bigger bonus for less valuable pieces
+impl Evaluate for shakmaty::Chess {
+    fn evaluate(&self) -> i16 {
+        eval(self)
+    }
 
-        if piece.color == position.turn() {
-            score += piece_value + att_bonus;
-        } else {
-            score -= piece_value + att_bonus;
+    fn lazy_evaluate(&self) -> i16 {
+        lazy_eval(self)
+    }
+}
+
+impl Evaluate for VariantPosition {
+    fn evaluate(&self) -> i16 {
+        match self {
+            VariantPosition::Chess(chess) => eval(chess),
+            _ => variant_eval(self),
         }
     }
 
-    // let white_pawns = position.board().pawns() & position.board().by_color(Color::White);
-    // let black_pawns = position.board().pawns() & position.board().by_color(Color::Black);
-    // let white_blockers = white_pawns.shift(8) & position.board().by_color(Color::Black);
-    // let black_blockers = black_pawns.shift(-8) & position.board().by_color(Color::White);
+    fn lazy_evaluate(&self) -> i16 {
+        match self {
+            VariantPosition::Chess(chess) => lazy_eval(chess),
+            _ => variant_eval(self),
+        }
+    }
+}
 
-    // score -= 35 * white_blockers.count() as i16;
-    // score += 35 * black_blockers.count() as i16;
+// Breakdown of the classical evaluation by term and side, for the `eval` command
+#[derive(Default)]
+pub struct Trace {
+    terms: Vec<(&'static str, Tapered, Tapered)>, // name, White's and Black's value
+    scale: i32,
+    phase: i32,
+}
 
-    // let mobility = position.legal_moves().len() as i16;
-    // let opp_mobility = position.clone().swap_turn().map(|p| p.legal_moves().len() as i16).unwrap_or(0);
+// Sum of the evaluation terms, recording each of them in the trace if there is one
+struct Terms<'a> {
+    score: Tapered,
+    trace: Option<&'a mut Trace>,
+}
 
-    // score += 5 * mobility;
-    // score -= 5 * opp_mobility;
+impl Terms<'_> {
+    fn add(&mut self, name: &'static str, white: Tapered, black: Tapered) {
+        self.score += white - black;
+        if let Some(trace) = self.trace.as_deref_mut() {
+            trace.terms.push((name, white, black));
+        }
+    }
+}
 
+// Material only, without the piece-square tables and the king, which is always on the board
+fn piece_values(position: &shakmaty::Chess, color: Color) -> Tapered {
+    let board = position.board();
+    let mut score = Tapered::default();
+    for role in [Role::Pawn, Role::Knight, Role::Bishop, Role::Rook, Role::Queen] {
+        score += MATERIAL[role as usize - 1] * (board.by_color(color) & board.by_role(role)).count() as i16;
+    }
     score
-}
\ No newline at end of file
+}
+
+fn classical(position: &shakmaty::Chess, trace: Option<&mut Trace>) -> i16 {
+    let (white, black) = (Color::White, Color::Black);
+    let white_to_move = position.turn() == white;
+    let mut terms = Terms { score: Tapered::default(), trace };
+    let board = position.board();
+
+    let (white_material, black_material) = (material(position, white), material(position, black));
+    if terms.trace.is_some() {
+        let king = |color: Color| MATERIAL[Role::King as usize - 1] * board.king_of(color).is_some() as i16;
+        let (white_pieces, black_pieces) = (piece_values(position, white), piece_values(position, black));
+        terms.add("Material", white_pieces, black_pieces);
+        terms.add("Piece-square", white_material - white_pieces - king(white), black_material - black_pieces - king(black));
+        // The kings' values always cancel out, so they are left out of the table
+        terms.score += king(white) - king(black);
+    } else {
+        terms.add("Material", white_material, black_material);
+    }
+
+    let pawns = pawns::probe(position);
+    // Isolated pawns on half-open files are only weak while there are rooks or queens to attack them
+    let pawn_structure = |color: Color| {
+        let enemy_heavies = (board.rooks() | board.queens()) & board.by_color(color.other());
+        let exposed = if enemy_heavies.any() { pawns.exposed[color as usize].count() as i16 } else { 0 };
+        pawns.score[color as usize] + pawns::ISOLATED_PAWN_EXPOSED * exposed
+    };
+    terms.add("Pawns", pawn_structure(white), pawn_structure(black));
+
+    terms.add(
+        "Mobility",
+        mobility(position, white, pawns.attacks[black as usize]),
+        mobility(position, black, pawns.attacks[white as usize]),
+    );
+    terms.add("Open files", open_files(position, white), open_files(position, black));
+    terms.add("Rook placement", rook_placement(position, white, &pawns), rook_placement(position, black, &pawns));
+    terms.add("Bad bishops", bad_bishops(position, white, &pawns), bad_bishops(position, black, &pawns));
+    terms.add("King danger", king_danger(position, white), king_danger(position, black));
+    terms.add("King tropism", tropism(position, white), tropism(position, black));
+    terms.add("Mop-up", mop_up(position, white), mop_up(position, black));
+    let attacks = [attack_maps(position, Color::Black), attack_maps(position, Color::White)];
+    terms.add(
+        "Threats",
+        threats(position, white, &attacks, pawns.attacks[black as usize], white_to_move),
+        threats(position, black, &attacks, pawns.attacks[white as usize], !white_to_move),
+    );
+    let (white_tempo, black_tempo) = if white_to_move { (TEMPO, Tapered::default()) } else { (Tapered::default(), TEMPO) };
+    terms.add("Tempo", white_tempo, black_tempo);
+    terms.add("Pawn shelter", pawns::shelter(position, white), pawns::shelter(position, black));
+
+    let mut score = terms.score;
+    let strong = if score.eg > 0 { white } else { black };
+    let scale = endgame_scale(position, strong);
+    score.eg = (score.eg as i32 * scale / SCALE_NORMAL) as i16;
+    let phase = phase(position);
+    if let Some(trace) = terms.trace {
+        trace.scale = scale;
+        trace.phase = phase;
+    }
+
+    from_side_to_move(position, score.taper(phase))
+}
+
+// The classical evaluation of a position, as a table of the terms of both sides, in centipawns from
+// White's point of view
+pub fn trace(position: &shakmaty::Chess) -> String {
+    let mut trace = Trace::default();
+    let score = classical(position, Some(&mut trace));
+    let white_score = from_side_to_move(position, score);
+
+    let mut lines = vec![
+        format!("{:<16}|{:^15}|{:^15}|{:^15}", "Term", "White", "Black", "Total"),
+        format!("{:<16}|{:>7}{:>7} |{:>7}{:>7} |{:>7}{:>7} ", "", "MG", "EG", "MG", "EG", "MG", "EG"),
+    ];
+    let row = |name: &str, white: Tapered, black: Tapered| {
+        let total = white - black;
+        format!(
+            "{:<16}|{:>7}{:>7} |{:>7}{:>7} |{:>7}{:>7} ",
+            name, white.mg, white.eg, black.mg, black.eg, total.mg, total.eg
+        )
+    };
+    let (mut white_sum, mut black_sum) = (Tapered::default(), Tapered::default());
+    for &(name, white, black) in &trace.terms {
+        lines.push(row(name, white, black));
+        white_sum += white;
+        black_sum += black;
+    }
+    lines.push(row("Total", white_sum, black_sum));
+    lines.push(format!("Endgame scale {}/{}", trace.scale, SCALE_NORMAL));
+    lines.push(format!("Phase {}/{} (middlegame weight)", trace.phase, MAX_PHASE));
+    lines.push(format!("Classical evaluation: {} (White), {} (side to move)", white_score, score));
+    if nnue::is_loaded()
+        && let Some(nnue) = nnue::evaluate(position)
+    {
+        lines.push(format!("NNUE evaluation: {} (White), {} (side to move)", from_side_to_move(position, nnue), nnue));
+    }
+    lines.join("\n")
+}
+
+const EVAL_TABLE_SIZE: usize = 1 << 16;
+
+// Cache of full evaluations, one table per thread like the pawn table. Positions that are reached
+// again without a TT entry (transpositions, re-searches after the entry was replaced) skip eval().
+thread_local! {
+    static EVAL_TABLE: RefCell<Vec<(u64, i16)>> = const { RefCell::new(Vec::new()) };
+}
+
+// The cached evaluation of a position, without computing it if it is not there
+pub fn probe_eval_cache(key: u64) -> Option<i16> {
+    EVAL_TABLE.with_borrow(|table| {
+        let slot = table.get((key % EVAL_TABLE_SIZE as u64) as usize)?;
+        (slot.0 == key && key != 0).then_some(slot.1)
+    })
+}
+
+// Evaluation of a position with the given zobrist key. The key includes the side to move, which the
+// score is relative to; the halfmove clock is not part of the key, but eval() does not depend on it.
+pub fn eval_cached<P: Evaluate>(position: &P, key: u64) -> i16 {
+    EVAL_TABLE.with_borrow_mut(|table| {
+        if table.is_empty() {
+            table.resize(EVAL_TABLE_SIZE, (0, 0));
+        }
+        let slot = &mut table[(key % EVAL_TABLE_SIZE as u64) as usize];
+        // An empty slot has key 0, which a real position only has by a negligible chance
+        if slot.0 != key || key == 0 {
+            *slot = (key, position.evaluate());
+        }
+        slot.1
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shakmaty::{CastlingMode, Chess, fen::Fen};
+
+    fn position(fen: &str) -> Chess {
+        let fen: Fen = fen.parse().unwrap();
+        fen.into_position(CastlingMode::Standard).unwrap()
+    }
+
+    // The evaluation from White's point of view
+    fn white_eval(fen: &str) -> i16 {
+        let pos = position(fen);
+        from_side_to_move(&pos, classical(&pos, None))
+    }
+
+    #[test]
+    fn pawnless_endings() {
+        for (fen, scale) in [
+            // Won: queen against rook, queen against two minor pieces, rook and bishop against bishop
+            ("8/8/3k4/8/8/2r5/8/Q3K3 w - - 0 1", SCALE_NORMAL),
+            ("8/8/3k4/3bb3/8/8/8/3QK3 w - - 0 1", SCALE_NORMAL),
+            ("8/8/3k4/3nn3/8/8/8/3QK3 w - - 0 1", SCALE_NORMAL),
+            ("8/8/3k4/3bn3/8/8/8/3QK3 w - - 0 1", SCALE_NORMAL),
+            // Drawish: rook against minor piece, rook and minor piece against rook
+            ("8/8/3k4/3b4/8/8/8/3RK3 w - - 0 1", 4),
+            ("8/8/3k4/3n4/8/8/8/3RK3 w - - 0 1", 4),
+            ("8/8/3k4/3r4/8/8/8/2NRK3 w - - 0 1", 4),
+            ("8/8/3k4/3r4/8/8/8/2BRK3 w - - 0 1", 4),
+        ] {
+            assert_eq!(endgame_scale(&position(fen), Color::White), scale, "{fen}");
+        }
+        // A queen against a rook is evaluated as the win it is
+        assert!(white_eval("8/8/3k4/8/8/2r5/8/Q3K3 w - - 0 1") > 300);
+    }
+
+    #[test]
+    fn opposite_colored_bishops() {
+        // One extra pawn: a draw
+        let one = white_eval("8/4k3/6p1/3b4/8/3PP3/4K3/2B5 w - - 0 1");
+        assert!(one.abs() <= 50, "{one}");
+        // Three extra pawns: still winning
+        let three = white_eval("8/4k3/6p1/3b4/8/2PPP2P/4K3/2B5 w - - 0 1");
+        assert!(three >= 150, "{three}");
+        // With bishops of the same color, one extra pawn is not scaled down as much
+        let same = white_eval("8/4k3/6p1/3b4/8/3PP3/4K3/5B2 w - - 0 1");
+        assert!(same > one, "{same} {one}");
+    }
+
+    #[test]
+    fn antichess_kings() {
+        use shakmaty::variant::{Variant, VariantPosition};
+        let antichess = |fen: &str| {
+            let fen: Fen = fen.parse().unwrap();
+            let pos = VariantPosition::from_setup(Variant::Antichess, fen.into_setup(), CastlingMode::Standard).unwrap();
+            from_side_to_move(&pos, pos.evaluate())
+        };
+        // Kings are worth about a minor piece, so many of them evaluate without overflowing
+        let kings = antichess("8/8/8/3n4/8/8/KKKKKKKK/KKKKKKKK w - - 0 1");
+        assert!((3000..6000).contains(&kings), "{kings}");
+        let king = antichess("8/8/8/3n4/8/8/8/K7 w - - 0 1");
+        assert!(king.abs() <= 100, "{king}");
+    }
+
+    // In a pawn ending the king belongs in the center, while in the middlegame it hides behind its pawns
+    #[test]
+    fn king_centralizes_in_the_endgame() {
+        let (central, corner) = ("7k/8/8/8/4K3/8/4P3/8 w - - 0 1", "7k/8/8/8/8/8/4P3/K7 w - - 0 1");
+        assert_eq!(phase(&position(central)), 0);
+        assert!(white_eval(central) > white_eval(corner) + 30, "{} {}", white_eval(central), white_eval(corner));
+        let king = |sq| eval_piece_tapered(sq, Color::White, Role::King);
+        assert!(king(Square::G1).mg > king(Square::E4).mg);
+        assert!(king(Square::G1).eg < king(Square::E4).eg);
+    }
+
+
+    #[test]
+    fn isolated_pawns() {
+        // The isolated d-pawn is worse than an e-pawn next to the f-pawn, more so with rooks to attack it
+        let gap = |pieces: [&str; 2]| {
+            let [black, white] = pieces;
+            let iqp = white_eval(&format!("{black}k3/pp3ppp/4p3/8/3P4/8/PP3PPP/{white}K3 w - - 0 1"));
+            let healthy = white_eval(&format!("{black}k3/pp3ppp/4p3/8/4P3/8/PP3PPP/{white}K3 w - - 0 1"));
+            healthy - iqp
+        };
+        let (bare, rooks) = (gap(["4", "4"]), gap(["r3", "R3"]));
+        assert!(bare > 0 && rooks > bare, "{bare} {rooks}");
+        // There is no passed pawn bonus, but an extra isolated pawn, passed or not, is still worth more
+        // than its penalty
+        let extra = white_eval("4k3/p7/8/3P4/8/8/P7/4K3 w - - 0 1");
+        assert!(extra > 50, "{extra}");
+    }
+
+    // A knight in the corner reaches two squares, in the center eight, and the difference in score
+    // is more than the piece-square tables alone give
+    #[test]
+    fn knight_mobility() {
+        let (central, corner) = ("4k3/6pp/8/8/3N4/8/6PP/4K3 w - - 0 1", "4k3/6pp/8/8/8/8/6PP/N3K3 w - - 0 1");
+        assert_eq!(mobility(&position(central), Color::White, Bitboard::EMPTY), KNIGHT_MOBILITY[8]);
+        assert_eq!(mobility(&position(corner), Color::White, Bitboard::EMPTY), KNIGHT_MOBILITY[2]);
+        let phase = phase(&position(central));
+        let knight = |sq| eval_piece_tapered(sq, Color::White, Role::Knight);
+        let pst = (knight(Square::D4) - knight(Square::A1)).taper(phase);
+        let (central_eval, corner_eval) = (white_eval(central), white_eval(corner));
+        assert!(central_eval - corner_eval > pst, "{central_eval} {corner_eval} {pst}");
+        // Squares attacked by enemy pawns do not count
+        let attacked = position("4k3/8/4p3/8/3N4/8/8/4K3 w - - 0 1");
+        let pawn_attacks = pawns::evaluate(&attacked).attacks[Color::Black as usize];
+        assert_eq!(mobility(&attacked, Color::White, pawn_attacks), KNIGHT_MOBILITY[7]);
+    }
+
+
+    // Queen, knight and bishop aiming at the castled king: 16 attack units, well beyond the piece-square
+    // tables. With the queen elsewhere the attack is worth much less, and a lone attacker nothing.
+    #[test]
+    fn king_danger_by_attackers() {
+        let danger = |fen| king_danger(&position(fen), Color::White).mg;
+        assert_eq!(danger("4k3/8/3b4/8/6nq/8/5PPP/5RK1 w - - 0 1"), -KING_DANGER[16]);
+        assert!(KING_DANGER[16] >= 90);
+        assert_eq!(danger("4k3/8/3b4/q7/6n1/8/5PPP/5RK1 w - - 0 1"), -KING_DANGER[6]);
+        // Without a queen on the board, half that
+        assert_eq!(danger("4k3/8/3b4/8/6n1/8/5PPP/5RK1 w - - 0 1"), -KING_DANGER[6] / 2);
+        assert_eq!(danger("4k3/8/8/8/7q/8/5PPP/5RK1 w - - 0 1"), 0);
+    }
+
+
+    #[test]
+    fn bad_bishops() {
+        let bad_bishops = |fen| {
+            let pos = position(fen);
+            super::bad_bishops(&pos, Color::Black, &pawns::evaluate(&pos))
+        };
+        // French structure: the light-squared bishop behind the d5/e6 chain, two of whose pawns are
+        // fixed by the white pawns in front of them, and the f7 pawn
+        assert_eq!(
+            bad_bishops("2b1k3/pp3ppp/4p3/3pP3/3P4/8/PPP2PPP/4K3 b - - 0 1"),
+            BAD_BISHOP_PAWN * 3 + BAD_BISHOP_FIXED * 2
+        );
+        // The same bishop developed outside the chain has no pawns ahead of it
+        assert_eq!(bad_bishops("4k3/pp3ppp/4p3/3pPb2/3P4/8/PPP2PPP/4K3 b - - 0 1"), Tapered::default());
+    }
+
+
+    #[test]
+    fn open_files() {
+        let open_files = |fen| super::open_files(&position(fen), Color::White);
+        // No pawns on the d-file, only a black one, and a white one
+        assert_eq!(open_files("4k3/8/8/8/8/8/8/3RK3 w - - 0 1"), ROOK_OPEN_FILE);
+        assert_eq!(open_files("4k3/3p4/8/8/8/8/8/3RK3 w - - 0 1"), ROOK_SEMI_OPEN_FILE);
+        assert_eq!(open_files("4k3/3p4/8/8/8/8/3P4/3RK3 w - - 0 1"), Tapered::default());
+        // Queens get a smaller bonus
+        assert_eq!(open_files("4k3/8/8/8/8/8/8/3QK3 w - - 0 1"), QUEEN_OPEN_FILE);
+        assert_eq!(open_files("4k3/3p4/8/8/8/8/8/3QK3 w - - 0 1"), QUEEN_SEMI_OPEN_FILE);
+    }
+
+
+    #[test]
+    fn rook_placement() {
+        let placement = |fen, color| {
+            let pos = position(fen);
+            super::rook_placement(&pos, color, &pawns::evaluate(&pos))
+        };
+        // Lucena: the rook behind the passed b-pawn supports it, while the black rook on a2 is not on
+        // White's second rank for any reason (no white king or pawns there)
+        let lucena = "1K1k4/1P6/8/8/8/8/r7/1R6 w - - 0 1";
+        assert_eq!(placement(lucena, Color::White), ROOK_BEHIND_PASSER);
+        assert_eq!(placement(lucena, Color::Black), Tapered::default());
+        // A rook in front of its passed pawn does not count
+        assert_eq!(placement("1R1k4/1P6/8/8/8/8/r7/1K6 b - - 0 1", Color::White), Tapered::default());
+        // Behind the enemy passed pawn, to stop it
+        assert_eq!(placement("3k4/8/1P6/8/8/8/8/1r2K3 w - - 0 1", Color::Black), ROOK_BEHIND_PASSER);
+        // Seventh rank, with the enemy king on its back rank, or with pawns on the seventh to win
+        assert_eq!(placement("3k4/R7/8/8/8/8/8/4K3 w - - 0 1", Color::White), ROOK_ON_SEVENTH);
+        assert_eq!(placement("8/R4p2/3k4/8/8/8/8/4K3 w - - 0 1", Color::White), ROOK_ON_SEVENTH);
+        assert_eq!(placement("8/R7/3k4/8/8/8/8/4K3 w - - 0 1", Color::White), Tapered::default());
+    }
+
+
+    #[test]
+    fn threats() {
+        let threats = |fen| {
+            let pos = position(fen);
+            let attacks = [attack_maps(&pos, Color::Black), attack_maps(&pos, Color::White)];
+            let pawn_attacks = pawns::evaluate(&pos).attacks[Color::Black as usize];
+            super::threats(&pos, Color::White, &attacks, pawn_attacks, pos.turn() == Color::White)
+        };
+        // The rook on e5 is attacked by the bishop and undefended; on d5 it is safe
+        let (hanging, safe) = ("5k2/6pp/8/4R3/8/8/1b4PP/4K3 b - - 0 1", "5k2/6pp/8/3R4/8/8/1b4PP/4K3 b - - 0 1");
+        assert_eq!(threats(hanging), THREAT_BY_MINOR + HANGING);
+        assert_eq!(threats(safe), Tapered::default());
+        assert!(white_eval(hanging) < white_eval(safe) - 30, "{} {}", white_eval(hanging), white_eval(safe));
+        // With White to move, the rook can still get away
+        assert_eq!(threats("5k2/6pp/8/4R3/8/8/1b4PP/4K3 w - - 0 1"), THREAT_BY_MINOR);
+        // A defended knight attacked by a pawn
+        assert_eq!(threats("4k3/6pp/4p3/3N4/4P3/8/6PP/4K3 b - - 0 1"), THREAT_BY_PAWN);
+    }
+
+
+    // A queen on h6 is two squares from the king on g8, on a1 as far as it gets
+    #[test]
+    fn king_tropism() {
+        let (near, far) = ("6k1/ppp5/7Q/8/8/8/PPP5/2K5 w - - 0 1", "6k1/ppp5/8/8/8/8/PPP5/Q1K5 w - - 0 1");
+        assert_eq!(tropism(&position(near), Color::White), Tapered::new(5 * TROPISM[Role::Queen as usize - 1], 0));
+        assert_eq!(tropism(&position(far), Color::White), Tapered::default());
+        let queen = |sq| eval_piece_tapered(sq, Color::White, Role::Queen);
+        let pst = (queen(Square::H6) - queen(Square::A1)).taper(phase(&position(near)));
+        let (near_eval, far_eval) = (white_eval(near), white_eval(far));
+        assert!(near_eval - far_eval > pst, "{near_eval} {far_eval} {pst}");
+    }
+
+
+    // A rook up against a bare king: drive the king to the edge, with our own king close to it
+    #[test]
+    fn mop_up() {
+        let mop_up = |fen| super::mop_up(&position(fen), Color::White);
+        let (center, edge) = (mop_up("8/8/8/3k4/8/8/8/R3K3 w - - 0 1"), mop_up("3k4/8/8/8/8/8/8/R3K3 w - - 0 1"));
+        assert!(edge.eg > center.eg && center.eg > 0, "{edge:?} {center:?}");
+        let close = mop_up("3k4/8/3K4/8/8/8/8/R7 w - - 0 1");
+        assert_eq!(close.eg - edge.eg, MOP_UP_KINGS * 6);
+        // Not for a rook against a knight, nor for a pawn up, nor against a pawn
+        assert_eq!(mop_up("1n1k4/8/8/8/8/8/8/R3K3 w - - 0 1"), Tapered::default());
+        assert_eq!(mop_up("3k4/8/8/8/8/8/P7/4K3 w - - 0 1"), Tapered::default());
+        assert_eq!(mop_up("3k4/3p4/8/8/8/8/8/R3K3 w - - 0 1"), Tapered::default());
+    }
+
+
+    // The precomputed table holds material plus the piece-square value, with White's squares flipped
+    #[test]
+    fn piece_square_table() {
+        for color in Color::ALL {
+            for role in Role::ALL {
+                let r = role as usize - 1;
+                for sq in Square::ALL {
+                    let idx = match color {
+                        Color::White => sq.flip_vertical(),
+                        Color::Black => sq,
+                    } as usize;
+                    let expected = MATERIAL[r] + Tapered::new(PST_MG[r][idx], PST_EG[r][idx]);
+                    assert_eq!(eval_piece_tapered(sq, color, role), expected, "{color:?} {role:?} {sq:?}");
+                    assert_eq!(eval_piece(sq, color, role), expected.mg);
+                }
+            }
+        }
+    }
+
+
+    // Material by role bitboards is the same as going over the board square by square
+    #[test]
+    fn material_by_bitboards() {
+        for (fen, _) in crate::bench::POSITIONS {
+            let pos = position(fen);
+            let mut expected = [Tapered::default(); 2];
+            for (sq, piece) in pos.board() {
+                expected[piece.color as usize] += eval_piece_tapered(sq, piece.color, piece.role);
+            }
+            assert_eq!([material(&pos, Color::Black), material(&pos, Color::White)], expected, "{fen}");
+        }
+    }
+
+
+    // Over random games, cached evaluations are the ones computed again, including positions that
+    // only differ by the side to move
+    #[test]
+    fn cached_evaluations() {
+        use shakmaty::zobrist::ZobristHash;
+        let mut seed = 0;
+        for _ in 0..20 {
+            let mut pos = Chess::default();
+            for _ in 0..200 {
+                let key = pos.zobrist_hash::<Zobrist64>(shakmaty::EnPassantMode::Legal).0;
+                let fen = Fen::from_position(pos.clone(), shakmaty::EnPassantMode::Legal);
+                assert_eq!(eval_cached(&pos, key), pos.evaluate(), "{fen}");
+                assert_eq!(probe_eval_cache(key), Some(pos.evaluate()), "{fen}");
+                if let Ok(passed) = pos.clone().swap_turn() {
+                    let key = passed.zobrist_hash::<Zobrist64>(shakmaty::EnPassantMode::Legal).0;
+                    assert_eq!(eval_cached(&passed, key), passed.evaluate(), "{fen}");
+                }
+                let moves = pos.legal_moves();
+                if moves.is_empty() {
+                    break;
+                }
+                seed = crate::util::random::splitmix64(seed);
+                pos.play_unchecked(&moves[seed as usize % moves.len()]);
+            }
+        }
+    }
+
+    #[test]
+    fn trace_start_position() {
+        let expected = [
+            "Term            |     White     |     Black     |     Total     ",
+            "                |     MG     EG |     MG     EG |     MG     EG ",
+            "Material        |   4000   4180 |   4000   4180 |      0      0 ",
+            "Piece-square    |    -95   -135 |    -95   -135 |      0      0 ",
+            "Pawns           |      0      0 |      0      0 |      0      0 ",
+            "Mobility        |   -102   -147 |   -102   -147 |      0      0 ",
+            "Open files      |      0      0 |      0      0 |      0      0 ",
+            "Rook placement  |      0      0 |      0      0 |      0      0 ",
+            "Bad bishops     |    -12    -20 |    -12    -20 |      0      0 ",
+            "King danger     |      0      0 |      0      0 |      0      0 ",
+            "King tropism    |      0      0 |      0      0 |      0      0 ",
+            "Mop-up          |      0      0 |      0      0 |      0      0 ",
+            "Threats         |      0      0 |      0      0 |      0      0 ",
+            "Tempo           |     15      5 |      0      0 |     15      5 ",
+            "Pawn shelter    |      0      0 |      0      0 |      0      0 ",
+            "Total           |   3806   3883 |   3791   3878 |     15      5 ",
+            "Endgame scale 64/64",
+            "Phase 24/24 (middlegame weight)",
+            "Classical evaluation: 15 (White), 15 (side to move)",
+        ];
+        assert_eq!(trace(&Chess::new()), expected.join("\n"));
+    }
+}
@@ -0,0 +1,269 @@
+use std::sync::{
+    RwLock,
+    atomic::{AtomicBool, Ordering},
+};
+
+use shakmaty::{Color, Position};
+
+// NNUE evaluation
+// An optional replacement for the hand-crafted evaluation, loaded from a file with the EvalFile
+// option. The network has one hidden layer: the 768 inputs (piece color, piece type and square) are
+// seen from both sides, which gives two hidden accumulators with shared weights. Both are clipped
+// to [0, QA] and feed a single output, the half of the side to move first. Mirroring the position
+// gives the same inputs for the side to move, so the evaluation is symmetric by construction.
+//
+// File layout, all numbers little-endian:
+//   magic b"DVNN", u32 version (1), u32 hidden size N (a multiple of 16)
+//   i16[768][N] input weights, by input feature
+//   i16[N]      hidden biases
+//   i16[2][N]   output weights: for the side to move's accumulator, then for the other side's
+//   i32         output bias
+// Input feature from the point of view of a side: 384 if the piece is the other side's, plus
+// 64 * (piece type - 1), plus the square, flipped vertically when seen from Black.
+//
+// The accumulators are recomputed from the board for every evaluation. The search position
+// (position::Position) does not carry them, so make/unmake do not update them incrementally; most
+// evaluations are skipped anyway by the eval cache and the static evals stored in the TT.
+
+const MAGIC: &[u8; 4] = b"DVNN";
+const VERSION: u32 = 1;
+const INPUTS: usize = 768;
+const MAX_HIDDEN: usize = 4096;
+
+const QA: i32 = 255; // hidden activations are clipped to [0, QA]
+const QB: i32 = 64; // output weights are scaled by QB
+const SCALE: i32 = 400; // centipawns for a network output of 1.0
+const MAX_SCORE: i32 = 30000; // network scores stay below the mate scores
+
+pub struct Network {
+    hidden: usize,
+    input_weights: Vec<i16>, // INPUTS * hidden
+    hidden_bias: Vec<i16>,
+    output_weights: Vec<i16>, // 2 * hidden
+    output_bias: i32,
+}
+
+static NETWORK: RwLock<Option<Network>> = RwLock::new(None);
+// Whether a network is loaded, to check without taking the lock
+static LOADED: AtomicBool = AtomicBool::new(false);
+
+pub fn is_loaded() -> bool {
+    LOADED.load(Ordering::Relaxed)
+}
+
+// Loads a network file, replacing the current network; on error, the classical evaluation is used
+pub fn load(path: &str) -> Result<(), String> {
+    let network = std::fs::read(path).map_err(|e| e.to_string()).and_then(|bytes| Network::parse(&bytes));
+    let mut current = NETWORK.write().unwrap();
+    LOADED.store(network.is_ok(), Ordering::Relaxed);
+    match network {
+        Ok(network) => {
+            *current = Some(network);
+            Ok(())
+        }
+        Err(e) => {
+            *current = None;
+            Err(e)
+        }
+    }
+}
+
+// Goes back to the classical evaluation
+pub fn unload() {
+    *NETWORK.write().unwrap() = None;
+    LOADED.store(false, Ordering::Relaxed);
+}
+
+// Evaluation of the position for the side to move, if a network is loaded
+pub fn evaluate(position: &shakmaty::Chess) -> Option<i16> {
+    let network = NETWORK.read().unwrap();
+    let network = network.as_ref()?;
+    let (us, them) = network.accumulators(position);
+    let output = network.output(&us, &them);
+    debug_assert_eq!(output, network.output_scalar(&us, &them));
+    Some(network.scale(output))
+}
+
+// Like evaluate(), but with the plain scalar code for the output layer, to check the fast one against
+pub fn evaluate_scalar(position: &shakmaty::Chess) -> Option<i16> {
+    let network = NETWORK.read().unwrap();
+    let network = network.as_ref()?;
+    let (us, them) = network.accumulators(position);
+    Some(network.scale(network.output_scalar(&us, &them)))
+}
+
+fn feature(perspective: Color, color: Color, role: shakmaty::Role, sq: shakmaty::Square) -> usize {
+    let sq = if perspective == Color::White { sq as usize } else { sq as usize ^ 56 };
+    384 * (color != perspective) as usize + 64 * (role as usize - 1) + sq
+}
+
+impl Network {
+    fn parse(bytes: &[u8]) -> Result<Network, String> {
+        let mut reader = Reader(bytes);
+        if reader.take(4)? != MAGIC {
+            return Err("not a network file".to_string());
+        }
+        let version = reader.u32()?;
+        if version != VERSION {
+            return Err(format!("unsupported network version {version}"));
+        }
+        let hidden = reader.u32()? as usize;
+        if hidden == 0 || hidden > MAX_HIDDEN || !hidden.is_multiple_of(16) {
+            return Err(format!("unsupported hidden layer size {hidden}"));
+        }
+        let network = Network {
+            hidden,
+            input_weights: reader.i16s(INPUTS * hidden)?,
+            hidden_bias: reader.i16s(hidden)?,
+            output_weights: reader.i16s(2 * hidden)?,
+            output_bias: reader.u32()? as i32,
+        };
+        if !reader.0.is_empty() {
+            return Err(format!("{} bytes left over at the end of the file", reader.0.len()));
+        }
+        Ok(network)
+    }
+
+    // The hidden accumulators of the side to move and of the other side
+    fn accumulators(&self, position: &shakmaty::Chess) -> (Vec<i16>, Vec<i16>) {
+        let (us, them) = (position.turn(), position.turn().other());
+        let mut accumulators = (self.hidden_bias.clone(), self.hidden_bias.clone());
+        for (sq, piece) in position.board() {
+            self.add_feature(&mut accumulators.0, feature(us, piece.color, piece.role, sq));
+            self.add_feature(&mut accumulators.1, feature(them, piece.color, piece.role, sq));
+        }
+        accumulators
+    }
+
+    // A simple loop over equal-length slices, which the compiler vectorizes
+    fn add_feature(&self, accumulator: &mut [i16], feature: usize) {
+        let weights = &self.input_weights[feature * self.hidden..(feature + 1) * self.hidden];
+        for (a, w) in accumulator.iter_mut().zip(weights) {
+            *a = a.wrapping_add(*w);
+        }
+    }
+
+    // Output layer, with SSE2 (always available on x86_64): activations and weights are multiplied
+    // and summed in pairs into 32 bits by pmaddwd, eight values at a time
+    #[cfg(target_arch = "x86_64")]
+    fn output(&self, us: &[i16], them: &[i16]) -> i32 {
+        use std::arch::x86_64::*;
+        let (us_weights, them_weights) = self.output_weights.split_at(self.hidden);
+        // SAFETY: SSE2 is part of the x86_64 baseline, and the loads are unaligned loads of eight
+        // i16 values from slices of `hidden` values, a multiple of 16, so they stay in bounds
+        unsafe {
+            let zero = _mm_setzero_si128();
+            let max = _mm_set1_epi16(QA as i16);
+            let mut sum = _mm_setzero_si128();
+            for (accumulator, weights) in [(us, us_weights), (them, them_weights)] {
+                for i in (0..self.hidden).step_by(8) {
+                    let a = _mm_loadu_si128(accumulator.as_ptr().add(i) as *const __m128i);
+                    let a = _mm_min_epi16(_mm_max_epi16(a, zero), max);
+                    let w = _mm_loadu_si128(weights.as_ptr().add(i) as *const __m128i);
+                    sum = _mm_add_epi32(sum, _mm_madd_epi16(a, w));
+                }
+            }
+            let mut lanes = [0i32; 4];
+            _mm_storeu_si128(lanes.as_mut_ptr() as *mut __m128i, sum);
+            lanes.iter().fold(self.output_bias, |total, lane| total.wrapping_add(*lane))
+        }
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn output(&self, us: &[i16], them: &[i16]) -> i32 {
+        self.output_scalar(us, them)
+    }
+
+    // The reference implementation of the output layer
+    fn output_scalar(&self, us: &[i16], them: &[i16]) -> i32 {
+        let (us_weights, them_weights) = self.output_weights.split_at(self.hidden);
+        let mut sum = self.output_bias;
+        for (accumulator, weights) in [(us, us_weights), (them, them_weights)] {
+            for (a, w) in accumulator.iter().zip(weights) {
+                sum = sum.wrapping_add((*a as i32).clamp(0, QA) * *w as i32);
+            }
+        }
+        sum
+    }
+
+    fn scale(&self, output: i32) -> i16 {
+        (output as i64 * SCALE as i64 / (QA * QB) as i64).clamp(-MAX_SCORE as i64, MAX_SCORE as i64) as i16
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.0.len() < n {
+            return Err("network file is too short".to_string());
+        }
+        let (bytes, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn i16s(&mut self, n: usize) -> Result<Vec<i16>, String> {
+        Ok(self.take(2 * n)?.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shakmaty::{Chess, EnPassantMode, fen::Fen};
+
+    // Read directly rather than with load(), which would switch the evaluation of every other test
+    fn test_network_bytes() -> Vec<u8> {
+        std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/nets/test-768x16.nnue")).unwrap()
+    }
+
+    // The SIMD output layer gives the same sums as the scalar one, over random games
+    #[test]
+    fn output_matches_scalar() {
+        let network = Network::parse(&test_network_bytes()).unwrap();
+        let mut seed = 0;
+        for _ in 0..20 {
+            let mut position = Chess::default();
+            for _ in 0..120 {
+                let (us, them) = network.accumulators(&position);
+                let fen = Fen::from_position(position.clone(), EnPassantMode::Legal);
+                assert_eq!(network.output(&us, &them), network.output_scalar(&us, &them), "{fen}");
+                let moves = position.legal_moves();
+                if moves.is_empty() {
+                    break;
+                }
+                seed = crate::util::random::splitmix64(seed);
+                position.play_unchecked(&moves[seed as usize % moves.len()]);
+            }
+        }
+        // Accumulators beyond the clipping range on both sides
+        let us: Vec<i16> = (0..network.hidden).map(|i| [-1000, 0, 100, 255, 256, 30000][i % 6]).collect();
+        let them: Vec<i16> = us.iter().rev().copied().collect();
+        assert_eq!(network.output(&us, &them), network.output_scalar(&us, &them));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let bytes = test_network_bytes();
+        assert!(Network::parse(&bytes).is_ok());
+        let error = |bytes: &[u8]| Network::parse(bytes).err().unwrap();
+        let mut magic = bytes.clone();
+        magic[0] = b'X';
+        assert_eq!(error(&magic), "not a network file");
+        let mut version = bytes.clone();
+        version[4] = 2;
+        assert_eq!(error(&version), "unsupported network version 2");
+        let mut hidden = bytes.clone();
+        hidden[8] = 17;
+        assert_eq!(error(&hidden), "unsupported hidden layer size 17");
+        assert_eq!(error(&bytes[..bytes.len() - 1]), "network file is too short");
+        assert_eq!(error(&bytes[..6]), "network file is too short");
+        let trailing = [&bytes[..], &[0, 0]].concat();
+        assert_eq!(error(&trailing), "2 bytes left over at the end of the file");
+    }
+}
@@ -0,0 +1,253 @@
+use std::cell::RefCell;
+
+use shakmaty::{Bitboard, Color, File, Position, Rank, attacks};
+
+use super::{Tapered, pawn_key};
+
+// Pawn structure evaluation
+// The pawns rarely change from one node to the next, so the pawn terms are cached in a table indexed
+// by the pawn key. Terms that also depend on other pieces are left to the caller, which gets the
+// bitboards it needs for them from the cached entry.
+
+// Pawn structure terms, per pawn
+const DOUBLED_PAWN: Tapered = Tapered::new(-10, -20); // for every pawn on a file beyond the first
+const ISOLATED_PAWN: Tapered = Tapered::new(-10, -10); // no friendly pawns on the adjacent files
+pub const ISOLATED_PAWN_EXPOSED: Tapered = Tapered::new(-10, -5); // extra, if enemy rooks or queens can attack it
+// Pawns that can become passed by trading off the enemy pawns in front of them, by relative rank
+const CANDIDATE_PASSER: [Tapered; 8] = [
+    Tapered::new(0, 0),
+    Tapered::new(5, 10),
+    Tapered::new(5, 10),
+    Tapered::new(10, 15),
+    Tapered::new(15, 25),
+    Tapered::new(20, 35),
+    Tapered::new(0, 0), // on the 7th rank, no enemy pawn can be in front on the adjacent files
+    Tapered::new(0, 0),
+];
+
+const FILE_A: u64 = 0x0101_0101_0101_0101;
+
+// The files next to each file
+const ADJACENT_FILES: [Bitboard; 8] = {
+    let mut masks = [Bitboard(0); 8];
+    let mut file = 0;
+    while file < 8 {
+        let left = if file > 0 { FILE_A << (file - 1) } else { 0 };
+        let right = if file < 7 { FILE_A << (file + 1) } else { 0 };
+        masks[file] = Bitboard(left | right);
+        file += 1;
+    }
+    masks
+};
+
+// The ranks in front of a rank, as seen from the given side
+pub fn ranks_ahead(color: Color, rank: Rank) -> Bitboard {
+    match color {
+        Color::White => Bitboard(!0 << (8 * rank as u32) << 8),
+        Color::Black => Bitboard((1 << (8 * rank as u32)) - 1),
+    }
+}
+
+// Everything indexed by color is indexed by `color as usize`
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct PawnEntry {
+    key: u64,
+    pub score: [Tapered; 2],      // the terms that only depend on the pawns
+    pub exposed: [Bitboard; 2],   // isolated pawns on half-open files
+    pub passed: [Bitboard; 2],
+    pub attacks: [Bitboard; 2],   // squares attacked by pawns
+    pub fixed: [Bitboard; 2],     // pawns blocked by an enemy pawn, which can only move by capturing
+}
+
+// Computes the pawn structure of both sides from scratch
+pub fn evaluate(position: &shakmaty::Chess) -> PawnEntry {
+    let mut entry = PawnEntry { key: pawn_key(position), ..Default::default() };
+    for color in Color::ALL {
+        evaluate_side(position, color, &mut entry);
+    }
+    entry
+}
+
+fn evaluate_side(position: &shakmaty::Chess, color: Color, entry: &mut PawnEntry) {
+    let board = position.board();
+    let pawns = board.pawns() & board.by_color(color);
+    let enemy_pawns = board.pawns() & board.by_color(color.other());
+    let c = color as usize;
+    let mut score = Tapered::default();
+    for file in File::ALL {
+        let on_file = pawns & Bitboard::from_file(file);
+        let count = on_file.count() as i16;
+        if count > 1 {
+            score += DOUBLED_PAWN * (count - 1);
+        }
+        if count > 0 && (pawns & ADJACENT_FILES[file as usize]).is_empty() {
+            score += ISOLATED_PAWN * count;
+            // On a half-open file, the pawn is an easy target for rooks and queens
+            if (enemy_pawns & Bitboard::from_file(file)).is_empty() {
+                entry.exposed[c] |= on_file;
+            }
+        }
+    }
+    let blocked_by = match color {
+        Color::White => enemy_pawns.0 >> 8,
+        Color::Black => enemy_pawns.0 << 8,
+    };
+    entry.fixed[c] = pawns & Bitboard(blocked_by);
+    for sq in pawns {
+        entry.attacks[c] |= attacks::pawn_attacks(color, sq);
+        let ahead = ranks_ahead(color, sq.rank());
+        let adjacent = ADJACENT_FILES[sq.file() as usize];
+        // A pawn with any pawn in front of it on its own file can never become passed
+        if (board.pawns() & Bitboard::from_file(sq.file()) & ahead).any() {
+            continue;
+        }
+        // Candidate: not passed yet, but with at least as many friendly pawns level or behind on the
+        // adjacent files (to trade with) as enemy pawns in front on them
+        let sentries = (enemy_pawns & adjacent & ahead).count();
+        let helpers = (pawns & adjacent & !ahead).count();
+        if sentries == 0 {
+            entry.passed[c].add(sq);
+        } else if helpers >= sentries {
+            score += CANDIDATE_PASSER[color.relative_rank(sq.rank()) as usize];
+        }
+    }
+    entry.score[c] = score;
+}
+
+// Pawn shelter: penalty by the relative rank of the friendly pawn closest to the king on each of the
+// files around it, only counting pawns in front of the king (index 0: no such pawn). The king's own
+// file counts double, as an open file in front of the king is the most dangerous.
+const SHELTER: [i16; 8] = [-30, 0, 0, -10, -20, -25, -30, -30];
+
+// Shelter of the king of one side; only matters in the middlegame, while there are pieces to attack it
+pub fn shelter(position: &shakmaty::Chess, color: Color) -> Tapered {
+    let board = position.board();
+    let Some(king) = board.king_of(color) else {
+        return Tapered::default();
+    };
+    let pawns = board.pawns() & board.by_color(color) & ranks_ahead(color, king.rank());
+    let king_file = king.file() as usize;
+    let mut penalty = 0;
+    for file in king_file.saturating_sub(1)..=(king_file + 1).min(7) {
+        let on_file = pawns & Bitboard::from_file(File::new(file as u32));
+        // The closest pawn to the king is the one furthest back
+        let closest = match color {
+            Color::White => on_file.first(),
+            Color::Black => on_file.last(),
+        };
+        let rank = closest.map_or(0, |sq| color.relative_rank(sq.rank()) as usize);
+        penalty += SHELTER[rank] * if file == king_file { 2 } else { 1 };
+    }
+    Tapered::new(penalty, 0)
+}
+
+const PAWN_TABLE_SIZE: usize = 16384;
+
+// One table per thread, so the search threads do not have to share it
+thread_local! {
+    static PAWN_TABLE: RefCell<Vec<PawnEntry>> = const { RefCell::new(Vec::new()) };
+}
+
+// Returns the pawn structure from the table, computing and storing it if it is not there
+pub fn probe(position: &shakmaty::Chess) -> PawnEntry {
+    let key = pawn_key(position);
+    PAWN_TABLE.with_borrow_mut(|table| {
+        if table.is_empty() {
+            table.resize(PAWN_TABLE_SIZE, PawnEntry::default());
+        }
+        let slot = &mut table[(key % PAWN_TABLE_SIZE as u64) as usize];
+        // An empty slot has key 0, which is also the key of a position without pawns; its entry is
+        // all zeroes, just like a freshly computed one
+        if slot.key != key {
+            *slot = evaluate(position);
+        }
+        *slot
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shakmaty::{CastlingMode, Chess, fen::Fen};
+
+    fn position(fen: &str) -> Chess {
+        let fen: Fen = fen.parse().unwrap();
+        fen.into_position(CastlingMode::Standard).unwrap()
+    }
+
+    // Pawn structure score of one side
+    fn score(fen: &str, color: Color) -> Tapered {
+        evaluate(&position(fen)).score[color as usize]
+    }
+
+    // Every pawn on a file beyond the first counts once
+    #[test]
+    fn doubled_pawns() {
+        assert_eq!(score("4k3/8/8/8/8/8/2PPP3/4K3 w - - 0 1", Color::White), Tapered::default());
+        assert_eq!(score("4k3/8/8/8/8/2P5/2PP4/4K3 w - - 0 1", Color::White), DOUBLED_PAWN);
+        assert_eq!(score("4k3/8/8/8/2P5/2P5/2PP4/4K3 w - - 0 1", Color::White), DOUBLED_PAWN * 2);
+        assert_eq!(score("4k3/8/8/8/8/2PP4/2PP4/4K3 w - - 0 1", Color::White), DOUBLED_PAWN * 2);
+        assert_eq!(score("4k3/2pp4/2p5/8/8/8/8/4K3 w - - 0 1", Color::Black), DOUBLED_PAWN);
+    }
+
+    // The isolated queen pawn: isolated, and exposed on the half-open d-file
+    #[test]
+    fn isolated_pawns() {
+        let iqp = evaluate(&position("4k3/pp3ppp/4p3/8/3P4/8/PP3PPP/4K3 w - - 0 1"));
+        assert_eq!(iqp.score[Color::White as usize], ISOLATED_PAWN);
+        assert_eq!(iqp.exposed[Color::White as usize], Bitboard::from_square(shakmaty::Square::D4));
+        // With a black pawn on the d-file in front of it, it cannot be attacked from the file
+        let closed = evaluate(&position("4k3/pp1p1ppp/8/8/3P4/8/PP3PPP/4K3 w - - 0 1"));
+        assert_eq!(closed.score[Color::White as usize], ISOLATED_PAWN);
+        assert_eq!(closed.exposed[Color::White as usize], Bitboard::EMPTY);
+    }
+
+
+    // Two against one on the queenside: the c-pawn becomes passed after trading the b-pawns. A pawn
+    // blocked on its file, or outnumbered on the adjacent files, is no candidate.
+    #[test]
+    fn candidate_passers() {
+        let candidate = CANDIDATE_PASSER[Rank::Fourth as usize];
+        assert_eq!(score("4k3/8/1p6/8/1PP5/8/8/4K3 w - - 0 1", Color::White), candidate);
+        assert_eq!(score("4k3/8/1pp5/8/1PP5/8/8/4K3 w - - 0 1", Color::White), Tapered::default());
+        assert_eq!(score("4k3/8/1p1p4/8/2P5/8/8/4K3 w - - 0 1", Color::White), ISOLATED_PAWN);
+        // The same for Black, from its side of the board
+        assert_eq!(score("4k3/8/8/1pp5/8/1P6/8/4K3 w - - 0 1", Color::Black), candidate);
+    }
+
+
+    // Over random games, where most moves leave the pawns alone, the table gives the same entries as
+    // computing them again
+    #[test]
+    fn cached_entries() {
+        let mut seed = 0;
+        for _ in 0..20 {
+            let mut pos = Chess::default();
+            for _ in 0..200 {
+                assert_eq!(probe(&pos), evaluate(&pos), "{}", Fen::from_position(pos.clone(), shakmaty::EnPassantMode::Legal));
+                let moves = pos.legal_moves();
+                if moves.is_empty() {
+                    break;
+                }
+                seed = crate::util::random::splitmix64(seed);
+                pos.play_unchecked(&moves[seed as usize % moves.len()]);
+            }
+        }
+    }
+
+
+    // A king on g1 behind f2, g2 and h2 is fully sheltered; without the g- and h-pawns it is not, and
+    // an advanced pawn shelters less than one on its starting square
+    #[test]
+    fn king_shelter() {
+        let shelter = |fen, color| super::shelter(&position(fen), color);
+        assert_eq!(shelter("4k3/8/8/8/8/8/5PPP/6K1 w - - 0 1", Color::White), Tapered::default());
+        assert_eq!(shelter("4k3/8/8/8/8/8/5P2/6K1 w - - 0 1", Color::White), Tapered::new(3 * SHELTER[0], 0));
+        assert_eq!(shelter("4k3/8/8/8/6P1/8/5P1P/6K1 w - - 0 1", Color::White), Tapered::new(2 * SHELTER[3], 0));
+        // Pawns behind the king do not shelter it
+        assert_eq!(shelter("4k3/8/8/8/8/6K1/5PPP/8 w - - 0 1", Color::White), Tapered::new(4 * SHELTER[0], 0));
+        // The same for Black
+        assert_eq!(shelter("6k1/5p2/8/8/8/8/8/4K3 w - - 0 1", Color::Black), Tapered::new(3 * SHELTER[0], 0));
+    }
+
+}
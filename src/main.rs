@@ -16,36 +16,175 @@
 //! - [`Quit`](ruci::Quit)
 
 use ruci::gui::Message;
-use ruci::{BestMove, Depth, Gui, Id, Info, NormalBestMove, Option, ReadyOk, UciOk};
+use ruci::{BestMove, Depth, Gui, Id, Info, NormalBestMove, Option, ReadyOk, Refutation, UciOk};
 use shakmaty::uci::{IllegalUciMoveError, UciMove};
-use shakmaty::{CastlingMode, Chess, Position};
+use shakmaty::fen::Fen;
+use shakmaty::variant::{Variant, VariantPosition};
+use shakmaty::{CastlingMode, EnPassantMode, Position};
+use search::SearchControl;
 use std::borrow::Cow;
 use std::io::{self, stdin, stdout};
 use std::io::{BufRead, Write};
-use std::sync::RwLock;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::Ordering;
+use std::sync::{Mutex, RwLock};
+use std::thread::ScopedJoinHandle;
 
 mod bench;
+mod datagen;
 mod search;
-// mod position;
+mod position;
 mod eval;
+mod symmetry;
 mod time;
 mod util;
 
 struct State {
-    position: Chess,
-    history: Vec<Chess>,
-    tt: RwLock<search::tt::TT>,
+    position: position::Position<VariantPosition>, // with the keys of the game positions before it, for repetitions
+    tt: RwLock<std::option::Option<search::tt::TT>>, // allocated on the first search, and kept for the whole game
+    hash_mb: usize,
+    tables: Mutex<search::SearchTables>, // histories, kept between the searches of a game
     config: Configuration,
+    game_seed: u64, // root noise seed for the current game, unless a seed is configured
+    exclude_moves: Vec<UciMove>, // root moves that are never searched (the inverse of searchmoves)
 }
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+// Variants for the UCI_Variant option. Other than standard chess, they are evaluated by material only.
+const VARIANTS: [Variant; 4] = [Variant::Chess, Variant::ThreeCheck, Variant::Antichess, Variant::Atomic];
+
+#[derive(Clone, Copy)]
 struct Configuration {
     threads: usize,
+    multipv: usize,
+    contempt: i16, // draw score penalty in centipawns, for the engine itself
+    analyse_mode: bool,
+    show_refutations: bool,
+    chess960: bool, // castling moves are sent and received as the king taking its own rook
+    variant: Variant, // one of VARIANTS
+    debug: bool, // report search statistics after each search
+    root_noise: i16, // maximum random bonus for root moves in centipawns; 0 disables it
+    root_noise_moves: usize, // only add root noise up to this full move number
+    root_noise_seed: u64, // 0 picks a new random seed for every game
+    use_tt: bool,
+    qsearch_see_threshold: i16,
+    qsearch_checks: bool,
+    qsearch_check_plies: usize, // quiet checks are only generated at the first plies of qsearch
+    lazy_eval: bool,
+    lazy_eval_margin: i16, // qsearch only uses material and PST when they are this far outside the window
+    see_pruning: bool,
+    see_pruning_depth: isize,
+    see_capture_margin: i16, // per ply of depth
+    see_quiet_margin: i16,   // per ply of depth
+    null_move: bool,
+    null_move_base: isize,           // R = base + depth / depth_divisor + min((eval - beta) / eval_divisor, eval_max)
+    null_move_depth_divisor: isize,
+    null_move_eval_divisor: i32,
+    null_move_eval_max: isize,
+    null_move_verification: bool,
+    null_move_verification_depth: isize,
+    aspiration_depth: isize,
+    aspiration_window: i16,
+    improving: bool,
+    iir: bool,
+    iir_depth: isize,
+    iir_pv: bool,
+    iir_non_pv: bool,
+    cutnode: bool, // more aggressive IIR and LMR at expected cut nodes
+    max_extensions: isize, // per line, excluding check extensions
+    recapture_extension: bool,
+    pawn_push_extension: bool,
+    singular: bool,
+    singular_depth: isize,
+    singular_margin: i16, // per ply of depth
+    double_extension: bool,
+    double_extension_margin: i16,
+    max_double_extensions: isize, // per line
+    futility: bool,
+    futility_depth: isize,
+    futility_margin: i16, // per ply of depth
+    lmp: bool,
+    history_pruning: bool,
+    history_pruning_depth: isize,
+    history_pruning_min_moves: usize,
+    history_pruning_margin: i32, // per ply of depth
+    lmr: bool,
+    lmr_min_moves: usize,
+    lmr_base: f64,
+    lmr_divisor: f64,
+    correction: bool, // learn corrections to the static eval from search results
+    #[cfg(test)]
+    panic_at_node: u64, // makes the search panic after this many nodes; 0 never does
 }
 
+const DEFAULT_HASH_MB: usize = 16;
+const MAX_HASH_MB: usize = 33_554_432;
+const MAX_THREADS: usize = 256;
+const MAX_CONTEMPT: i16 = 200;
+const MAX_ROOT_NOISE: i16 = 100;
+
 const DEFAULT_CONFIG: Configuration = Configuration {
     threads: 1,
+    multipv: 1,
+    contempt: 0,
+    analyse_mode: false,
+    chess960: false,
+    variant: Variant::Chess,
+    show_refutations: false,
+    debug: false,
+    root_noise: 0,
+    root_noise_moves: 8,
+    root_noise_seed: 0,
+    use_tt: true,
+    qsearch_see_threshold: 0,
+    qsearch_checks: true,
+    qsearch_check_plies: 1,
+    lazy_eval: true,
+    lazy_eval_margin: 400,
+    see_pruning: true,
+    see_pruning_depth: 6,
+    see_capture_margin: -90,
+    see_quiet_margin: -50,
+    null_move: true,
+    null_move_base: 3,
+    null_move_depth_divisor: 3,
+    null_move_eval_divisor: 200,
+    null_move_eval_max: 3,
+    null_move_verification: true,
+    null_move_verification_depth: 8,
+    aspiration_depth: 5,
+    aspiration_window: 25,
+    improving: true,
+    iir: true,
+    iir_depth: 4,
+    iir_pv: true,
+    iir_non_pv: true,
+    cutnode: true,
+    max_extensions: 4,
+    recapture_extension: true,
+    pawn_push_extension: true,
+    singular: true,
+    singular_depth: 8,
+    singular_margin: 2,
+    double_extension: true,
+    double_extension_margin: 20,
+    max_double_extensions: 3,
+    futility: true,
+    futility_depth: 3,
+    futility_margin: 100,
+    lmp: true,
+    history_pruning: true,
+    history_pruning_depth: 3,
+    history_pruning_min_moves: 3,
+    history_pruning_margin: 4000,
+    lmr: true,
+    lmr_min_moves: 3,
+    lmr_base: 0.75,
+    lmr_divisor: 2.25,
+    correction: true,
+    #[cfg(test)]
+    panic_at_node: 0,
 };
 // struct Option {
 //     name: &'static str,
@@ -60,171 +199,498 @@ const DEFAULT_CONFIG: Configuration = Configuration {
 // }
 
 /// Starts a new engine that forever reads messages, unless told to quit.
+///
+/// Searches run on a separate thread, so that `stop` and `quit` are handled while searching.
 pub fn engine<E, G>(engine: E, gui: G) -> io::Result<()>
 where
-    E: Write,
+    E: Write + Send,
     G: BufRead,
 {
-    let mut gui = Gui { engine, gui };
+    let output = Mutex::new(engine);
+    let mut gui = Gui { engine: SharedWriter(&output), gui };
     let mut state = State {
-        position: Chess::new(),
-        history: Vec::new(),
-        tt: RwLock::new(search::tt::TT::new(1 << 20)),
-        config: DEFAULT_CONFIG
+        position: position::Position::new(VariantPosition::new(Variant::Chess)),
+        tt: RwLock::new(None),
+        hash_mb: DEFAULT_HASH_MB,
+        tables: Mutex::new(search::SearchTables::default()),
+        config: DEFAULT_CONFIG,
+        game_seed: util::random::clock_seed(),
+        exclude_moves: Vec::new(),
     };
+    let control = SearchControl::default();
 
     gui.send_string("engine started")?;
 
-    loop {
-        let message = gui.read();
+    std::thread::scope(|scope| {
+        let mut search_thread = None;
+        let mut search_start = std::time::Instant::now();
+        loop {
+            let message = gui.read();
 
-        let message = match message {
-            Ok(m) => m,
-            Err(e) => {
-                gui.send_string(&e.to_string())?;
-                continue;
-            }
-        };
+            let message = match message {
+                Ok(m) => m,
+                // Not UCI: print the evaluation of the current position term by term, for debugging
+                Err(ruci::ReadError::Parse { got, .. }) if got.trim() == "eval" => {
+                    match state.position.pos() {
+                        VariantPosition::Chess(chess) => writeln!(gui.engine, "{}", eval::trace(chess))?,
+                        _ => gui.send_string("eval is only available for standard chess")?,
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    gui.send_string(&e.to_string())?;
+                    continue;
+                }
+            };
 
-        match message {
-            Message::SetOption(opt) => match opt.name.as_ref() {
-                "Hash" => {
-                    let hash_size_mb: usize = opt.value.and_then(|s| s.parse().ok()).unwrap();
-                    let tt = search::tt::TT::new((hash_size_mb * 1024 * 1024) / 8);
-                    {
-                        let mut tt_lock = state.tt.write().unwrap();
-                        *tt_lock = tt;
+            match message {
+                Message::SetOption(opt) => {
+                    stop_search(&control, &mut search_thread);
+                    match opt.name.as_ref() {
+                        "Hash" => {
+                            let hash_size_mb: usize = opt.value.and_then(|s| s.parse().ok()).unwrap();
+                            state.hash_mb = hash_size_mb.clamp(1, MAX_HASH_MB);
+                            // Free the old table now; the new one is allocated by the next search, so
+                            // setting the option several times does not allocate several tables
+                            *state.tt.write().unwrap() = None;
+                        }
+                        "Threads" => {
+                            let num_threads: usize = opt.value.and_then(|s| s.parse().ok()).unwrap();
+                            state.config.threads = num_threads.clamp(1, MAX_THREADS);
+                        }
+                        "Contempt" => {
+                            let contempt: i16 = opt.value.and_then(|s| s.parse().ok()).unwrap();
+                            state.config.contempt = contempt.clamp(-MAX_CONTEMPT, MAX_CONTEMPT);
+                        }
+                        "UCI_AnalyseMode" => {
+                            state.config.analyse_mode = opt.value.is_some_and(|s| s == "true");
+                        }
+                        "UCI_ShowRefutations" => {
+                            state.config.show_refutations = opt.value.is_some_and(|s| s == "true");
+                        }
+                        "UCI_Chess960" => {
+                            // Takes effect from the next position command
+                            state.config.chess960 = opt.value.is_some_and(|s| s == "true");
+                        }
+                        "UCI_Variant" => {
+                            // Takes effect from the next position command
+                            let name = opt.value.as_deref().unwrap_or("");
+                            match Variant::from_uci(name) {
+                                Ok(variant) if VARIANTS.contains(&variant) => {
+                                    state.config.variant = variant;
+                                    // The same board has another evaluation and other moves in another variant
+                                    *state.tt.write().unwrap() = None;
+                                }
+                                _ => gui.send_string(&format!("unsupported variant: {name}"))?,
+                            }
+                        }
+                        "Ponder" => {
+                            // Nothing to configure: the GUI tells us when to ponder with `go ponder`
+                        }
+                        "RootNoise" => {
+                            let noise: i16 = opt.value.and_then(|s| s.parse().ok()).unwrap();
+                            state.config.root_noise = noise.clamp(0, MAX_ROOT_NOISE);
+                        }
+                        "RootNoiseMoves" => {
+                            state.config.root_noise_moves = opt.value.and_then(|s| s.parse().ok()).unwrap();
+                        }
+                        "RootNoiseSeed" => {
+                            state.config.root_noise_seed = opt.value.and_then(|s| s.parse().ok()).unwrap();
+                        }
+                        "ExcludeMoves" => {
+                            state.exclude_moves.clear();
+                            for word in opt.value.as_deref().unwrap_or("").split_whitespace() {
+                                match word.parse() {
+                                    Ok(m) => state.exclude_moves.push(m),
+                                    Err(e) => gui.send_string(&format!("ignoring excluded move {word}: {e}"))?,
+                                }
+                            }
+                        }
+                        "EvalFile" => {
+                            let path = opt.value.as_deref().unwrap_or("").trim();
+                            if path.is_empty() {
+                                eval::nnue::unload();
+                            } else if let Err(e) = eval::nnue::load(path) {
+                                gui.send_string(&format!("could not load {path}: {e}; using the classical evaluation"))?;
+                            } else {
+                                gui.send_string(&format!("loaded network {path}"))?;
+                            }
+                            // The TT stores static evaluations, which are not valid for another evaluation
+                            *state.tt.write().unwrap() = None;
+                        }
+                        "Clear Hash" => {
+                            if let Some(tt) = state.tt.write().unwrap().as_ref() {
+                                tt.clear(state.config.threads);
+                            }
+                            state.tables.lock().unwrap().clear();
+                        }
+                        "MultiPV" => {
+                            let lines: usize = opt.value.and_then(|s| s.parse().ok()).unwrap();
+                            state.config.multipv = lines.max(1);
+                        }
+                        _ => {
+                            gui.send_string(&format!("unknown option: {}", opt.name))?;
+                        }
                     }
                 }
-                "Threads" => {
-                    let num_threads: usize = opt.value.and_then(|s| s.parse().ok()).unwrap();
-                    if num_threads != 1 {
-                        gui.send_string("only 1 thread supported")?;
-                    } else {
-                        state.config.threads = num_threads;
+                Message::Debug(debug) => {
+                    // Takes effect from the next search
+                    state.config.debug = debug.0;
+                }
+                Message::UciNewGame(_) => {
+                    stop_search(&control, &mut search_thread);
+                    if let Some(tt) = state.tt.write().unwrap().as_ref() {
+                        tt.clear(state.config.threads);
                     }
+                    state.tables.lock().unwrap().clear();
+                    state.game_seed = util::random::clock_seed();
                 }
-                _ => {
-                    gui.send_string(&format!("unknown option: {}", opt.name))?;
+                Message::Quit(_) => {
+                    stop_search(&control, &mut search_thread);
+                    return Ok(());
                 }
-            },
-            Message::Quit(_) => return Ok(()),
-            Message::Position(position) => {
-                let (position, moves) = match position {
-                    ruci::Position::StartPos { moves } => (Chess::new(), moves),
-                    ruci::Position::Fen { moves, fen } => {
-                        match fen.into_owned().into_position(CastlingMode::Standard) {
-                            Ok(p) => (p, moves),
-                            Err(e) => {
-                                gui.send_string(&format!("error parsing FEN: {e}"))?;
-                                continue;
+                Message::Position(position) => {
+                    // Only expected when pondering on the wrong move; the search is no longer needed
+                    stop_search(&control, &mut search_thread);
+                    let (position, moves) = match position {
+                        ruci::Position::StartPos { moves } => (VariantPosition::new(state.config.variant), moves),
+                        ruci::Position::Fen { moves, fen } => {
+                            let (setup, mode) = (fen.into_owned().into_setup(), CastlingMode::from_chess960(state.config.chess960));
+                            match VariantPosition::from_setup(state.config.variant, setup, mode) {
+                                Ok(p) => (p, moves),
+                                Err(e) => {
+                                    gui.send_string(&format!("error parsing FEN: {e}"))?;
+                                    continue;
+                                }
                             }
                         }
-                    }
-                };
+                    };
 
-                match moves.iter().try_fold(
-                    (position, Vec::new()),
-                    |(mut position, mut history), r#move| {
-                        history.push(position.clone());
+                    match moves.iter().try_fold(position::Position::new(position), |mut position, r#move| {
                         let r#move = r#move.to_move(&position)?;
                         position.play_unchecked(&r#move);
-                        Ok::<_, IllegalUciMoveError>((position, history))
-                    },
-                ) {
-                    Ok((position, history)) => {
-                        state.position = position;
-                        state.history = history;
-                        gui.send_string("position set")?;
+                        Ok::<_, IllegalUciMoveError>(position)
+                    }) {
+                        Ok(position) => {
+                            state.position = position;
+                            gui.send_string("position set")?;
+                        }
+                        Err(e) => {
+                            gui.send_string(&format!("error converting UCI move to valid move: {e}"))?;
+                        }
                     }
-                    Err(e) => {
-                        gui.send_string(&format!("error converting UCI move to valid move: {e}"))?;
+                }
+                Message::Go(go) => {
+                    stop_search(&control, &mut search_thread);
+                    if state.position.legal_moves().is_empty() || state.position.is_variant_end() {
+                        let null =
+                            BestMove::Normal(NormalBestMove { r#move: UciMove::Null, ponder: None });
+                        gui.send(null)?;
+                        continue;
+                    }
+
+                    search_start = std::time::Instant::now();
+                    let tc = time::TimeControl::from_ruci(state.position.turn(), &go);
+                    let deadline = match tc {
+                        Some(tc) => time::Deadline::from_tc(&tc, search_start),
+                        None => time::Deadline::Depth(6),
+                    };
+
+                    // Restrict the root moves for `go searchmoves`, skipping any moves that are not legal
+                    let mut root_moves = Vec::new();
+                    for uci_move in go.search_moves.iter() {
+                        match uci_move.to_move(&state.position) {
+                            Ok(m) => root_moves.push(m),
+                            Err(e) => gui.send_string(&format!("ignoring searchmove {uci_move}: {e}"))?,
+                        }
                     }
+
+                    // Remove the moves excluded with the ExcludeMoves option, unless that leaves nothing
+                    let excluded: Vec<_> =
+                        state.exclude_moves.iter().filter_map(|m| m.to_move(&state.position).ok()).collect();
+                    if !excluded.is_empty() {
+                        if root_moves.is_empty() {
+                            root_moves = state.position.legal_moves().into_iter().collect();
+                        }
+                        let remaining: Vec<_> = root_moves.iter().filter(|m| !excluded.contains(m)).cloned().collect();
+                        if remaining.is_empty() {
+                            gui.send_string("all moves are excluded; ignoring ExcludeMoves")?;
+                        } else {
+                            let names: Vec<_> = excluded.iter().map(|m| m.to_uci(CastlingMode::from_chess960(state.config.chess960)).to_string()).collect();
+                            gui.send_string(&format!("excluding moves {}", names.join(" ")))?;
+                            root_moves = remaining;
+                        }
+                    }
+
+                    let (position, mut config, mate) = (state.position.clone(), state.config, go.mate);
+                    if config.root_noise_seed == 0 {
+                        config.root_noise_seed = state.game_seed;
+                    }
+                    state.tt.write().unwrap().get_or_insert_with(|| search::tt::TT::with_megabytes(state.hash_mb));
+                    let (tt, tables, control, output) = (&state.tt, &state.tables, &control, &output);
+                    control.stop.store(false, Ordering::Relaxed);
+                    control.pondering.store(go.ponder, Ordering::Relaxed);
+                    control.ponder_time.store(0, Ordering::Relaxed);
+                    let thread = std::thread::Builder::new()
+                        .stack_size(search::STACK_SIZE)
+                        .spawn_scoped(scope, move || match position.pos() {
+                            // Standard chess gets a search of its own, without the dispatch of VariantPosition
+                            VariantPosition::Chess(_) => {
+                                let position = position.map(|pos| match pos {
+                                    VariantPosition::Chess(chess) => chess,
+                                    _ => unreachable!(),
+                                });
+                                run_search(position, root_moves, deadline, mate, tt, tables, &config, control, output)
+                            }
+                            _ => run_search(position, root_moves, deadline, mate, tt, tables, &config, control, output),
+                        })?;
+                    search_thread = Some(thread);
                 }
-            }
-            Message::Go(go) => {
-                if state.position.legal_moves().first().is_none() {
-                    let null =
-                        BestMove::Normal(NormalBestMove { r#move: UciMove::Null, ponder: None });
-                    gui.send(null)?;
+                Message::PonderHit(_) => {
+                    // The opponent played the expected move: from now on, the clock is ours
+                    let ponder_time = search_start.elapsed().as_millis() as u64;
+                    control.ponder_time.store(ponder_time, Ordering::Relaxed);
+                    control.pondering.store(false, Ordering::Relaxed);
+                }
+                Message::Stop(_) => {
+                    stop_search(&control, &mut search_thread);
                 }
+                Message::Uci(_) => {
+                    let name = format!("Davies {}", VERSION);
+                    let id_name = Id::Name(Cow::Borrowed(&name));
+                    let id_author = Id::Author(Cow::Borrowed("Quinten Kock"));
 
-                let tc = time::TimeControl::from_ruci(state.position.turn(), &go);
-                let deadline = match tc {
-                    Some(tc) => time::Deadline::from_tc(&tc, std::time::Instant::now()),
-                    None => time::Deadline::Depth(6),
-                };
+                    gui.send(id_name)?;
+                    gui.send(id_author)?;
 
-                let starttime = std::time::Instant::now();
-                let tt = state.tt.read().unwrap();
-                let (_score, mut pv, _count) = search::search(
-                    state.position.clone(),
-                    state.history.clone(),
-                    deadline,
-                    &tt,
-                    &state.config,
-                    &mut |depth, score, pv, count| {
-                        let elapsed = starttime.elapsed().as_millis() as u64;
-                        let nodes = count.count();
-                        let nps = nodes * 1000 / elapsed.max(1);
-                        let info = Info {
-                            depth: Some(Depth { depth: depth as usize, seldepth: Some(count.seldepth() as usize) }),
-                            pv: Cow::Owned(
-                                pv.iter()
-                                    .map(|m| m.to_uci(CastlingMode::Standard))
-                                    .collect(),
-                            ),
-                            score: Some(ruci::ScoreWithBound { kind: score, bound: None }),
-                            nodes: Some(nodes as usize),
-                            nps: Some(nps as usize),
-                            hash_full: Some(tt.hashfull() as usize),
-                            time: Some(elapsed as usize),
-                            ..Default::default()
-                        };
-                        gui.send(info).unwrap();
-                    },
-                );
-                let bestmove = pv.first().cloned();
-                if let Some(mv) = bestmove {
-                    let best_move = BestMove::Normal(NormalBestMove {
-                        r#move: mv.to_uci(CastlingMode::Standard),
-                        ponder: None,
-                    });
-                    gui.send(best_move)?;
-                } else {
-                    let null =
-                        BestMove::Normal(NormalBestMove { r#move: UciMove::Null, ponder: None });
-                    gui.send(null)?;
+                    gui.send(Option {
+                        name: std::borrow::Cow::Borrowed("Hash"),
+                        r#type: ruci::OptionType::Spin {
+                            default: Some(DEFAULT_HASH_MB as i64),
+                            min: Some(1),
+                            max: Some(MAX_HASH_MB as i64),
+                        },
+                    })?;
+                    gui.send(Option {
+                        name: std::borrow::Cow::Borrowed("Clear Hash"),
+                        r#type: ruci::OptionType::Button,
+                    })?;
+                    gui.send(Option {
+                        name: std::borrow::Cow::Borrowed("Threads"),
+                        r#type: ruci::OptionType::Spin { default: Some(DEFAULT_CONFIG.threads as i64), min: Some(1), max: Some(MAX_THREADS as i64) },
+                    })?;
+                    gui.send(Option {
+                        name: std::borrow::Cow::Borrowed("Ponder"),
+                        r#type: ruci::OptionType::Check { default: Some(false) },
+                    })?;
+                    gui.send(Option {
+                        name: std::borrow::Cow::Borrowed("MultiPV"),
+                        r#type: ruci::OptionType::Spin { default: Some(DEFAULT_CONFIG.multipv as i64), min: Some(1), max: Some(256) },
+                    })?;
+                    gui.send(Option {
+                        name: std::borrow::Cow::Borrowed("Contempt"),
+                        r#type: ruci::OptionType::Spin {
+                            default: Some(DEFAULT_CONFIG.contempt as i64),
+                            min: Some(-MAX_CONTEMPT as i64),
+                            max: Some(MAX_CONTEMPT as i64),
+                        },
+                    })?;
+                    gui.send(Option {
+                        name: std::borrow::Cow::Borrowed("RootNoise"),
+                        r#type: ruci::OptionType::Spin {
+                            default: Some(DEFAULT_CONFIG.root_noise as i64),
+                            min: Some(0),
+                            max: Some(MAX_ROOT_NOISE as i64),
+                        },
+                    })?;
+                    gui.send(Option {
+                        name: std::borrow::Cow::Borrowed("RootNoiseMoves"),
+                        r#type: ruci::OptionType::Spin {
+                            default: Some(DEFAULT_CONFIG.root_noise_moves as i64),
+                            min: Some(0),
+                            max: Some(100),
+                        },
+                    })?;
+                    gui.send(Option {
+                        name: std::borrow::Cow::Borrowed("RootNoiseSeed"),
+                        r#type: ruci::OptionType::Spin {
+                            default: Some(DEFAULT_CONFIG.root_noise_seed as i64),
+                            min: Some(0),
+                            max: Some(i64::MAX),
+                        },
+                    })?;
+                    gui.send(Option {
+                        name: std::borrow::Cow::Borrowed("ExcludeMoves"),
+                        r#type: ruci::OptionType::String { default: Some(Cow::Borrowed("")) },
+                    })?;
+                    gui.send(Option {
+                        name: std::borrow::Cow::Borrowed("EvalFile"),
+                        r#type: ruci::OptionType::String { default: Some(Cow::Borrowed("")) },
+                    })?;
+                    gui.send(Option {
+                        name: std::borrow::Cow::Borrowed("UCI_AnalyseMode"),
+                        r#type: ruci::OptionType::Check { default: Some(DEFAULT_CONFIG.analyse_mode) },
+                    })?;
+                    gui.send(Option {
+                        name: std::borrow::Cow::Borrowed("UCI_ShowRefutations"),
+                        r#type: ruci::OptionType::Check { default: Some(DEFAULT_CONFIG.show_refutations) },
+                    })?;
+                    gui.send(Option {
+                        name: std::borrow::Cow::Borrowed("UCI_Chess960"),
+                        r#type: ruci::OptionType::Check { default: Some(DEFAULT_CONFIG.chess960) },
+                    })?;
+                    let variants: Vec<_> = VARIANTS.iter().map(|v| Cow::Borrowed(v.uci())).collect();
+                    gui.send(Option {
+                        name: std::borrow::Cow::Borrowed("UCI_Variant"),
+                        r#type: ruci::OptionType::Combo {
+                            default: Some(Cow::Borrowed(DEFAULT_CONFIG.variant.uci())),
+                            var: Cow::Owned(variants),
+                        },
+                    })?;
+                    gui.send(UciOk)?;
+                }
+                Message::IsReady(_) => {
+                    gui.send(ReadyOk)?;
                 }
+                _ => gui.send_string("unsupported message")?,
             }
-            Message::Uci(_) => {
-                let name = format!("Davies {}", VERSION);
-                let id_name = Id::Name(Cow::Borrowed(&name));
-                let id_author = Id::Author(Cow::Borrowed("Quinten Kock"));
-
-                gui.send(id_name)?;
-                gui.send(id_author)?;
-
-                gui.send(Option {
-                    name: std::borrow::Cow::Borrowed("Hash"),
-                    r#type: ruci::OptionType::Spin {
-                        default: Some(8),
-                        min: Some(1),
-                        max: Some(33_554_432),
-                    },
-                })?;
-                gui.send(Option {
-                    name: std::borrow::Cow::Borrowed("Threads"),
-                    r#type: ruci::OptionType::Spin { default: Some(DEFAULT_CONFIG.threads as i64), min: Some(1), max: Some(1) },
-                })?;
-                gui.send(UciOk)?;
+        }
+    })
+}
+
+// Writer shared between the engine loop and the search thread. Every message is written while
+// holding the lock, so messages from both never interleave.
+struct SharedWriter<'a, E>(&'a Mutex<E>);
+
+impl<E: Write> Write for SharedWriter<'_, E> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        let mut engine = self.0.lock().unwrap();
+        engine.write_all(buf)?;
+        engine.flush()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+// Stops the running search (if any) and waits for it to send its best move. Never waits for the
+// search to end by itself: an infinite or ponder search only ends when stopped.
+fn stop_search(control: &SearchControl, search_thread: &mut std::option::Option<ScopedJoinHandle<()>>) {
+    control.stop.store(true, Ordering::Relaxed);
+    if let Some(thread) = search_thread.take() {
+        thread.join().unwrap();
+    }
+}
+
+// Searches the given position and reports the result, to be run on the search thread
+#[allow(clippy::too_many_arguments)]
+fn run_search<P: search::Searchable, E: Write>(
+    position: position::Position<P>,
+    root_moves: Vec<shakmaty::Move>,
+    deadline: time::Deadline,
+    mate: std::option::Option<usize>,
+    tt: &RwLock<std::option::Option<search::tt::TT>>,
+    tables: &Mutex<search::SearchTables>,
+    config: &Configuration,
+    control: &SearchControl,
+    output: &Mutex<E>,
+) {
+    let mut gui = Gui { engine: SharedWriter(output), gui: io::empty() };
+    let mut refutation_gui = Gui { engine: SharedWriter(output), gui: io::empty() };
+    let infinite = matches!(deadline, time::Deadline::None);
+    let starttime = std::time::Instant::now();
+    let tt = tt.read().unwrap();
+    let tt = tt.as_ref().expect("the TT is allocated before starting a search");
+    let mut tables = tables.lock().unwrap();
+    let root = position.pos().clone();
+    let mode = CastlingMode::from_chess960(config.chess960);
+    let mut last_pv = Vec::new();
+    // A bug in the search must not cost the game, so a panic is caught and reported, and the best
+    // move found so far (or any legal move) is played instead
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        search::search(
+            position,
+            &root_moves,
+            deadline,
+            mate,
+            tt,
+            config,
+            &mut tables,
+            control,
+            &mut |depth, line, score, bound, pv, count| {
+                if line == 1 && !pv.is_empty() {
+                    last_pv.clone_from(pv);
+                }
+                let elapsed = starttime.elapsed().as_millis() as u64;
+                let nodes = count.count();
+                let nps = nodes * 1000 / elapsed.max(1);
+                let info = Info {
+                    depth: Some(Depth { depth: depth as usize, seldepth: Some(count.seldepth() as usize) }),
+                    pv: Cow::Owned(
+                        pv.iter()
+                            .map(|m| m.to_uci(mode))
+                            .collect(),
+                    ),
+                    multi_pv: Some(line),
+                    score: Some(ruci::ScoreWithBound { kind: score, bound }),
+                    nodes: Some(nodes as usize),
+                    nps: Some(nps as usize),
+                    hash_full: Some(tt.hashfull()),
+                    time: Some(elapsed as usize),
+                    ..Default::default()
+                };
+                gui.send(info).unwrap();
+            },
+            Some(&mut |refuted, line| {
+                let refutation = Refutation {
+                    refuted_move: refuted.to_uci(mode),
+                    refutation: Cow::Owned(line.iter().map(|m| m.to_uci(mode)).collect()),
+                };
+                let info = Info { refutation: Some(refutation), ..Default::default() };
+                refutation_gui.send(info).unwrap();
+            }),
+        )
+    }));
+    let pv = match result {
+        Ok((_score, pv, count, stats)) => {
+            if config.debug {
+                for line in stats.summary(&count) {
+                    gui.send_string(&line).unwrap();
+                }
             }
-            Message::IsReady(_) => {
-                gui.send(ReadyOk)?;
+            pv
+        }
+        Err(payload) => {
+            let message = payload
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown error");
+            let fen = Fen::from_position(root.clone(), EnPassantMode::Legal);
+            gui.send_string(&format!("search panicked: {message} (position {fen})")).unwrap();
+            if last_pv.is_empty() {
+                root.legal_moves().into_iter().take(1).collect()
+            } else {
+                last_pv
             }
-            _ => gui.send_string("unsupported message")?,
         }
+    };
+    // In an infinite search or while pondering, the best move may only be sent after `stop` (or
+    // `ponderhit`), even if the search itself ended early (e.g. by reaching the maximum depth)
+    while (infinite || control.pondering.load(Ordering::Relaxed)) && !control.stop.load(Ordering::Relaxed) {
+        std::thread::sleep(std::time::Duration::from_millis(1));
     }
+    let best_move = match pv.first() {
+        Some(mv) => mv.to_uci(mode),
+        None => UciMove::Null,
+    };
+    let ponder = pv.get(1).map(|mv| mv.to_uci(mode));
+    gui.send(BestMove::Normal(NormalBestMove { r#move: best_move, ponder })).unwrap();
 }
 
 pub fn main() {
@@ -232,6 +698,454 @@ pub fn main() {
         bench::bench();
         return;
     }
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).is_some_and(|arg| arg == "datagen") {
+        datagen::datagen(&args[2..]);
+        return;
+    }
+    if let Some(i) = args.iter().position(|arg| arg == "symmetry") {
+        symmetry::symmetry(args.get(i + 1).map(String::as_str));
+        return;
+    }
+
+    engine(stdout(), stdin().lock()).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    // Output of the engine, shared with the test
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // An engine talked to through in-memory pipes, like a GUI would
+    struct Session {
+        input: io::PipeWriter,
+        output: Output,
+        engine: std::thread::JoinHandle<io::Result<()>>,
+    }
+
+    // How long to wait for an answer before declaring the engine stuck
+    const TIMEOUT: Duration = Duration::from_secs(30);
+
+    impl Session {
+        fn new() -> Session {
+            let (reader, input) = io::pipe().unwrap();
+            let output = Output::default();
+            let engine_output = output.clone();
+            let engine = std::thread::Builder::new()
+                .stack_size(search::STACK_SIZE)
+                .spawn(move || engine(engine_output, io::BufReader::new(reader)))
+                .unwrap();
+            Session { input, output, engine }
+        }
+
+        fn send(&mut self, line: &str) {
+            writeln!(self.input, "{line}").unwrap();
+        }
+
+        fn output(&self) -> String {
+            String::from_utf8(self.output.0.lock().unwrap().clone()).unwrap()
+        }
+
+        // Waits until the engine has sent a line matching the predicate, returning the output so far
+        fn wait_for(&self, predicate: impl Fn(&str) -> bool) -> String {
+            let start = Instant::now();
+            loop {
+                let output = self.output();
+                if output.lines().any(&predicate) {
+                    return output;
+                }
+                assert!(start.elapsed() < TIMEOUT, "engine stuck, output so far:\n{output}");
+                std::thread::sleep(Duration::from_millis(5));
+            }
+        }
+
+        // Number of best moves sent so far
+        fn best_moves(&self) -> usize {
+            self.output().lines().filter(|line| line.starts_with("bestmove")).count()
+        }
+
+        // Waits until the engine has sent the given number of best moves in total
+        fn wait_for_best_moves(&self, count: usize) -> String {
+            let start = Instant::now();
+            while self.best_moves() < count {
+                assert!(start.elapsed() < TIMEOUT, "engine stuck, output so far:\n{}", self.output());
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            self.output()
+        }
+
+        // Sends quit and waits for the engine to end, returning its output
+        fn quit(mut self) -> String {
+            self.send("quit");
+            let start = Instant::now();
+            while !self.engine.is_finished() {
+                assert!(start.elapsed() < TIMEOUT, "engine did not quit, output so far:\n{}", self.output());
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            let output = self.output();
+            self.engine.join().unwrap().unwrap();
+            output
+        }
+    }
 
-    engine(stdout().lock(), stdin().lock()).unwrap();
+    // The value after a keyword in an info line
+    fn field(line: &str, name: &str) -> std::option::Option<u64> {
+        let mut words = line.split_whitespace();
+        words.find(|w| *w == name)?;
+        words.next()?.parse().ok()
+    }
+
+    // Every iteration reports nodes, nps, hashfull and time. Within a search, nodes go up, and depth,
+    // time and hashfull never go down (the depth repeats for aspiration window failures).
+    #[test]
+    fn info_fields() {
+        let mut session = Session::new();
+        session.send("position startpos moves e2e4");
+        session.send("go depth 7");
+        let output = session.wait_for_best_moves(1);
+        session.quit();
+        let lines: Vec<&str> = output.lines().filter(|line| line.starts_with("info depth")).collect();
+        let mut last = [0; 4];
+        for line in &lines {
+            let [depth, nodes, time, hashfull] = ["depth", "nodes", "time", "hashfull"].map(|name| {
+                field(line, name).unwrap_or_else(|| panic!("no {name} in {line}"))
+            });
+            assert!(field(line, "nps").is_some(), "no nps in {line}");
+            assert!(depth >= last[0] && nodes > last[1] && time >= last[2] && hashfull >= last[3], "{output}");
+            last = [depth, nodes, time, hashfull];
+        }
+        for depth in 1..=7 {
+            assert!(lines.iter().any(|line| field(line, "depth") == Some(depth)), "{output}");
+        }
+    }
+
+    // An infinite search only sends its best move after stop, even when there is nothing left to search
+    #[test]
+    fn no_best_move_before_stop() {
+        for fen in ["rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", "7k/8/6K1/8/8/8/8/R7 w - - 0 1"] {
+            let mut session = Session::new();
+            session.send(&format!("position fen {fen}"));
+            session.send("go infinite");
+            session.wait_for(|line| field(line, "depth").is_some_and(|depth| depth >= 5));
+            std::thread::sleep(Duration::from_millis(200));
+            assert_eq!(session.best_moves(), 0, "{}", session.output());
+            session.send("stop");
+            session.wait_for_best_moves(1);
+            assert_eq!(session.quit().lines().filter(|line| line.starts_with("bestmove")).count(), 1);
+        }
+    }
+
+    #[test]
+    fn stop_infinite_search() {
+        let mut session = Session::new();
+        session.send("position startpos");
+        session.send("go infinite");
+        session.wait_for(|line| line.starts_with("info depth"));
+        session.send("stop");
+        let output = session.wait_for_best_moves(1);
+        assert!(!output.contains("bestmove 0000"), "{output}");
+        session.send("isready");
+        session.wait_for(|line| line == "readyok");
+        assert_eq!(session.quit().lines().filter(|line| line.starts_with("bestmove")).count(), 1);
+    }
+
+    #[test]
+    fn quit_during_infinite_search() {
+        let mut session = Session::new();
+        session.send("go infinite");
+        session.wait_for(|line| line.starts_with("info depth"));
+        session.quit();
+    }
+
+    // Whether the move of the last bestmove line is legal in the given position
+    fn best_move_is_legal(output: &str, fen: &str) -> bool {
+        let position: shakmaty::Chess = fen.parse::<Fen>().unwrap().into_position(CastlingMode::Standard).unwrap();
+        let line = output.lines().rev().find_map(|line| line.strip_prefix("bestmove ")).unwrap();
+        line.split_whitespace().next().unwrap().parse::<UciMove>().unwrap().to_move(&position).is_ok()
+    }
+
+    // Starts pondering on the expected reply e7e5, sending no best move while pondering
+    fn ponder_on_e5() -> Session {
+        let mut session = Session::new();
+        session.send("position startpos moves e2e4 e7e5");
+        session.send("go ponder wtime 2000 btime 2000");
+        session.wait_for(|line| line.starts_with("info depth"));
+        // Longer than the time the clock allows, which does not count while pondering
+        std::thread::sleep(Duration::from_millis(300));
+        assert_eq!(session.best_moves(), 0, "{}", session.output());
+        session
+    }
+
+    #[test]
+    fn ponder_hit() {
+        let mut session = ponder_on_e5();
+        let start = Instant::now();
+        session.send("ponderhit");
+        let output = session.wait_for_best_moves(1);
+        // From now on the search runs on our clock
+        assert!(start.elapsed() < Duration::from_secs(2), "{output}");
+        assert!(best_move_is_legal(&output, "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2"), "{output}");
+        session.quit();
+    }
+
+    #[test]
+    fn ponder_miss() {
+        // The GUI stops the ponder search, or directly sends the position the opponent did play
+        for stop in [true, false] {
+            let mut session = ponder_on_e5();
+            if stop {
+                session.send("stop");
+                session.wait_for_best_moves(1);
+            }
+            session.send("position startpos moves e2e4 c7c5");
+            session.send("go wtime 2000 btime 2000");
+            let output = session.wait_for_best_moves(2);
+            assert!(best_move_is_legal(&output, "rnbqkbnr/pp1ppppp/8/2p5/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2"), "{output}");
+            session.quit();
+        }
+    }
+
+    #[test]
+    fn stop_while_pondering() {
+        let mut session = ponder_on_e5();
+        session.send("stop");
+        session.wait_for_best_moves(1);
+        session.send("isready");
+        session.wait_for(|line| line == "readyok");
+        assert_eq!(session.quit().lines().filter(|line| line.starts_with("bestmove")).count(), 1);
+    }
+
+    // The best move comes from the search moves, even when another move wins a queen
+    #[test]
+    fn search_moves() {
+        let fen = "4k3/8/8/3q4/8/8/3R4/4K3 w - - 0 1";
+        let mut session = Session::new();
+        session.send(&format!("position fen {fen}"));
+        session.send("go depth 5 searchmoves e1e2 e1f1 e2e4");
+        let output = session.wait_for_best_moves(1);
+        session.quit();
+        assert!(output.lines().any(|line| line.contains("ignoring searchmove e2e4")), "{output}");
+        for line in output.lines().filter(|line| line.starts_with("info depth")) {
+            let pv = line.split(" pv ").nth(1).unwrap_or_else(|| panic!("no pv in {line}"));
+            assert!(pv.starts_with("e1e2") || pv.starts_with("e1f1"), "{line}");
+        }
+        assert!(output.contains("bestmove e1e2") || output.contains("bestmove e1f1"), "{output}");
+        assert!(best_move_is_legal(&output, fen));
+    }
+
+    // With one winning move, the other lines are other moves with lower scores
+    #[test]
+    fn multipv() {
+        let mut session = Session::new();
+        session.send("setoption name MultiPV value 3");
+        session.send("position fen 4k3/8/8/3q4/8/8/3R4/4K3 w - - 0 1");
+        session.send("go depth 5");
+        let output = session.wait_for_best_moves(1);
+        session.quit();
+        let lines: Vec<(u64, i64, &str)> = output
+            .lines()
+            .filter(|line| field(line, "depth") == Some(5))
+            .map(|line| {
+                let score = line.split(" score cp ").nth(1).and_then(|rest| rest.split_whitespace().next()).unwrap();
+                (field(line, "multipv").unwrap(), score.parse().unwrap(), line.split(" pv ").nth(1).unwrap())
+            })
+            .collect();
+        let [(1, first, pv1), (2, second, pv2), (3, third, pv3)] = lines[lines.len() - 3..] else { panic!("{output}") };
+        assert!(pv1.starts_with("d2d5") && output.contains("bestmove d2d5"), "{output}");
+        assert!(first > second && second > third, "{output}");
+        let moves = [pv1, pv2, pv3].map(|pv| pv.split_whitespace().next().unwrap());
+        assert!(moves[0] != moves[1] && moves[1] != moves[2] && moves[0] != moves[2], "{output}");
+    }
+
+    // Excluding the move that wins the queen gives another legal best move, in every MultiPV line
+    #[test]
+    fn exclude_moves() {
+        let fen = "4k3/8/8/3q4/8/8/3R4/4K3 w - - 0 1";
+        for multipv in [1, 3] {
+            let mut session = Session::new();
+            session.send(&format!("setoption name MultiPV value {multipv}"));
+            session.send("setoption name ExcludeMoves value d2d5");
+            session.send(&format!("position fen {fen}"));
+            session.send("go depth 5");
+            let output = session.wait_for_best_moves(1);
+            session.quit();
+            assert!(output.lines().any(|line| line.contains("excluding moves d2d5")), "{output}");
+            for line in output.lines().filter(|line| line.starts_with("info depth")) {
+                let pv = line.split(" pv ").nth(1).unwrap_or_else(|| panic!("no pv in {line}"));
+                assert!(!pv.starts_with("d2d5"), "{line}");
+            }
+            assert!(!output.contains("bestmove d2d5") && best_move_is_legal(&output, fen), "{output}");
+        }
+    }
+
+    // Helper threads share the TT with the main thread, which must still send a legal best move,
+    // whether the search ends by itself or is stopped
+    #[test]
+    fn threads_on_bench_positions() {
+        let mut session = Session::new();
+        session.send("setoption name Threads value 8");
+        for (i, (fen, _)) in bench::POSITIONS.iter().enumerate() {
+            session.send(&format!("position fen {fen}"));
+            session.send("go depth 3");
+            let output = session.wait_for_best_moves(2 * i + 1);
+            assert!(best_move_is_legal(&output, fen), "{fen}: {output}");
+            session.send("go infinite");
+            std::thread::sleep(Duration::from_millis(50));
+            session.send("stop");
+            let output = session.wait_for_best_moves(2 * i + 2);
+            assert!(best_move_is_legal(&output, fen), "{fen}: {output}");
+        }
+        session.quit();
+    }
+
+    // Runs a search in which a panic is injected after the given number of nodes
+    fn search_with_panic(panic_at_node: u64) -> String {
+        let position = position::Position::new(shakmaty::Chess::default());
+        let tt = RwLock::new(Some(search::tt::TT::with_megabytes(1)));
+        let tables = Mutex::new(search::SearchTables::default());
+        let config = Configuration { panic_at_node, ..DEFAULT_CONFIG };
+        let control = SearchControl::default();
+        let output = Mutex::new(Vec::new());
+        std::thread::scope(|scope| {
+            std::thread::Builder::new()
+                .stack_size(search::STACK_SIZE)
+                .spawn_scoped(scope, || {
+                    run_search(position, Vec::new(), time::Deadline::Depth(8), None, &tt, &tables, &config, &control, &output)
+                })
+                .unwrap()
+                .join()
+                .unwrap();
+        });
+        String::from_utf8(output.into_inner().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn panic_in_search() {
+        let start = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+        // Before any iteration is done, any legal move is played
+        let output = search_with_panic(1);
+        assert!(output.contains(&format!("search panicked: injected panic (position {start})")), "{output}");
+        assert!(best_move_is_legal(&output, start), "{output}");
+        // Later, the best move of the last completed iteration
+        let output = search_with_panic(5000);
+        assert!(output.contains("search panicked"), "{output}");
+        let last_pv = output.lines().rev().find_map(|line| line.split(" pv ").nth(1)).unwrap();
+        let best = output.lines().find_map(|line| line.strip_prefix("bestmove ")).unwrap();
+        assert_eq!(best.split_whitespace().next(), last_pv.split_whitespace().next(), "{output}");
+    }
+
+    // Resizing the table replaces it by an empty one, which the next search uses
+    #[test]
+    fn resize_hash() {
+        let mut session = Session::new();
+        session.send("position startpos");
+        for (count, megabytes) in [1, 256, 1].into_iter().enumerate() {
+            session.send(&format!("setoption name Hash value {megabytes}"));
+            session.send("go depth 6");
+            let output = session.wait_for_best_moves(count + 1);
+            let first = output.lines().filter(|line| field(line, "depth") == Some(1)).nth(count).unwrap();
+            assert_eq!(field(first, "hashfull"), Some(0), "{output}");
+            assert!(best_move_is_legal(&output, "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1"), "{output}");
+        }
+        session.quit();
+    }
+
+    // The info lines of a search to depth 8 after the given commands, without the timing fields
+    fn search_after(commands: &[&str]) -> Vec<String> {
+        let mut session = Session::new();
+        for command in commands {
+            session.send(command);
+        }
+        let searches = commands.iter().filter(|command| command.starts_with("go")).count();
+        let before = session.wait_for_best_moves(searches).len();
+        session.send("position startpos moves e2e4");
+        session.send("go depth 8");
+        let output = session.wait_for_best_moves(searches + 1);
+        session.quit();
+        output[before..]
+            .lines()
+            .filter(|line| line.starts_with("info depth"))
+            .map(|line| {
+                let mut words = line.split_whitespace();
+                let mut kept = Vec::new();
+                while let Some(word) = words.next() {
+                    if word == "time" || word == "nps" {
+                        words.next();
+                    } else {
+                        kept.push(word);
+                    }
+                }
+                kept.join(" ")
+            })
+            .collect()
+    }
+
+    // Tables and TT are kept between the searches of a game, and ucinewgame forgets all of them: the
+    // search is then the same as in a new engine
+    #[test]
+    fn new_game_clears_everything() {
+        let cold = search_after(&[]);
+        let earlier = ["position startpos moves d2d4", "go depth 8", "position startpos moves e2e4 e7e5", "go depth 8"];
+        assert_ne!(search_after(&earlier), cold);
+        assert_eq!(search_after(&[&earlier[..], &["ucinewgame"]].concat()), cold);
+    }
+
+    // The Clear Hash button also forgets everything, without starting a new game
+    #[test]
+    fn clear_hash() {
+        let earlier = ["position startpos moves e2e4 e7e5", "go depth 8", "setoption name Clear Hash"];
+        assert_eq!(search_after(&earlier), search_after(&[]));
+    }
+
+    // The TT is kept between the moves of a game: after the expected reply to the best move, the
+    // search takes far fewer nodes than in a new engine
+    #[test]
+    fn tt_kept_between_moves() {
+        let mut session = Session::new();
+        session.send("position startpos moves e2e4");
+        session.send("go depth 10");
+        let output = session.wait_for_best_moves(1);
+        let best = output.lines().find_map(|line| line.strip_prefix("bestmove ")).unwrap();
+        let [mv, "ponder", reply] = best.split_whitespace().collect::<Vec<_>>()[..] else { panic!("{output}") };
+        let continuation = format!("position startpos moves e2e4 {mv} {reply}");
+        let nodes = |mut session: Session, count| {
+            session.send(&continuation);
+            session.send("go depth 8");
+            let output = session.wait_for_best_moves(count);
+            session.quit();
+            let last = output.lines().rev().find(|line| line.starts_with("info depth")).unwrap();
+            field(last, "nodes").unwrap()
+        };
+        let (warm, cold) = (nodes(session, 2), nodes(Session::new(), 1));
+        assert!(warm < cold / 2, "{warm} nodes after an earlier search, {cold} in a new engine");
+    }
+
+    // Commands that wait for the search to end must stop it, or an infinite search never ends
+    #[test]
+    fn commands_during_infinite_search() {
+        let mut session = Session::new();
+        for (count, command) in ["setoption name MultiPV value 1", "go depth 1", "ucinewgame", "position startpos"].into_iter().enumerate() {
+            session.send("go infinite");
+            session.wait_for(|line| line.starts_with("info depth"));
+            session.send(command);
+            session.wait_for_best_moves(count + 1);
+        }
+        session.send("stop");
+        session.quit();
+    }
 }
@@ -1,31 +1,206 @@
 use shakmaty::{
-    CastlingSide, Chess, Color, Move, zobrist::{Zobrist64, ZobristHash, ZobristValue}
+    Bitboard, CastlingSide, Chess, Color, Move, Square,
+    variant::{Antichess, Atomic, Crazyhouse, ThreeCheck, VariantPosition},
+    zobrist::{Zobrist64, ZobristHash, ZobristValue},
 };
 
+use shakmaty::Position as _;
+
+// What the wrapper and the search need to know about a position type besides shakmaty::Position
+pub trait Rules: shakmaty::Position + Clone {
+    // Passes the turn, for null moves; None when not possible (in check). shakmaty only does this
+    // through FromSetup, which VariantPosition, the position type of all variants, doesn't implement.
+    fn pass_turn(self) -> Option<Self>;
+
+    // Whether captures explode, taking the capturing piece and the pieces around the target with them
+    fn explodes(&self) -> bool {
+        false
+    }
+}
+
+impl Rules for Chess {
+    fn pass_turn(self) -> Option<Self> {
+        self.swap_turn().ok()
+    }
+}
+
+impl Rules for Atomic {
+    fn pass_turn(self) -> Option<Self> {
+        self.swap_turn().ok()
+    }
+
+    fn explodes(&self) -> bool {
+        true
+    }
+}
+
+impl Rules for Antichess {
+    fn pass_turn(self) -> Option<Self> {
+        self.swap_turn().ok()
+    }
+}
+
+impl Rules for ThreeCheck {
+    fn pass_turn(self) -> Option<Self> {
+        self.swap_turn().ok()
+    }
+}
+
+impl Rules for Crazyhouse {
+    fn pass_turn(self) -> Option<Self> {
+        self.swap_turn().ok()
+    }
+}
+
+impl Rules for VariantPosition {
+    fn pass_turn(self) -> Option<Self> {
+        self.swap_turn().ok()
+    }
+
+    fn explodes(&self) -> bool {
+        matches!(self, VariantPosition::Atomic(_))
+    }
+}
+
 type Zob = Zobrist64;
-pub struct Position {
-    pos: Chess,
+// A position with the state the search keeps up to date in make/unmake: the zobrist key, updated
+// incrementally, and the key history for repetitions. The evaluation is computed from the board
+// alone, so there are no NNUE accumulators here (see eval::nnue).
+// Generic over the position type, so variants (see Rules) can use it as well
+#[derive(Clone)]
+pub struct Position<P = Chess> {
+    pos: P,
+    zobrist: Zobrist64,
+    // Keys of the positions of the line, the current one last. Only the ones from start on, since
+    // the last irreversible move, can repeat; make() leaves the earlier ones for unmake().
+    keys: Vec<u64>,
+    start: usize,
+}
+
+// What make() changed, for unmake() to put back: the key, the start of the repetition window, and
+// the shakmaty position before the move. shakmaty only plays moves forward and has no way to change
+// the board of a position in place, so that is still a copy (of fixed size, without allocations).
+pub struct Undo<P> {
+    pos: P,
     zobrist: Zobrist64,
-    // TODO: add more fields as necessary, e.g. NNUE accumulators
+    start: usize,
 }
 
-impl Position {
-    pub fn new(pos: Chess) -> Self {
+impl<P: Rules> Position<P> {
+    pub fn new(pos: P) -> Self {
+        Self::with_history(pos, &[])
+    }
+
+    // A position reached in a game, with the keys of the earlier positions of the game (as given by
+    // the position command), oldest first. Only the ones since the last irreversible move are kept.
+    pub fn with_history(pos: P, game_keys: &[u64]) -> Self {
         // let zobrist = shakmaty::zobrist::hash(&pos);
-        let zobrist = pos.zobrist_hash(shakmaty::EnPassantMode::Legal);
-        Position { pos, zobrist }
+        let zobrist: Zob = pos.zobrist_hash(shakmaty::EnPassantMode::Legal);
+        let start = game_keys.len().saturating_sub(pos.halfmoves() as usize);
+        let mut keys = game_keys[start..].to_vec();
+        keys.push(zobrist.0);
+        Position { pos, zobrist, keys, start: 0 }
     }
 
-    pub fn pos(&self) -> &Chess {
+    pub fn pos(&self) -> &P {
         &self.pos
     }
 
     pub fn zobrist(&self) -> u64 {
-        self.zobrist()
+        self.zobrist.0
+    }
+
+    // The same position as another type, e.g. a standard chess position out of a VariantPosition
+    pub fn map<Q>(self, f: impl FnOnce(P) -> Q) -> Position<Q> {
+        Position { pos: f(self.pos), zobrist: self.zobrist, keys: self.keys, start: self.start }
+    }
+
+    // Plays a move, returning what unmake() needs to take it back
+    pub fn make(&mut self, m: &Move) -> Undo<P> {
+        let undo = Undo { pos: self.pos.clone(), zobrist: self.zobrist, start: self.start };
+        shakmaty::Position::play_unchecked(self, m);
+        undo
+    }
+
+    // Passes the turn, for null move pruning; not possible when in check. Positions from before
+    // the null move don't count as repetitions after it.
+    pub fn make_null(&mut self) -> Option<Undo<P>> {
+        let null = self.pos.clone().pass_turn()?;
+        let (zobrist, start, state_keys) = (self.zobrist, self.start, self.state_keys());
+        let pos = std::mem::replace(&mut self.pos, null);
+        self.zobrist ^= Zob::zobrist_for_white_turn() ^ state_keys ^ self.state_keys();
+        debug_assert_eq!(self.zobrist, self.pos.zobrist_hash(shakmaty::EnPassantMode::Legal));
+        self.start = self.keys.len();
+        self.keys.push(self.zobrist.0);
+        Some(Undo { pos, zobrist, start })
+    }
+
+    // Takes back the last move (or null move) of make() or make_null()
+    pub fn unmake(&mut self, undo: Undo<P>) {
+        self.pos = undo.pos;
+        self.zobrist = undo.zobrist;
+        self.start = undo.start;
+        self.keys.pop();
+    }
+
+    // Whether the position is a draw by repetition for the search: it occurred before within the
+    // last plies_from_root plies (twofold, as the side to move could repeat it again), or twice
+    // before in total, counting the game history (threefold).
+    pub fn is_repetition(&self, plies_from_root: usize) -> bool {
+        let mut count = 0;
+        // A position can only repeat with the same side to move, after at least four plies
+        for (distance, key) in self.keys[self.start..].iter().rev().enumerate().skip(4).step_by(2) {
+            if *key == self.zobrist.0 {
+                count += 1;
+                if distance <= plies_from_root || count == 2 {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    // Keys of everything besides the pieces on the board and the side to move. A move can change
+    // these in ways that are easier to recompute than to follow: castling rights, en passant, and in
+    // variants the pockets and promoted pieces of Crazyhouse and the remaining checks of three-check.
+    fn state_keys(&self) -> Zob {
+        let mut zobrist = Zob::default();
+
+        // Like the full hash, only hash the en passant square if en passant is actually possible
+        if let Some(sq) = self.pos.ep_square(shakmaty::EnPassantMode::Legal) {
+            zobrist ^= Zob::zobrist_for_en_passant_file(sq.file());
+        }
+
+        let castles = self.pos.castles();
+        for color in Color::ALL {
+            for side in CastlingSide::ALL {
+                if castles.has(color, side) {
+                    zobrist ^= Zob::zobrist_for_castling_right(color, side);
+                }
+            }
+        }
+
+        for sq in self.pos.promoted() {
+            zobrist ^= Zob::zobrist_for_promoted(sq);
+        }
+        if let Some(pockets) = self.pos.pockets() {
+            for (color, pocket) in pockets.zip_color() {
+                for (role, pieces) in pocket.zip_role() {
+                    zobrist ^= Zob::zobrist_for_pocket(color, role, pieces);
+                }
+            }
+        }
+        if let Some(remaining_checks) = self.pos.remaining_checks() {
+            for (color, remaining) in remaining_checks.zip_color() {
+                zobrist ^= Zob::zobrist_for_remaining_checks(color, remaining);
+            }
+        }
+
+        zobrist
     }
 }
 
-impl shakmaty::Position for Position {
+impl<P: Rules> shakmaty::Position for Position<P> {
     fn board(&self) -> &shakmaty::Board {
         self.pos.board()
     }
@@ -83,28 +258,18 @@ impl shakmaty::Position for Position {
     }
 
     fn play_unchecked(&mut self, m: &Move) {
-        // Update zobrist hash
-        self.zobrist ^= Zob::zobrist_for_white_turn();
-
-        // Clear epsquare zobrist
-        if let Some(sq) = self.pos.ep_square(shakmaty::EnPassantMode::Always) {
-            self.zobrist ^= Zob::zobrist_for_en_passant_file(sq.file());
-        }
-
-        // Clear castling zobrist
-        let castles = self.pos.castles();
-        for color in Color::ALL {
-            for side in CastlingSide::ALL {
-                if castles.has(color, side) {
-                    self.zobrist ^= Zob::zobrist_for_castling_right(color, side);
-                }
-            }
+        // Like the halfmove clock, the history starts over at pawn moves and captures: in standard
+        // chess, the positions before them can't occur again
+        if m.is_zeroing() {
+            self.start = self.keys.len();
         }
 
-        // NOTE: crazyhouse and three-check require more zobrist updates
+        // The white turn key is in the hash when White is to move, so it toggles with every move
+        self.zobrist ^= Zob::zobrist_for_white_turn();
+        self.zobrist ^= self.state_keys();
 
         match m {
-            Move::Normal { role, from, capture, to, promotion } => {
+            Move::Normal { from, capture, to, promotion, .. } => {
                 // Remove piece from 'from' square
                 let piece = self.pos.board().piece_at(*from).unwrap();
                 self.zobrist ^= Zob::zobrist_for_piece(*from, piece);
@@ -124,27 +289,224 @@ impl shakmaty::Position for Position {
                 };
                 self.zobrist ^= Zob::zobrist_for_piece(*to, moved_piece);
             },
-            Move::EnPassant { from, to } => todo!(),
-            Move::Castle { king, rook } => todo!(),
-            Move::Put { role, to } => todo!(),
+            Move::EnPassant { from, to } => {
+                // The captured pawn is next to the capturing one, not on the target square
+                let pawn = shakmaty::Piece { role: shakmaty::Role::Pawn, color: self.pos.turn() };
+                let captured = shakmaty::Piece { role: shakmaty::Role::Pawn, color: self.pos.turn().other() };
+                self.zobrist ^= Zob::zobrist_for_piece(*from, pawn);
+                self.zobrist ^= Zob::zobrist_for_piece(Square::from_coords(to.file(), from.rank()), captured);
+                self.zobrist ^= Zob::zobrist_for_piece(*to, pawn);
+            },
+            Move::Castle { king, rook } => {
+                // shakmaty encodes castling as the king taking its own rook; both end up on the
+                // squares of the castling side instead
+                let color = self.pos.turn();
+                let side = if king < rook { CastlingSide::KingSide } else { CastlingSide::QueenSide };
+                let king_piece = shakmaty::Piece { role: shakmaty::Role::King, color };
+                let rook_piece = shakmaty::Piece { role: shakmaty::Role::Rook, color };
+                self.zobrist ^= Zob::zobrist_for_piece(*king, king_piece);
+                self.zobrist ^= Zob::zobrist_for_piece(*rook, rook_piece);
+                self.zobrist ^= Zob::zobrist_for_piece(side.king_to(color), king_piece);
+                self.zobrist ^= Zob::zobrist_for_piece(side.rook_to(color), rook_piece);
+            },
+            Move::Put { role, to } => {
+                // Crazyhouse drop; the piece leaving the pocket is in the state keys
+                let piece = shakmaty::Piece { role: *role, color: self.pos.turn() };
+                self.zobrist ^= Zob::zobrist_for_piece(*to, piece);
+            },
+        }
+
+        // Atomic: the capturing piece explodes instead of landing, and so do the pieces around the
+        // target square, except pawns (the mover has already left its square)
+        if m.is_capture() && self.pos.explodes() {
+            let (board, to) = (self.pos.board(), m.to());
+            let mover = shakmaty::Piece { role: m.promotion().unwrap_or(m.role()), color: self.pos.turn() };
+            self.zobrist ^= Zob::zobrist_for_piece(to, mover);
+            let around = shakmaty::attacks::king_attacks(to) & board.occupied() & !board.pawns();
+            for sq in around & !Bitboard::from(m.from().unwrap()) {
+                self.zobrist ^= Zob::zobrist_for_piece(sq, board.piece_at(sq).unwrap());
+            }
         }
 
         self.pos.play_unchecked(m);
+        self.zobrist ^= self.state_keys();
 
-        // Set epsquare zobrist
-        if let Some(sq) = self.pos.ep_square(shakmaty::EnPassantMode::Always) {
-            self.zobrist ^= Zob::zobrist_for_en_passant_file(sq.file());
+        // Debug builds check every update against the full hash
+        if cfg!(debug_assertions) {
+            let expected: Zob = self.pos.zobrist_hash(shakmaty::EnPassantMode::Legal);
+            if self.zobrist != expected {
+                let fen = shakmaty::fen::Fen::from_position(self.pos.clone(), shakmaty::EnPassantMode::Legal);
+                panic!("zobrist key {:016x} after {} is wrong, {} has {:016x}", self.zobrist.0, m, fen, expected.0);
+            }
         }
+        self.keys.push(self.zobrist.0);
+    }
+}
 
-        // Set castling zobrist
-        let castles = self.pos.castles();
-        for color in Color::ALL {
-            for side in CastlingSide::ALL {
-                if castles.has(color, side) {
-                    self.zobrist ^= Zob::zobrist_for_castling_right(color, side);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shakmaty::{EnPassantMode, fen::Fen};
+
+    // Positions from which random games soon castle or capture en passant, which random games from the
+    // bench positions rarely do
+    const ZOBRIST_POSITIONS: [&str; 7] = [
+        "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+        "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+        "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+        "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8",
+        "r3k2r/5ppp/8/2pPp3/1pP1Pp2/8/PP3PPP/R3K2R w KQkq c6 0 1",
+        "rnbqkbnr/ppp1p1pp/8/3pPp2/8/8/PPPP1PPP/RNBQKBNR w KQkq f6 0 3",
+        "rnbqkbnr/pppp1ppp/8/8/3PpP2/8/PPP1P1PP/RNBQKBNR b KQkq f3 0 3",
+    ];
+
+    // Knight moves back and forth from the start position, which repeat it every four plies
+    #[test]
+    fn repetitions() {
+        let shuffle = ["g1f3", "g8f6", "f3g1", "f6g8"];
+        // Plays the moves, returning the position and the keys of the positions before it
+        let play = |moves: &[&str]| {
+            let mut position = Position::new(Chess::new());
+            let mut keys = Vec::new();
+            for uci in moves {
+                let m = uci.parse::<shakmaty::uci::UciMove>().unwrap().to_move(&position).unwrap();
+                keys.push(position.zobrist());
+                position.play_unchecked(&m);
+            }
+            (position, keys)
+        };
+        // Twofold within the search: the root repeats four plies in, but not if the root is later
+        let (searched, _) = play(&shuffle);
+        assert!(searched.is_repetition(4));
+        assert!(!searched.is_repetition(3));
+
+        // Threefold counting the game history: the root occurred once before it, and again four plies in
+        let (game, game_keys) = play(&shuffle);
+        let mut position = Position::with_history(game.pos().clone(), &game_keys);
+        assert!(!position.is_repetition(0));
+        for uci in shuffle {
+            position.play_unchecked(&uci.parse::<shakmaty::uci::UciMove>().unwrap().to_move(&position).unwrap());
+        }
+        assert!(position.is_repetition(0));
+
+        // A pawn move in between: the earlier positions are gone
+        let (pawn, _) = play(&["g1f3", "g8f6", "f3g1", "f6g8", "e2e4", "e7e5", "g1f3", "g8f6", "f3g1", "f6g8"]);
+        assert!(!pawn.is_repetition(0));
+        assert!(pawn.is_repetition(4));
+    }
+
+    // Index of the kind of move in the counts of check_games()
+    fn move_kind(m: &Move) -> usize {
+        match m {
+            Move::Normal { promotion: Some(_), .. } => 2,
+            Move::Normal { capture: Some(_), .. } => 1,
+            Move::Normal { .. } => 0,
+            Move::EnPassant { .. } => 3,
+            Move::Castle { .. } => 4,
+            Move::Put { .. } => 5,
+        }
+    }
+
+    const CRAZYHOUSE_POSITIONS: [&str; 2] = [
+        "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR[] w KQkq - 0 1",
+        "r1bqk2r/pppp1ppp/2n5/2b1p3/2B1P3/5N2/PPPP1PPP/R1BQK2R[Nn] w KQkq - 0 5",
+    ];
+
+    // Positions for the other variants, with castling still possible after the captures start
+    const VARIANT_POSITIONS: [&str; 2] = [
+        "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+        "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+    ];
+
+    // Antichess has no castling
+    const ANTICHESS_POSITIONS: [&str; 2] = [
+        "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w - - 0 1",
+        "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w - - 0 1",
+    ];
+
+    // Plays random games from the given positions, checking the incremental key against the full hash
+    // after every move, that unmaking each move (and a null move) restores the position, and repetitions
+    // against a scan of the keys of the game. Returns the number of moves checked by kind.
+    fn check_games<P>(fens: impl IntoIterator<Item = &'static str>) -> [u64; 6]
+    where
+        P: Rules + shakmaty::FromSetup,
+    {
+        let mut kinds = [0; 6];
+        let mut seed = 0;
+        for fen in fens {
+            let start: P = fen.parse::<Fen>().unwrap().into_position(shakmaty::CastlingMode::Standard).unwrap();
+            for _ in 0..GAMES_PER_POSITION {
+                let mut position = Position::new(start.clone());
+                let mut keys = vec![position.zobrist()];
+                for _ in 0..MAX_GAME_PLIES {
+                    let legal = position.legal_moves();
+                    if legal.is_empty() {
+                        break;
+                    }
+                    seed = crate::util::random::splitmix64(seed);
+                    let m = &legal[(seed % legal.len() as u64) as usize];
+                    let fen = Fen::from_position(position.pos().clone(), EnPassantMode::Legal);
+
+                    // Making and unmaking the move, or a null move, must give back the same position
+                    let state = |position: &Position<P>| {
+                        (position.pos().clone().into_setup(EnPassantMode::Always), position.zobrist, position.keys.clone(), position.start)
+                    };
+                    let before = state(&position);
+                    let undo = position.make(m);
+                    position.unmake(undo);
+                    if let Some(undo) = position.make_null() {
+                        position.unmake(undo);
+                    }
+                    assert!(state(&position) == before, "{fen}: not the same position after unmaking {m}");
+
+                    position.play_unchecked(m);
+                    kinds[move_kind(m)] += 1;
+                    let expected: Zobrist64 = position.pos().zobrist_hash(EnPassantMode::Legal);
+                    assert_eq!(position.zobrist(), expected.0, "{fen}: wrong key after {m}");
+
+                    // Repetitions since the last zeroing move
+                    if m.is_zeroing() {
+                        keys.clear();
+                    }
+                    let earlier = keys.iter().filter(|k| **k == expected.0).count();
+                    keys.push(expected.0);
+                    assert_eq!(position.is_repetition(usize::MAX), earlier >= 1, "{fen}: twofold after {m}");
+                    assert_eq!(position.is_repetition(0), earlier >= 2, "{fen}: threefold after {m}");
                 }
             }
         }
+        kinds
+    }
+
+    const GAMES_PER_POSITION: u64 = 100;
+    const MAX_GAME_PLIES: usize = 200;
+
+    #[test]
+    fn standard_games() {
+        let bench = crate::bench::POSITIONS.iter().map(|(fen, _)| *fen);
+        let [quiet, captures, promotions, en_passant, castling, _] = check_games::<Chess>(bench.chain(ZOBRIST_POSITIONS));
+        for count in [quiet, captures, promotions, en_passant, castling] {
+            assert!(count > 0, "{quiet} quiet, {captures} captures, {promotions} promotions, {en_passant} en passant, {castling} castling");
+        }
+    }
+
+    #[test]
+    fn crazyhouse_games() {
+        assert!(check_games::<Crazyhouse>(CRAZYHOUSE_POSITIONS)[5] > 0);
+    }
+
+    #[test]
+    fn atomic_games() {
+        assert!(check_games::<Atomic>(VARIANT_POSITIONS)[1] > 0);
+    }
+
+    #[test]
+    fn three_check_games() {
+        check_games::<ThreeCheck>(VARIANT_POSITIONS);
+    }
 
+    #[test]
+    fn antichess_games() {
+        check_games::<Antichess>(ANTICHESS_POSITIONS);
     }
 }
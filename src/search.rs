@@ -1,20 +1,31 @@
+use arrayvec::ArrayVec;
 use std::{
-    cmp::min,
     sync::atomic::{AtomicBool, AtomicIsize, AtomicU64, Ordering::Relaxed},
     time::Instant,
 };
 
 use crate::{
-    eval::{eval, eval_piece},
+    eval::{Evaluate, eval_cached, nnue, pawn_key, probe_eval_cache},
+    position::{Position as SearchPosition, Rules},
     time,
-    util::sort::LazySort,
+    util::random::splitmix64,
 };
 use shakmaty::{
-    Chess, Move, Position, Square, zobrist::{Zobrist64, ZobristHash}
+    Move, Outcome, Position, Rank, Role, Square, zobrist::{Zobrist64, ZobristHash}
 };
 
+mod movepick;
+mod depth;
+pub mod see;
+pub mod stats;
 pub mod tt;
+#[cfg(test)]
+mod tests;
 
+use depth::Depth;
+use movepick::MovePicker;
+use see::see;
+use stats::SearchStats;
 use tt::*;
 
 pub struct NodeCount {
@@ -33,44 +44,177 @@ impl NodeCount {
     }
 }
 
-// Move ordering
-// note: somewhat confusing, but for the inner values, lower is better
-// this is related to how sorting works (lower values earlier)
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
-enum MoveOrderKey {
-    TTMove(i16),
-    Capture(i16, i16), // victim value, aggressor value
-    Quiet(i32),        // development value
+// Mate scores
+// A side that is checkmated at `ply` scores -MATE + ply, so shorter mates are preferred. Any score
+// beyond MATE_BOUND is a mate score. In the TT, mate scores are stored relative to the node instead
+// of the root, as the same position can be reached at different plies.
+const MATE: i16 = 32700;
+const MATE_BOUND: i16 = 32000;
+
+fn is_mate_score(score: i16) -> bool {
+    score.abs() > MATE_BOUND
 }
 
-fn move_key(pos: &Chess, tte: Option<TTEntry>, m: &Move, _g: &SearchState, t: &ThreadState) -> MoveOrderKey {
-    // TT-move first
-    if let Some(tte) = tte
-        && move_match_tt(m, &tte)
-    {
-        return MoveOrderKey::TTMove(match m.promotion() {
-            Some(shakmaty::Role::Queen) => -4,
-            Some(shakmaty::Role::Rook) => -3,
-            Some(shakmaty::Role::Bishop) => -2,
-            Some(shakmaty::Role::Knight) => -1,
-            _ => 0,
-        });
+// Lowest score of a mate in at most n moves
+fn mate_within(n: usize) -> i16 {
+    MATE - (2 * n.min(MAX_PLY) as i16 - 1)
+}
+
+// Number of moves until mate; negative if we are getting mated
+fn mate_in(score: i16) -> isize {
+    if score > 0 {
+        (MATE - score + 1) as isize / 2
+    } else {
+        -((MATE + score) as isize) / 2
     }
+}
+
+fn score_to_tt(score: i16, ply: isize) -> i16 {
+    if score > MATE_BOUND {
+        score + ply as i16
+    } else if score < -MATE_BOUND {
+        score - ply as i16
+    } else {
+        score
+    }
+}
 
-    if let Some(captured) = m.capture() {
-        // for captures, order by MVV-LVA
-        let victim_pos = if m.is_en_passant() { Square::from_coords(m.to().file(), m.from().unwrap().rank()) } else { m.to() };
-        let victim_value = eval_piece(victim_pos, pos.turn().other(), captured);
-        let aggressor_value = eval_piece(
-            m.from().unwrap(),
-            pos.turn(),
-            pos.board().role_at(m.from().unwrap()).unwrap(),
-        );
-        MoveOrderKey::Capture(-victim_value, -aggressor_value)
+fn score_from_tt(score: i16, ply: isize) -> i16 {
+    if score > MATE_BOUND {
+        score - ply as i16
+    } else if score < -MATE_BOUND {
+        score + ply as i16
     } else {
-        let hist = t.butterfly[pos.turn() as usize][m.from().unwrap() as usize][m.to() as usize] as i32;
-        MoveOrderKey::Quiet(-hist)
+        score
+    }
+}
+
+// Whether a TT score can be used for a cutoff, depending on if it's compatible with our alpha/beta
+// window. It is compatible if:
+// - it is an exact score (so it is the real score)
+// - it is not an upper bound, and the score >= beta (so the real score also >= beta)
+// - it is not a lower bound, and the score <= alpha (so the real score also <= alpha)
+fn tt_cutoff(tte: &TTEntry, alpha: i16, beta: i16) -> bool {
+    match tte.score_type {
+        ScoreType::Exact => true,
+        ScoreType::LowerBound => tte.value >= beta,
+        ScoreType::UpperBound => tte.value <= alpha,
+    }
+}
+
+// History heuristic
+// Quiet moves causing a beta cutoff get a bonus scaled by depth, quiets searched before it get an
+// equal malus. Updates use the 'gravity' formula: the change shrinks as the value approaches
+// MAX_HISTORY, so values saturate within [-MAX_HISTORY, MAX_HISTORY] instead of overflowing.
+const MAX_HISTORY: i32 = 16384;
+
+fn history_bonus(depth: isize) -> i32 {
+    (300 * depth as i32 - 250).clamp(-MAX_HISTORY, MAX_HISTORY)
+}
+
+fn update_history(entry: &mut i16, bonus: i32) {
+    let bonus = bonus.clamp(-MAX_HISTORY, MAX_HISTORY);
+    // The change alone can be up to 2 * MAX_HISTORY, so it is added in i32; the result is in bounds
+    let old = *entry as i32;
+    *entry = (old + bonus - old * bonus.abs() / MAX_HISTORY) as i16;
+}
+
+// Continuation history is indexed by [previous piece][previous to square][piece][to square],
+// where the previous move is the one played 1 or 2 plies before the current move
+type ContinuationHistory = [[[[i16; 64]; 12]; 64]; 12];
+
+fn new_continuation_history() -> Box<ContinuationHistory> {
+    vec![[[[0; 64]; 12]; 64]; 12].into_boxed_slice().try_into().unwrap()
+}
+
+fn piece_index(color: shakmaty::Color, role: shakmaty::Role) -> usize {
+    let role: usize = role.into();
+    color as usize * 6 + role - 1
+}
+
+// The continuation history entries for a move, for moves 1 and 2 plies ago (if they exist)
+fn continuation_entries(t: &ThreadState, ply: Option<usize>) -> [Option<(usize, Square)>; 2] {
+    let prev = |n: usize| {
+        ply.and_then(|p| p.checked_sub(n)).and_then(|p| t.stack[p].played).map(|m| (m.piece, m.to))
+    };
+    [prev(1), prev(2)]
+}
+
+fn quiet_history<P: Position>(t: &ThreadState, pos: &P, ply: Option<usize>, m: &Move) -> i32 {
+    let piece = piece_index(pos.turn(), m.role());
+    let to = m.to() as usize;
+    let mut hist = t.butterfly[pos.turn() as usize][m.from().unwrap() as usize][to] as i32;
+    for (table, prev) in t.cont_history.iter().zip(continuation_entries(t, ply)) {
+        if let Some((prev_piece, prev_to)) = prev {
+            hist += table[prev_piece][prev_to as usize][piece][to] as i32;
+        }
     }
+    hist
+}
+
+fn update_quiet_history<P: Position>(t: &mut ThreadState, pos: &P, ply: usize, m: &Move, bonus: i32) {
+    let piece = piece_index(pos.turn(), m.role());
+    let to = m.to() as usize;
+    update_history(&mut t.butterfly[pos.turn() as usize][m.from().unwrap() as usize][to], bonus);
+    let prevs = continuation_entries(t, Some(ply));
+    for (table, prev) in t.cont_history.iter_mut().zip(prevs) {
+        if let Some((prev_piece, prev_to)) = prev {
+            update_history(&mut table[prev_piece][prev_to as usize][piece][to], bonus);
+        }
+    }
+}
+
+// Capture history is indexed by [color][moved role][to square][captured role]
+type CaptureHistory = [[[[i16; 6]; 64]; 6]; 2];
+
+// Divisor bringing capture history in the range of piece values for ordering
+const CAPTURE_HISTORY_SCALE: i32 = 64;
+
+fn capture_history<'a, P: Position>(table: &'a CaptureHistory, pos: &P, m: &Move) -> &'a i16 {
+    let moved: usize = m.role().into();
+    let captured: usize = m.capture().unwrap().into();
+    &table[pos.turn() as usize][moved - 1][m.to() as usize][captured - 1]
+}
+
+fn capture_history_mut<'a, P: Position>(table: &'a mut CaptureHistory, pos: &P, m: &Move) -> &'a mut i16 {
+    let moved: usize = m.role().into();
+    let captured: usize = m.capture().unwrap().into();
+    &mut table[pos.turn() as usize][moved - 1][m.to() as usize][captured - 1]
+}
+
+// Correction history
+// Tracks the difference between search score and static eval per pawn structure and side to move,
+// as a running average in fixed point (CORRECTION_GRAIN units per centipawn), and uses it to
+// correct the static eval.
+const CORRECTION_SIZE: usize = 16384;
+const CORRECTION_GRAIN: i32 = 256;
+const MAX_CORRECTION: i32 = 64 * CORRECTION_GRAIN;
+
+fn correction_entry<P: Position>(pos: &P) -> (usize, usize) {
+    (pos.turn() as usize, (pawn_key(pos) % CORRECTION_SIZE as u64) as usize)
+}
+
+fn corrected_eval<P: Searchable>(pos: &P, t: &ThreadState) -> i16 {
+    correct_eval(pos, t, pos.evaluate())
+}
+
+// Applies the correction to a raw static eval (e.g. one stored in the TT), and scales it towards 0 as
+// the 50 move rule approaches: an advantage is worth less with little time left to convert it, and
+// captures and pawn moves (which reset the clock) get the full score. This is done here rather than
+// in eval, as the TT key does not include the halfmove clock.
+fn correct_eval<P: Position>(pos: &P, t: &ThreadState, raw_eval: i16) -> i16 {
+    let (col, idx) = correction_entry(pos);
+    let eval = raw_eval as i32 + t.correction[col][idx] as i32 / CORRECTION_GRAIN;
+    (eval * (200 - pos.halfmoves().min(100) as i32) / 200) as i16
+}
+
+fn update_correction<P: Position>(t: &mut ThreadState, pos: &P, depth: isize, diff: i16) {
+    let (col, idx) = correction_entry(pos);
+    let entry = &mut t.correction[col][idx];
+    let weight = (depth as i32 + 1).min(16);
+    let target = (diff as i32).clamp(-256, 256) * CORRECTION_GRAIN;
+    let new = (*entry as i32 * (256 - weight) + target * weight) / 256;
+    *entry = new.clamp(-MAX_CORRECTION, MAX_CORRECTION) as i16;
 }
 
 // Actual search implementation
@@ -79,50 +223,338 @@ struct SearchState<'a> {
     tt: &'a TT,
     nodes: NodeCount,
     deadline: time::Deadline,
-    stop: AtomicBool,
+    control: &'a SearchControl,
+    lmr: [[Depth; 64]; 64],
+    root_moves: &'a [Move], // if not empty, only these moves are searched at the root
+    contempt: i16,
+    root_noise: Vec<(Move, i16)>, // random bonus per root move, for opening variety
+}
+
+// Late move pruning thresholds, indexed by [improving][depth]: (3 + depth^2) / (2 - improving)
+const LMP_THRESHOLDS: [[usize; 5]; 2] = [[0, 2, 3, 6, 9], [0, 4, 7, 12, 19]];
+
+// Late move reduction table, indexed by [depth][move count], in fractions of a ply
+fn lmr_table(config: &crate::Configuration) -> [[Depth; 64]; 64] {
+    std::array::from_fn(|depth| {
+        std::array::from_fn(|count| {
+            if depth == 0 || count == 0 {
+                Depth::ZERO
+            } else {
+                let r = config.lmr_base + (depth as f64).ln() * (count as f64).ln() / config.lmr_divisor;
+                Depth::fractional_plies(r)
+            }
+        })
+    })
+}
+
+const CLOCK_INTERVAL: u32 = 2048;
+const CLOCK_INTERVAL_LOW_TIME: u32 = 128;
+const LOW_TIME: std::time::Duration = std::time::Duration::from_millis(50);
+
+// Lets another thread stop the search, or end pondering
+#[derive(Default)]
+pub struct SearchControl {
+    pub stop: AtomicBool,       // set to stop the search; also set when the search is done
+    pub pondering: AtomicBool,  // while set, the deadline is ignored
+    pub ponder_time: AtomicU64, // time spent pondering in ms, which does not count against the deadline
+}
+
+impl SearchState<'_> {
+    // Score of a draw for the side to move at `ply`: with contempt, the engine avoids draws, so they
+    // are bad for the side to move at the root, and good for its opponent
+    fn draw_score(&self, ply: isize) -> i16 {
+        if ply % 2 == 0 { -self.contempt } else { self.contempt }
+    }
+
+    fn root_noise(&self, m: &Move) -> i16 {
+        self.root_noise.iter().find(|(rm, _)| rm == m).map_or(0, |(_, noise)| *noise)
+    }
+
+    // The time according to the deadline, which only starts counting when pondering ends
+    fn now(&self) -> Instant {
+        Instant::now() - std::time::Duration::from_millis(self.control.ponder_time.load(Relaxed))
+    }
+
+    // Reading the clock is relatively expensive, so it is only done every so many nodes (and always
+    // at the root), and more often when the hard deadline is close
+    fn check_hard(&self, t: &mut ThreadState, ply: isize) -> bool {
+        if self.control.stop.load(Relaxed) {
+            return true;
+        }
+        if self.control.pondering.load(Relaxed) || !t.abortable {
+            return false;
+        }
+        // Node limits are checked at every node, so that they are exact (and reproducible)
+        let nodes_limited = matches!(self.deadline, time::Deadline::Nodes(_));
+        if ply > 0 && t.clock_countdown > 0 && !nodes_limited {
+            t.clock_countdown -= 1;
+            return false;
+        }
+        let now = self.now();
+        t.clock_countdown = match self.deadline {
+            time::Deadline::Time(_, _, hard) if hard.saturating_duration_since(now) < LOW_TIME => {
+                CLOCK_INTERVAL_LOW_TIME
+            }
+            _ => CLOCK_INTERVAL,
+        };
+        if self.deadline.check_hard(now, self.nodes.count() as usize) {
+            // Also stops the other threads
+            self.control.stop.store(true, Relaxed);
+            return true;
+        }
+        false
+    }
+
+    fn check_soft(&self, depth_searched: usize, scale: f64) -> bool {
+        !self.control.pondering.load(Relaxed)
+            && self.deadline.check_soft(self.now(), self.nodes.count() as usize, depth_searched, scale)
+    }
+
+    // `moves` can be a subset of the legal moves (in qsearch); an entry whose move is not among them
+    // is not used
+    fn tt_get<P: Position>(&self, t: &mut ThreadState, position: &P, moves: &[Move], key: u64) -> Option<TTEntry> {
+        if !self.config.use_tt {
+            return None;
+        }
+        t.stats.tt_probes += 1;
+        let entry = self.tt.probe(key);
+        // In debug mode, count the entries whose move is not legal at all: these are key collisions
+        if self.config.debug
+            && let Some(entry) = entry
+            && !entry.is_legal(moves)
+            && !entry.is_legal(&position.legal_moves())
+        {
+            t.stats.tt_collisions += 1;
+        }
+        let entry = entry.filter(|entry| entry.is_legal(moves));
+        t.stats.tt_hits += entry.is_some() as u64;
+        entry
+    }
+
+    fn tt_write(&self, key: u64, data: TTEntry) {
+        if self.config.use_tt {
+            self.tt.write(key, data);
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct PlayedMove {
+    piece: usize,
+    to: Square,
+    capture: bool,
+}
+
+// Maximum search ply; nodes at this ply are not searched but return their static eval. This bounds
+// the recursion depth, and with it the size of the per-ply tables below.
+const MAX_PLY: usize = 128;
+
+// Search stack: state of the line currently being searched, per ply
+#[derive(Default)]
+struct SearchStackEntry {
+    played: Option<PlayedMove>, // move played at this ply (None for null moves)
+    static_eval: Option<i16>,   // None when in check
+    excluded: Option<Move>,     // move excluded in a singular extension search
+    killers: [Option<Move>; 2],
+    extensions: isize, // extensions used along the line to this ply
+    double_extensions: isize, // double extensions used along the line to this ply
+}
+
+type SearchStack = [SearchStackEntry; MAX_PLY + 1];
+
+// The newest killer goes in the first slot, and the one it replaces moves to the second
+fn store_killer(killers: &mut [Option<Move>; 2], mv: &Move) {
+    if killers[0].as_ref() != Some(mv) {
+        killers[1] = killers[0].take();
+        killers[0] = Some(mv.clone());
+    }
 }
 
 struct ThreadState {
     butterfly: [[[i16; 64]; 64]; 2],
-    pv: [[Option<Move>; 256]; 256],
+    capture_history: CaptureHistory,
+    cont_history: [Box<ContinuationHistory>; 2],
+    stack: SearchStack,
+    nmp_min_ply: isize,                     // null moves are disabled before this ply
+    correction: Box<[[i16; CORRECTION_SIZE]; 2]>,
+    pv: [[Option<Move>; MAX_PLY + 1]; MAX_PLY + 1],
+    root_excluded: Vec<Move>, // root moves already reported in an earlier MultiPV line
+    root_order: Vec<(Move, u64)>, // root moves in search order, with the nodes spent on them
+    clock_countdown: u32,         // nodes until the clock is checked again
+    abortable: bool,              // if not set, only an explicit stop aborts the search
+    root_best: Option<i16>,       // exact score of the root PV so far in this iteration
+    refutations: Vec<(Move, Vec<Move>)>, // root moves that failed low in this iteration, with the refuting line
+    stats: SearchStats,
+}
+
+fn gives_check<P: Position + Clone>(position: &P, m: &Move) -> bool {
+    let mut pos = position.clone();
+    pos.play_unchecked(m);
+    pos.is_check()
+}
+
+// In three-check, a check may win the game whatever material it costs, so SEE must not prune it
+fn check_counts<P: Position + Clone>(position: &P, m: &Move) -> bool {
+    position.remaining_checks().is_some() && gives_check(position, m)
 }
 
-fn qsearch(position: shakmaty::Chess, mut alpha: i16, beta: i16, g: &SearchState, t: &mut ThreadState) -> i16 {
+fn qsearch<P: Searchable>(
+    position: &mut SearchPosition<P>,
+    ply: isize,
+    qply: usize,
+    mut alpha: i16,
+    beta: i16,
+    g: &SearchState,
+    t: &mut ThreadState,
+) -> i16 {
     g.nodes.qnodes.fetch_add(1, Relaxed);
 
-    let (moves, mut best) = if !position.is_check() {
-        let best = eval(&position);
+    if position.is_variant_end() {
+        return variant_end_score(position, ply, g);
+    }
+    if position.is_insufficient_material() {
+        return g.draw_score(ply);
+    }
+    if ply as usize >= MAX_PLY {
+        return corrected_eval(position.pos(), t);
+    }
+
+    // Long capture sequences can take a while as well, so also check the time here
+    if g.check_hard(t, ply) {
+        return -32768;
+    }
+
+    // Start loading the TT entry now, so move generation hides the memory latency
+    let key = position.zobrist();
+    g.tt.prefetch(key);
+
+    let in_check = position.is_check();
+    let moves = if !in_check {
+        let mut moves = position.capture_moves();
+        moves.extend(position.promotion_moves().into_iter().filter(|m| !m.is_capture()));
+        // Close to the horizon, also look at quiet checks, to find e.g. a check that forks or mates
+        if g.config.qsearch_checks && qply < g.config.qsearch_check_plies {
+            let mut checks = position.legal_moves();
+            checks.retain(|m| !m.is_capture() && !m.is_promotion() && gives_check(position.pos(), m));
+            moves.extend(checks);
+        }
+        moves
+    } else {
+        position.legal_moves()
+    };
+
+    // Qsearch results are stored at depth 0, so they can never satisfy a probe from the main search
+    let tt_entry = g.tt_get(t, position.pos(), &moves, key).map(|tte| TTEntry { value: score_from_tt(tte.value, ply), ..tte });
+    if let Some(tte) = tt_entry
+        && tt_cutoff(&tte, alpha, beta)
+    {
+        t.stats.tt_cutoffs += 1;
+        return tte.value;
+    }
+    let tt_move = tt_entry.and_then(|tte| tte.best_move(&moves));
+
+    // The static eval is stored in the TT, so it does not have to be computed again. Otherwise, if
+    // material alone is far outside the window, that is good enough to stand pat on or to search the
+    // captures with; such a lazy eval is not stored, as it is not the real static eval.
+    let mut lazy = false;
+    let raw_eval = if in_check {
+        None
+    } else if let Some(eval) = tt_entry.and_then(|tte| tte.eval).or_else(|| probe_eval_cache(key)) {
+        Some(eval)
+    } else if g.config.lazy_eval
+        && !nnue::is_loaded()
+        && let material = position.pos().lazy_evaluate()
+        && let corrected = correct_eval(position.pos(), t, material)
+        && (corrected.saturating_sub(g.config.lazy_eval_margin) >= beta
+            || corrected.saturating_add(g.config.lazy_eval_margin) <= alpha)
+    {
+        lazy = true;
+        t.stats.lazy_evals += 1;
+        Some(material)
+    } else {
+        Some(eval_cached(position.pos(), key))
+    };
+    let mut best = if let Some(raw_eval) = raw_eval {
+        let best = correct_eval(position.pos(), t, raw_eval);
         if best >= beta {
             return best;
         }
-        if best > alpha {
-            alpha = best;
-        }
-        let mut moves = position.legal_moves();
-        moves.retain(|m| m.is_promotion() || m.is_capture());
-        (moves, best)
+        best
     } else {
         // If checked, search all moves and forbid standing pat
         // Instead, assume checkmate unless a move can let us escape
-        (position.legal_moves(), -32700)
+        -MATE + ply as i16
     };
+    let original_alpha = alpha;
+    if best > alpha {
+        alpha = best;
+    }
+    let mut best_move = None;
 
-    let moves = LazySort::new(&moves, |m| move_key(&position, None, m, g, t));
-    for (_i, _key ,mv) in moves {
-        let mut pos = position.clone();
-        pos.play_unchecked(&mv);
-        let score = -qsearch(pos, -beta, -alpha, g, t);
-        if score >= beta {
+    let mut picker = MovePicker::new(&moves, tt_move);
+    while let Some(mv) = picker.next(position.pos(), t, None) {
+        // SEE pruning: skip captures and checks that lose material, unless we need to escape check
+        if !in_check
+            && !mv.is_promotion()
+            && see(position.pos(), mv) < g.config.qsearch_see_threshold
+            && !check_counts(position.pos(), mv)
+        {
+            continue;
+        }
+
+        let undo = position.make(mv);
+        let score = qsearch(position, ply + 1, qply + 1, -beta, -alpha, g, t);
+        position.unmake(undo);
+        if score == -32768 {
+            // out of time
             return score;
         }
+        let score = -score;
         if score > best {
             best = score;
+            best_move = Some(mv);
+        }
+        if score >= beta {
+            break;
         }
         if score > alpha {
             alpha = score;
         }
     }
-    return best;
+
+    g.tt_write(
+        key,
+        TTEntry {
+            // If standing pat was best, there is no move to store
+            mv: best_move.map_or(0, encode_move),
+            eval: raw_eval.filter(|_| !lazy),
+            depth: 0,
+            value: score_to_tt(best, ply),
+            score_type: if best >= beta {
+                ScoreType::LowerBound
+            } else if best > original_alpha {
+                ScoreType::Exact
+            } else {
+                ScoreType::UpperBound
+            },
+        },
+    );
+    best
+}
+
+// Score for the side to move of a game ended by the rules of a variant
+fn variant_end_score<P: Position>(position: &P, ply: isize, g: &SearchState) -> i16 {
+    match position.variant_outcome() {
+        Some(Outcome::Decisive { winner }) if winner == position.turn() => MATE - ply as i16,
+        Some(Outcome::Decisive { .. }) => -MATE + ply as i16,
+        Some(Outcome::Draw) | None => g.draw_score(ply),
+    }
+}
+
+fn non_pawn_pieces<P: Position>(position: &P) -> usize {
+    let board = position.board();
+    let ours = board.by_color(position.turn());
+    (ours & !board.pawns() & !board.kings()).count()
 }
 
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -132,135 +564,444 @@ enum NodeType {
     All,
 }
 
-fn alphabeta(
-    position: shakmaty::Chess,
-    mut history: Vec<shakmaty::Chess>,
-    depth: isize,
+// Moves are made and unmade on the one position, which is back where it was when the search
+// returns, unless it ran out of time
+#[allow(clippy::too_many_arguments)]
+fn alphabeta<P: Searchable>(
+    position: &mut SearchPosition<P>,
+    mut depth: Depth,
     ply: isize,
     mut alpha: i16,
     beta: i16,
+    cutnode: bool, // expected to fail high; never set at PV nodes
     g: &SearchState,
     t: &mut ThreadState,
 ) -> i16 {
+    debug_assert!(!cutnode || beta as i32 - alpha as i32 == 1);
     g.nodes.nodes.fetch_add(1, Relaxed);
+    #[cfg(test)]
+    if g.config.panic_at_node != 0 && g.nodes.count() >= g.config.panic_at_node {
+        panic!("injected panic");
+    }
     g.nodes.seldepth.fetch_max(ply, Relaxed);
     t.pv[ply as usize][0] = None;
+    if ply as usize >= MAX_PLY {
+        return corrected_eval(position.pos(), t);
+    }
+    t.stack[ply as usize + 1].killers = [None, None];
 
     // Check if we are done; go to qsearch if so
-    if depth <= 0 {
+    if depth < Depth::ONE {
         g.nodes.leaves.fetch_add(1, Relaxed);
-        return qsearch(position, alpha, beta, g, t);
+        return qsearch(position, ply, 0, alpha, beta, g, t);
     }
 
     // Check if we are out of time
-    if g.deadline
-        .check_hard(Instant::now(), g.nodes.count() as usize)
-        || g.stop.load(Relaxed)
-    {
+    if g.check_hard(t, ply) {
         return -32768;
     }
 
+    // Start loading the TT entry now, so move generation hides the memory latency
+    let key = position.zobrist();
+    g.tt.prefetch(key);
+
+    // Variants can end the game with moves left, e.g. on the third check or an exploded king
+    if position.is_variant_end() {
+        return variant_end_score(position, ply, g);
+    }
+
     // Generate moves; detect checkmate/stalemate
     let moves = position.legal_moves();
     if moves.is_empty() {
-        if position.is_check() {
-            return -32700;
+        if position.variant_outcome().is_some() {
+            // e.g. stalemate, which wins in antichess
+            return variant_end_score(position, ply, g);
+        } else if position.is_check() {
+            return -MATE + ply as i16;
         } else {
-            return 0;
+            return g.draw_score(ply);
         }
     }
 
-    let mut child_depth = depth - 1;
-    if position.is_check() {
-        // extend search when in check
-        child_depth += 1;
-    }
+    // In a singular extension search, one move is excluded from the search; since the result is not
+    // the result of the whole node, the TT must not be used for cutoffs or written to
+    let excluded = t.stack[ply as usize].excluded.clone();
 
-    // Fetch TT entry, do IID if there is none
-    let zob: Zobrist64 = position.zobrist_hash(shakmaty::EnPassantMode::Legal);
-    let tt_entry = g.tt.get(&moves, zob.0).or_else(|| {
-        if depth >= 3 {
-            let depth_internal = min(depth - 2, 2);
-            alphabeta(
-                position.clone(),
-                history.clone(),
-                depth_internal,
-                ply,
-                alpha,
-                beta,
-                g,
-                t,
-            );
-            g.tt.get(&moves, zob.0)
-        } else {
-            None
-        }
-    });
+    // Fetch TT entry
+    let tt_entry = g.tt_get(t, position.pos(), &moves, key).map(|tte| TTEntry { value: score_from_tt(tte.value, ply), ..tte });
+
+    // PV nodes (searched with an open window) are where the reported PV and score come from, so they
+    // are not cut short by the TT or speculative pruning
+    let is_pv = beta as i32 - alpha as i32 > 1;
 
     // If we have a valid TT entry, with enough depth, we can potentially use its score (TT-cut)
+    // Never cut at PV nodes (including the root), as we need a PV and best move from there
     if let Some(tte) = tt_entry
-        && tte.depth as isize >= depth
+        && !is_pv
+        && excluded.is_none()
+        && Depth::from_tt(tte.depth) >= depth
+        && tt_cutoff(&tte, alpha, beta)
     {
-        // We can use the TT score for cutoffs, depending on if it's compatible with our alpha/beta window
-        // It is compatible if:
-        // - it is not an upper bound, and the score >= beta (so the real score also >= beta)
-        // - it is not a lower bound, and the score < alpha (so the real score also < alpha)
-        let cut = tte.score_type != ScoreType::UpperBound && tte.value >= beta
-            || tte.score_type != ScoreType::LowerBound && tte.value <= alpha;
+        t.stats.tt_cutoffs += 1;
+        return tte.value;
+    }
 
-        if cut {
-            return tte.value;
-        }
+    // Internal iterative reduction: without a TT move our move ordering is poor, so rather than
+    // spending a full-depth search on this node, search it a bit shallower
+    if g.config.iir
+        && tt_entry.is_none()
+        && depth >= Depth::plies(g.config.iir_depth)
+        && if is_pv { g.config.iir_pv } else { g.config.iir_non_pv }
+    {
+        // at an expected cut node, the first move is likely to cut anyway; reduce more
+        depth -= Depth::plies(if cutnode && g.config.cutnode { 2 } else { 1 });
     }
+    // Pruning margins and the tables indexed by depth count whole plies
+    let plies = depth.whole_plies();
 
-    // three-fold repetition draw detection
-    let reps = history.iter().filter(|h| **h == position).count();
-    if reps >= 2 {
-        // draw
-        return 0;
+    let mut child_depth = depth - Depth::ONE;
+    if position.is_check() {
+        // extend search when in check
+        child_depth += Depth::ONE;
     }
-    // fifty-move rule draw detection
-    if position.halfmoves() >= 100 {
-        return 0;
+
+    // draws by repetition (within the search, or threefold with the game), by the fifty-move rule
+    // or by insufficient material; not at the root, where we still need a move
+    if ply > 0
+        && (position.is_repetition(ply as usize) || position.halfmoves() >= 100 || position.is_insufficient_material())
+    {
+        return g.draw_score(ply);
     }
-    history.push(position.clone());
+    let in_check = position.is_check();
+    let raw_eval = if in_check { None } else { Some(tt_entry.and_then(|tte| tte.eval).unwrap_or_else(|| eval_cached(position.pos(), key))) };
+    let static_eval = raw_eval.map(|raw_eval| correct_eval(position.pos(), t, raw_eval));
+    t.stack[ply as usize].static_eval = static_eval;
 
-    let mut best_value = i16::MIN;
-    let mut best_move = moves[0].clone();
-    let mut node_type = NodeType::All;
-    let mut moves = LazySort::new(&moves, |m| move_key(&position, tt_entry, m, g, t));
-    while let Some((_i, _key, mv)) = moves.next() {
-        let mut pos = position.clone();
-        pos.play_unchecked(mv);
-        let hist = if mv.is_zeroing() { Vec::new() } else { history.clone() };
+    // We are 'improving' if static eval went up compared to our previous move; if we have no
+    // earlier eval to compare to, assume we are. When improving, we prune and reduce less.
+    // If the heuristic is disabled, we always assume to be improving, for the least pruning.
+    let improving = !g.config.improving
+        || match (static_eval, (ply as usize).checked_sub(2).map(|p| t.stack[p].static_eval)) {
+            (None, _) => false,
+            (Some(eval), Some(Some(prev))) => eval > prev,
+            (Some(_), _) => true,
+        };
 
-        let score = alphabeta(pos, hist, child_depth, ply + 1, -beta, -alpha, g, t);
+    // Null move pruning: if passing still fails high in a reduced search, we are probably doing well
+    // enough to cut. Not used when in check, after a null move, or with only pawns left (zugzwang).
+    if g.config.null_move
+        && !is_pv
+        && excluded.is_none()
+        && !in_check
+        && ply > 0
+        && ply >= t.nmp_min_ply
+        && plies >= 3
+        && !is_mate_score(beta)
+        && t.stack[ply as usize - 1].played.is_some()
+        && non_pawn_pieces(position.pos()) > 0
+        && let Some(eval) = static_eval
+        && eval >= beta
+        && let Some(undo) = position.make_null()
+    {
+        // Reduce more at high depth, and when we are far ahead (so the null move is likely to cut anyway)
+        let eval_margin = ((eval as i32 - beta as i32) / g.config.null_move_eval_divisor) as isize;
+        let r = g.config.null_move_base
+            + plies / g.config.null_move_depth_divisor
+            + eval_margin.min(g.config.null_move_eval_max);
+        t.stack[ply as usize].played = None;
+        t.stats.null_move_tries += 1;
+        let score = alphabeta(position, depth - Depth::ONE - Depth::plies(r), ply + 1, -beta, -beta + 1, !cutnode, g, t);
+        position.unmake(undo);
         if score == -32768 {
-            // out of time
             return score;
         }
         let score = -score;
+        if score >= beta {
+            t.stats.null_move_cutoffs += 1;
+            // Do not trust mate scores found after passing
+            let score = if is_mate_score(score) { beta } else { score };
+
+            // Zugzwang is more likely at high depth and with little material, so verify the cutoff
+            // with a reduced search without null moves in the first part of the tree
+            if !g.config.null_move_verification
+                || plies < g.config.null_move_verification_depth && non_pawn_pieces(position.pos()) > 1
+            {
+                return score;
+            }
+            t.nmp_min_ply = ply + 3 * (plies - r) / 4;
+            let verified = alphabeta(position, depth - Depth::plies(r), ply, beta - 1, beta, cutnode, g, t);
+            t.nmp_min_ply = 0;
+            if verified == -32768 {
+                return verified;
+            }
+            if verified >= beta {
+                return score;
+            }
+        }
+    }
+
+    let mut best_value = i16::MIN;
+    let mut best_move = moves[0].clone();
+    let mut node_type = NodeType::All;
+    let mut quiets_searched: ArrayVec<&Move, 256> = ArrayVec::new();
+    let mut captures_searched: ArrayVec<&Move, 256> = ArrayVec::new();
+
+    // The TT move is searched first, so a hash-move cutoff does not need to order the other moves
+    let tt_move = tt_entry.and_then(|tte| tte.best_move(&moves));
+
+    // Singular extensions: if all moves except the TT move fail low against a bound somewhat below
+    // the TT score, the TT move is singular and likely critical, so extend it. If they fail low by a
+    // large margin, extend it twice, but only a few times per line so the search cannot explode.
+    let mut singular_extension = 0;
+    if g.config.singular
+        && ply > 0
+        && excluded.is_none()
+        && plies >= g.config.singular_depth
+        && let (Some(tte), Some(tt_move)) = (tt_entry, tt_move)
+        && tte.score_type != ScoreType::UpperBound
+        && Depth::from_tt(tte.depth) >= depth - Depth::plies(3)
+        && !is_mate_score(tte.value)
+    {
+        if moves.len() == 1 {
+            singular_extension = 1;
+        } else {
+            let singular_beta = tte.value - g.config.singular_margin * plies as i16;
+            t.stack[ply as usize].excluded = Some(tt_move.clone());
+            let score = alphabeta(position, Depth::plies((plies - 1) / 2), ply, singular_beta - 1, singular_beta, cutnode, g, t);
+            t.stack[ply as usize].excluded = None;
+            t.pv[ply as usize][0] = None;
+            if score == -32768 {
+                return score;
+            }
+            if score < singular_beta {
+                singular_extension = 1;
+            }
+            if g.config.double_extension
+                && !is_pv
+                && score < singular_beta - g.config.double_extension_margin
+                && t.stack[ply as usize].double_extensions < g.config.max_double_extensions
+            {
+                singular_extension = 2;
+            }
+        }
+    }
+
+    // At the root, moves are searched in the order prepared by iterative deepening
+    let mut root_order = (ply == 0 && !t.root_order.is_empty()).then(|| {
+        let order: Vec<&Move> = t.root_order.iter().filter_map(|(rm, _)| moves.iter().find(|m| *m == rm)).collect();
+        order.into_iter()
+    });
+    let mut picker = MovePicker::new(&moves, tt_move);
+    while let Some(mv) = match root_order.as_mut() {
+        Some(order) => order.next(),
+        None => picker.next(position.pos(), t, Some(ply as usize)),
+    } {
+        if Some(mv) == excluded.as_ref()
+            || ply == 0 && (t.root_excluded.contains(mv) || !g.root_moves.is_empty() && !g.root_moves.contains(mv))
+        {
+            continue;
+        }
+
+        // SEE pruning: at low depth, skip moves losing too much material, once we have a move that
+        // does not get us mated
+        if g.config.see_pruning
+            && !in_check
+            && plies <= g.config.see_pruning_depth
+            && best_value > -MATE_BOUND
+            && !is_mate_score(alpha)
+        {
+            let margin = if mv.is_capture() { g.config.see_capture_margin } else { g.config.see_quiet_margin };
+            if see(position.pos(), mv) < margin * plies as i16 && !check_counts(position.pos(), mv) {
+                continue;
+            }
+        }
+
+        // Futility pruning: at frontier nodes, quiet moves are unlikely to raise a static eval that
+        // is far below alpha
+        let futility_margin = g.config.futility_margin * (plies + improving as isize) as i16;
+        if g.config.futility
+            && !is_pv
+            && plies <= g.config.futility_depth
+            && !is_mate_score(alpha)
+            && !mv.is_capture()
+            && !mv.is_promotion()
+            && let Some(eval) = static_eval
+            && eval.saturating_add(futility_margin) <= alpha
+        {
+            best_value = best_value.max(eval + futility_margin);
+            continue;
+        }
+
+        // Late move pruning: at low depth, skip quiets after having searched enough other moves
+        let move_count = quiets_searched.len() + captures_searched.len();
+        if g.config.lmp
+            && ply > 0
+            && !in_check
+            && (plies as usize) < LMP_THRESHOLDS[0].len()
+            && alpha > -MATE_BOUND
+            && !mv.is_capture()
+            && !mv.is_promotion()
+            && move_count >= LMP_THRESHOLDS[improving as usize][plies as usize]
+        {
+            continue;
+        }
+
+        // History pruning: at low depth, skip late quiets that have mostly failed low before
+        if g.config.history_pruning
+            && !is_pv
+            && !in_check
+            && plies <= g.config.history_pruning_depth
+            && alpha > -MATE_BOUND
+            && move_count >= g.config.history_pruning_min_moves
+            && !mv.is_capture()
+            && !mv.is_promotion()
+            && Some(mv) != tt_move
+            && quiet_history(t, position.pos(), Some(ply as usize), mv) < -g.config.history_pruning_margin * plies as i32
+            && !gives_check(position.pos(), mv)
+        {
+            continue;
+        }
+        t.stack[ply as usize].played = Some(PlayedMove {
+            piece: piece_index(position.turn(), mv.role()),
+            to: mv.to(),
+            capture: mv.is_capture(),
+        });
+        // Extensions: singular TT moves, recaptures on the square of the previous capture, and pawn
+        // pushes to the 7th rank. The extensions along a line are limited, to bound the search.
+        let recapture = ply > 0
+            && mv.is_capture()
+            && t.stack[ply as usize - 1].played.is_some_and(|prev| prev.capture && prev.to == mv.to());
+        let pawn_push = mv.role() == Role::Pawn && mv.to().rank() == position.turn().relative_rank(Rank::Seventh);
+        let mut extension = if Some(mv) == tt_move && singular_extension > 0 {
+            singular_extension
+        } else if g.config.recapture_extension && recapture || g.config.pawn_push_extension && pawn_push {
+            1
+        } else {
+            0
+        };
+        extension = extension.min(g.config.max_extensions - t.stack[ply as usize].extensions).max(0);
+        t.stack[ply as usize + 1].extensions = t.stack[ply as usize].extensions + extension;
+        t.stack[ply as usize + 1].double_extensions = t.stack[ply as usize].double_extensions + (extension > 1) as isize;
+        let child_depth = child_depth + Depth::plies(extension);
+
+        let undo = position.make(mv);
+
+        // Late move reductions: quiet moves late in the ordering are unlikely to be good, so search
+        // them at reduced depth first and only search them fully if they turn out to beat alpha
+        let mut reduction = Depth::ZERO;
+        if g.config.lmr
+            && plies >= 3
+            && move_count >= g.config.lmr_min_moves
+            && !in_check
+            && !mv.is_capture()
+            && !mv.is_promotion()
+            && !position.is_check()
+        {
+            reduction = g.lmr[(plies as usize).min(63)][move_count.min(63)];
+            if Some(mv) == tt_move || t.stack[ply as usize].killers.iter().any(|k| k.as_ref() == Some(mv)) {
+                reduction -= Depth::ONE;
+            }
+            if !improving {
+                reduction += Depth::ONE;
+            }
+            if cutnode && g.config.cutnode {
+                reduction += Depth::ONE;
+            }
+            reduction = reduction.clamp(Depth::ZERO, child_depth - Depth::ONE);
+        }
+
+        let nodes_before = g.nodes.count();
+
+        // Principal variation search: the first move is searched with the full window. Later moves
+        // are expected to be worse, so we only try to prove that with a zero window (at reduced
+        // depth if possible), and re-search at full depth and then with the full window if they
+        // turn out to beat alpha after all.
+        // Node types alternate: the first move of a cut node leads to an expected all node and
+        // vice versa, while a zero window search of a later move expects to fail high.
+        // Root noise (for opening variety) is added to the score of a root move afterwards, so the
+        // move is searched with the window shifted down by it
+        let noise = if ply == 0 { g.root_noise(mv) } else { 0 };
+        let shift = |bound: i16| (bound as i32 - noise as i32).max(i16::MIN as i32 + 1) as i16;
+        let (window_alpha, window_beta) = (shift(alpha), shift(beta));
+        let mut score;
+        if move_count == 0 {
+            let full = alphabeta(position, child_depth, ply + 1, -window_beta, -window_alpha, !is_pv && !cutnode, g, t);
+            if full == -32768 {
+                // out of time
+                return full;
+            }
+            score = -full;
+        } else {
+            let zw = alphabeta(position, child_depth - reduction, ply + 1, -window_alpha - 1, -window_alpha, true, g, t);
+            if zw == -32768 {
+                // out of time
+                return zw;
+            }
+            score = -zw;
+            if score > window_alpha && reduction > Depth::ZERO {
+                t.stats.lmr_researches += 1;
+                let zw = alphabeta(position, child_depth, ply + 1, -window_alpha - 1, -window_alpha, !cutnode, g, t);
+                if zw == -32768 {
+                    // out of time
+                    return zw;
+                }
+                score = -zw;
+            }
+            if score > window_alpha && score < window_beta {
+                t.stats.pvs_researches += 1;
+                let full = alphabeta(position, child_depth, ply + 1, -window_beta, -window_alpha, false, g, t);
+                if full == -32768 {
+                    // out of time
+                    return full;
+                }
+                score = -full;
+            }
+        }
+        position.unmake(undo);
+        if !is_mate_score(score) {
+            score += noise;
+        }
+        if ply == 0
+            && let Some(entry) = t.root_order.iter_mut().find(|(rm, _)| rm == mv)
+        {
+            entry.1 += g.nodes.count() - nodes_before;
+        }
+        if ply == 0 && score <= alpha && g.config.show_refutations {
+            let mut after = position.pos().clone();
+            after.play_unchecked(mv);
+            let line = g.tt.extract_pv(&after, REFUTATION_LENGTH);
+            t.refutations.retain(|(rm, _)| rm != mv);
+            t.refutations.push((mv.clone(), line));
+        }
         if score > best_value {
             best_value = score;
             best_move = mv.clone();
             if score >= beta {
                 // fail-soft
                 node_type = NodeType::Cut;
+                t.stats.beta_cutoffs += 1;
+                t.stats.first_move_cutoffs += (move_count == 0) as u64;
 
-                // Update butterfly table
+                // Update killers and butterfly table
                 if !mv.is_capture() {
-                    const MAX_HISTORY: i32 = 16384;
-                    let bonus = (300 * depth as i32 - 250).clamp(-MAX_HISTORY, MAX_HISTORY);
-                    let col = position.turn() as usize;
-                    let from = mv.from().unwrap() as usize;
-                    let to = mv.to() as usize;
-                    t.butterfly[col][from][to] += (bonus - (t.butterfly[col][from][to] as i32 * bonus.abs()) / (MAX_HISTORY)) as i16;
-
-                    for fail in moves.seen().filter(|m| !m.is_capture() && *m != mv) {
-                        let from = fail.from().unwrap() as usize;
-                        let to = fail.to() as usize;
-                        t.butterfly[col][from][to] -= (bonus - (t.butterfly[col][from][to] as i32 * bonus.abs()) / (MAX_HISTORY)) as i16;
+                    store_killer(&mut t.stack[ply as usize].killers, mv);
+
+                    let bonus = history_bonus(plies);
+                    update_quiet_history(t, position.pos(), ply as usize, mv, bonus);
+
+                    // Quiets that were searched before the cutoff move failed low, so punish them
+                    for fail in quiets_searched {
+                        update_quiet_history(t, position.pos(), ply as usize, fail, -bonus);
                     }
+                } else {
+                    let bonus = history_bonus(plies);
+                    update_history(capture_history_mut(&mut t.capture_history, position.pos(), mv), bonus);
+                }
+
+                // Captures searched before the cutoff move failed low, regardless of what caused the cutoff
+                for fail in captures_searched {
+                    let bonus = history_bonus(plies);
+                    update_history(capture_history_mut(&mut t.capture_history, position.pos(), fail), -bonus);
                 }
 
                 break;
@@ -268,28 +1009,59 @@ fn alphabeta(
             if score > alpha {
                 alpha = score;
                 node_type = NodeType::PV;
+                // Alpha can only be raised inside the window, so this only happens at PV nodes
                 t.pv[ply as usize][0] = Some(mv.clone());
-                for i in 0..255 {
+                for i in 0..MAX_PLY {
                     t.pv[ply as usize][i + 1] = t.pv[ply as usize + 1][i].clone();
+                    if t.pv[ply as usize][i + 1].is_none() {
+                        break;
+                    }
+                }
+                if ply == 0 {
+                    t.root_best = Some(score);
                 }
             }
 
         }
+        if mv.is_capture() {
+            captures_searched.push(mv);
+        } else {
+            quiets_searched.push(mv);
+        }
     }
 
-    if best_value < -32500 {
-        best_value += 1;
+    // Likewise, with root moves excluded for MultiPV or searchmoves, the root result is not that of
+    // the whole node
+    if excluded.is_some() || ply == 0 && (!t.root_excluded.is_empty() || !g.root_moves.is_empty()) {
+        return best_value;
     }
-    if best_value > 32500 {
-        best_value -= 1;
+
+    // Update correction history, if the search score is informative about the static eval:
+    // skip when in check, after a capture (which static eval cannot be expected to see) and when the
+    // bound is on the wrong side of the static eval
+
+    if g.config.correction
+        && let Some(raw_eval) = raw_eval
+        && !best_move.is_capture()
+        && !is_mate_score(best_value)
+    {
+        let informative = match node_type {
+            NodeType::PV => true,
+            NodeType::Cut => best_value > raw_eval,
+            NodeType::All => best_value < raw_eval,
+        };
+        if informative {
+            update_correction(t, position.pos(), plies, best_value - raw_eval);
+        }
     }
-    g.tt.write(
-        zob.0,
+
+    g.tt_write(
+        key,
         TTEntry {
-            from: best_move.from().unwrap() as u8,
-            to: best_move.to() as u8,
-            depth: depth as u8,
-            value: best_value,
+            mv: encode_move(&best_move),
+            eval: raw_eval,
+            depth: depth.to_tt(),
+            value: score_to_tt(best_value, ply),
             score_type: match node_type {
                 NodeType::PV => ScoreType::Exact,
                 NodeType::Cut => ScoreType::LowerBound,
@@ -297,40 +1069,152 @@ fn alphabeta(
             },
         },
     );
-    return best_value;
+    best_value
 }
 
 fn convert_score(score: i16) -> ruci::Score {
-    if score > 32000 {
-        ruci::Score::MateIn((32701 - score as isize) / 2)
-    } else if score < -32000 {
-        ruci::Score::MateIn(-(32700 + score) as isize / 2)
+    if is_mate_score(score) {
+        ruci::Score::MateIn(mate_in(score))
     } else {
         ruci::Score::Centipawns(score as isize)
     }
 }
 
-fn collect_pv(t: &ThreadState) -> Vec<Move> {
+// Reads the PV from the root row of the PV table, stopping at the first move that is not legal
+// (which could only happen if a line was cut off without clearing the rest of it). The PV table
+// loses the line at TT cutoffs, so a PV shorter than the depth is continued from the TT.
+fn collect_pv<P: Position + Clone>(position: &P, depth: isize, g: &SearchState, t: &ThreadState) -> Vec<Move> {
     let mut pv = Vec::new();
-    for i in 0..256 {
-        match &t.pv[0][i] {
-            Some(mv) => pv.push(mv.clone()),
-            None => break,
+    let mut pos = position.clone();
+    for mv in t.pv[0].iter().map_while(|mv| mv.as_ref()) {
+        if !pos.is_legal(mv) {
+            break;
         }
+        pos.play_unchecked(mv);
+        pv.push(mv.clone());
+    }
+    if !pv.is_empty() && pv.len() < depth as usize {
+        pv.extend(g.tt.extract_pv(&pos, depth as usize - pv.len()));
     }
     pv
 }
 
-pub fn search(
-    position: shakmaty::Chess,
-    history: Vec<shakmaty::Chess>,
+// Called with the depth, the MultiPV line index (starting at 1), the score and bound, and the PV
+pub type InfoCallback<'a> =
+    dyn FnMut(isize, usize, ruci::Score, Option<ruci::ScoreBound>, &Vec<Move>, &NodeCount) + 'a;
+
+// Called after each iteration with a root move that failed low, and the line refuting it
+pub type RefutationCallback<'a> = dyn FnMut(&Move, &[Move]) + 'a;
+
+// Maximum length of a refutation line
+const REFUTATION_LENGTH: usize = 8;
+
+impl ThreadState {
+    fn new() -> Self {
+        ThreadState {
+            butterfly: [[[0; 64]; 64]; 2],
+            capture_history: [[[[0; 6]; 64]; 6]; 2],
+            cont_history: [new_continuation_history(), new_continuation_history()],
+            stack: std::array::from_fn(|_| SearchStackEntry::default()),
+            nmp_min_ply: 0,
+            correction: Box::new([[0; CORRECTION_SIZE]; 2]),
+            pv: std::array::from_fn(|_| std::array::from_fn(|_| None)),
+            root_excluded: Vec::new(),
+            root_order: Vec::new(),
+            clock_countdown: 0,
+            abortable: true,
+            root_best: None,
+            refutations: Vec::new(),
+            stats: SearchStats::default(),
+        }
+    }
+
+    // Prepare for the next search of the same game: the tables are kept, but the histories are halved
+    // so that old information fades. Killers are indexed by ply from the root, which has moved, so
+    // they are cleared.
+    fn new_search(&mut self) {
+        self.butterfly.iter_mut().flatten().flatten().for_each(|v| *v /= 2);
+        self.capture_history.iter_mut().flatten().flatten().flatten().for_each(|v| *v /= 2);
+        for table in self.cont_history.iter_mut() {
+            table.iter_mut().flatten().flatten().flatten().for_each(|v| *v /= 2);
+        }
+        self.correction.iter_mut().flatten().for_each(|v| *v /= 2);
+        for entry in self.stack.iter_mut() {
+            *entry = SearchStackEntry::default();
+        }
+        self.nmp_min_ply = 0;
+        self.clock_countdown = 0;
+        self.abortable = true;
+        self.refutations.clear();
+        self.stats = SearchStats::default();
+    }
+}
+
+// Per-thread search tables (histories and correction history), kept between the searches of a game
+#[derive(Default)]
+pub struct SearchTables {
+    threads: Vec<ThreadState>,
+}
+
+impl SearchTables {
+    // Forget everything, e.g. for a new game
+    pub fn clear(&mut self) {
+        self.threads.clear();
+    }
+}
+
+// Root noise: in the first moves of the game, each root move gets a random bonus of at most
+// `root_noise` centipawns, so games do not all follow the same opening. Moves more than that apart
+// keep their order. The bonus only depends on the seed, the position and the move, so it is
+// reproducible with a fixed seed.
+fn root_noise<P: Position>(position: &P, config: &crate::Configuration) -> Vec<(Move, i16)> {
+    if config.root_noise == 0 || config.analyse_mode || position.fullmoves().get() as usize > config.root_noise_moves {
+        return Vec::new();
+    }
+    let zob: Zobrist64 = position.zobrist_hash(shakmaty::EnPassantMode::Legal);
+    position
+        .legal_moves()
+        .into_iter()
+        .map(|m| {
+            let key = (m.from().unwrap() as u64) << 6 | m.to() as u64 | (m.promotion().map_or(0, |r| r as u64) << 12);
+            let random = splitmix64(config.root_noise_seed ^ zob.0 ^ splitmix64(key));
+            let noise = (random % (config.root_noise as u64 + 1)) as i16;
+            (m, noise)
+        })
+        .collect()
+}
+
+// Stops the helpers when the main thread is done, also if it panics, so the scope can still be left
+struct StopGuard<'a>(&'a SearchControl);
+
+impl Drop for StopGuard<'_> {
+    fn drop(&mut self) {
+        self.0.stop.store(true, Relaxed);
+    }
+}
+
+// The position types the search runs on: Chess, and VariantPosition for the variants
+pub trait Searchable: Rules + Evaluate + Send + Sync {}
+
+impl<P: Rules + Evaluate + Send + Sync> Searchable for P {}
+
+// Stack size for threads running a search
+pub const STACK_SIZE: usize = 64 * 1024 * 1024;
+
+#[allow(clippy::too_many_arguments)]
+pub fn search<P: Searchable>(
+    position: SearchPosition<P>,
+    root_moves: &[Move],
     deadline: time::Deadline,
+    mate: Option<usize>, // stop as soon as a mate in at most this many moves is found
     tt: &TT,
     config: &crate::Configuration,
-    callback: &mut dyn FnMut(isize, ruci::Score, &Vec<Move>, &NodeCount),
-) -> (ruci::Score, Vec<Move>, NodeCount) {
-    let mut score = eval(&position);
-    let mut pv = Vec::new();
+    tables: &mut SearchTables,
+    control: &SearchControl,
+    callback: &mut InfoCallback,
+    refutation_callback: Option<&mut RefutationCallback>,
+) -> (ruci::Score, Vec<Move>, NodeCount, SearchStats) {
+    tt.new_search();
     let global = SearchState {
         config,
         tt,
@@ -341,39 +1225,296 @@ pub fn search(
             seldepth: AtomicIsize::new(0),
         },
         deadline,
-        stop: AtomicBool::new(false),
+        control,
+        lmr: lmr_table(config),
+        root_moves,
+        contempt: if config.analyse_mode { 0 } else { config.contempt },
+        root_noise: root_noise(position.pos(), config),
     };
-    let mut local = ThreadState {
-        butterfly: [[[0; 64]; 64]; 2],
-        pv: std::array::from_fn(|_| std::array::from_fn(|_| None)),
+
+    // Lazy SMP: helper threads search the same root, sharing only the TT (and the node counts and
+    // search control). Their results are never reported, but the TT entries they leave behind speed up
+    // the main thread. Helpers are stopped when the main thread is done.
+    if tables.threads.len() < config.threads {
+        tables.threads.resize_with(config.threads, ThreadState::new);
+    }
+    let (local, helper_tables) = tables.threads[..config.threads].split_first_mut().unwrap();
+    let (lines, stats) = std::thread::scope(|scope| {
+        let helpers: Vec<_> = helper_tables
+            .iter_mut()
+            .enumerate()
+            .map(|(i, helper)| {
+                let (position, global) = (position.clone(), &global);
+                std::thread::Builder::new()
+                    .stack_size(STACK_SIZE)
+                    .spawn_scoped(scope, move || {
+                        helper.new_search();
+                        iterative_deepening(&position, None, i + 1, global, helper, None, None);
+                        helper.stats
+                    })
+                    .expect("failed to spawn search thread")
+            })
+            .collect();
+        local.new_search();
+        let lines = {
+            let _stop = StopGuard(control);
+            iterative_deepening(&position, mate, 0, &global, local, Some(callback), refutation_callback)
+        };
+        let mut stats = local.stats;
+        for helper in helpers {
+            stats += helper.join().expect("search thread panicked");
+        }
+        (lines, stats)
+    });
+
+    match lines.into_iter().next() {
+        Some((score, pv)) => (convert_score(score), pv, global.nodes, stats),
+        None => (convert_score(position.pos().evaluate()), Vec::new(), global.nodes, stats),
+    }
+}
+
+// Upper bound for the estimated growth in time of the next iteration
+const MAX_EBF: f64 = 4.0;
+
+// Whether the next iteration is expected to finish in the remaining time: its duration is estimated
+// from the last one, times the growth from the one before (effective branching factor)
+fn next_iteration_fits(
+    iteration_time: std::time::Duration,
+    last_iteration_time: Option<std::time::Duration>,
+    remaining: std::time::Duration,
+) -> bool {
+    let ebf = match last_iteration_time {
+        Some(last) => (iteration_time.as_secs_f64() / last.as_secs_f64().max(1e-6)).clamp(1.0, MAX_EBF),
+        None => MAX_EBF,
     };
-    for d in 1.. {
-        let alpha = score - 50;
-        let beta = score + 50;
-        let asp_score = alphabeta(position.clone(), history.clone(), d, 0, alpha, beta, &global, &mut local);
-        let new_score = if asp_score > alpha && asp_score < beta {
-            asp_score 
+    iteration_time.mul_f64(ebf) <= remaining
+}
+
+// Soft deadline scale by best move stability
+const STABILITY_SCALE: [f64; 5] = [2.0, 1.4, 1.1, 0.9, 0.8];
+
+// Counts the iterations the best move did not change, to spend more time when it just changed, and
+// less when it has been stable for a while
+#[derive(Default)]
+struct Stability {
+    best_move: Option<Move>,
+    iterations: usize,
+}
+
+impl Stability {
+    fn update(&mut self, best_move: Option<&Move>) {
+        if best_move == self.best_move.as_ref() {
+            self.iterations = (self.iterations + 1).min(STABILITY_SCALE.len() - 1);
         } else {
-            alphabeta(position.clone(), history.clone(), d, 0, i16::MIN + 1, i16::MAX - 1, &global, &mut local)
+            self.iterations = 0;
+            self.best_move = best_move.cloned();
+        }
+    }
+
+    // Scale of the soft deadline
+    fn scale(&self) -> f64 {
+        STABILITY_SCALE[self.iterations]
+    }
+}
+
+// Iterative deepening loop of a single thread, returning the lines of the last completed iteration,
+// best first: (score, PV). Only the main thread (id 0) reports info, uses MultiPV and stops by
+// itself; helpers search until stopped.
+#[allow(clippy::too_many_arguments)]
+fn iterative_deepening<P: Searchable>(
+    root: &SearchPosition<P>,
+    mate: Option<usize>,
+    id: usize,
+    global: &SearchState,
+    local: &mut ThreadState,
+    mut callback: Option<&mut InfoCallback>,
+    mut refutation_callback: Option<&mut RefutationCallback>,
+) -> Vec<(i16, Vec<Move>)> {
+    let config = global.config;
+    let position = root.pos();
+    let mut lines: Vec<(i16, Vec<Move>)> = Vec::new();
+    let root_move_count = if global.root_moves.is_empty() {
+        position.legal_moves().len()
+    } else {
+        global.root_moves.len()
+    };
+    let num_lines = if id == 0 { config.multipv.clamp(1, root_move_count.max(1)) } else { 1 };
+    local.root_order = position
+        .legal_moves()
+        .into_iter()
+        .filter(|m| global.root_moves.is_empty() || global.root_moves.contains(m))
+        .map(|m| (m, 0))
+        .collect();
+    let mut stability = Stability::default();
+    let mut iteration_start = Instant::now();
+    let mut last_iteration_time: Option<std::time::Duration> = None;
+    for iteration in 1..MAX_PLY as isize {
+        // Half of the helpers search one ply deeper, so the threads do not all search the same tree
+        let d = iteration + (id % 2) as isize;
+        if d >= MAX_PLY as isize {
+            break;
+        }
+        // Age the history table, so results from earlier (shallower) iterations slowly fade
+        for entry in local.butterfly.iter_mut().flatten().flatten() {
+            *entry /= 2;
+        }
+        for entry in local.capture_history.iter_mut().flatten().flatten().flatten() {
+            *entry /= 2;
+        }
+        for entry in local.cont_history.iter_mut().flat_map(|c| c.iter_mut()).flatten().flatten().flatten() {
+            *entry /= 2;
+        }
+        // Order the root moves: the best move of the last iteration first, then the other moves by the
+        // number of nodes spent on them, as moves that were hard to refute are likely to be good
+        if let Some(best) = lines.first().and_then(|(_, pv)| pv.first()) {
+            local.root_order.sort_by_key(|(m, nodes)| (m != best, std::cmp::Reverse(*nodes)));
+            debug_assert!(local.root_order[0].0 == *best);
+        }
+        for entry in local.root_order.iter_mut() {
+            entry.1 = 0;
+        }
+
+        // MultiPV: search the root once per line, each time excluding the best moves of the earlier
+        // lines of this iteration
+        let mut new_lines = Vec::new();
+        local.root_excluded.clear();
+        local.root_best = None;
+        local.refutations.clear();
+        // Always complete the first iteration, so we have a move to play
+        local.abortable = iteration > 1;
+        for line in 0..num_lines {
+            let (score, pv) = lines.get(line).cloned().unwrap_or_else(|| (position.evaluate(), Vec::new()));
+
+            // Aspiration windows: from some depth on, we expect the score to be close to the previous
+            // iteration's, so search with a narrow window around it, widening it on failure
+            let mut delta = config.aspiration_window as i32;
+            let (mut alpha, mut beta) = if let Some(n) = mate
+                && line == 0
+            {
+                // go mate: a window just below the mate score only has to prove (or refute) the mate,
+                // which is much cheaper than a full search; a failure widens it like an aspiration window
+                (mate_within(n) - 1, i16::MAX - 1)
+            } else if d >= config.aspiration_depth && !is_mate_score(score) {
+                (score.saturating_sub(delta as i16), score.saturating_add(delta as i16))
+            } else {
+                (i16::MIN + 1, i16::MAX - 1)
+            };
+            let new_score = loop {
+                // A search that runs out of time leaves its position halfway down a line, so every
+                // search starts from a copy of the root
+                let asp_score = alphabeta(&mut root.clone(), Depth::plies(d), 0, alpha, beta, false, global, local);
+                if asp_score == -32768 {
+                    break asp_score;
+                }
+                delta *= 2;
+                if asp_score <= alpha && alpha > i16::MIN + 1 {
+                    if let Some(callback) = callback.as_mut() {
+                        let bound = Some(ruci::ScoreBound::UpperBound);
+                        callback(d, line + 1, convert_score(asp_score), bound, &pv, &global.nodes);
+                    }
+                    alpha = if asp_score < -MATE_BOUND {
+                        i16::MIN + 1
+                    } else {
+                        (asp_score as i32 - delta).max(i16::MIN as i32 + 1) as i16
+                    };
+                } else if asp_score >= beta && beta < i16::MAX - 1 {
+                    if let Some(callback) = callback.as_mut() {
+                        let bound = Some(ruci::ScoreBound::LowerBound);
+                        callback(d, line + 1, convert_score(asp_score), bound, &pv, &global.nodes);
+                    }
+                    beta = if asp_score > MATE_BOUND {
+                        i16::MAX - 1
+                    } else {
+                        (asp_score as i32 + delta).min(i16::MAX as i32 - 1) as i16
+                    };
+                } else {
+                    break asp_score;
+                }
+            };
+            if new_score == -32768 {
+                break;
+            }
+            let pv = collect_pv(position, d, global, local);
+            if let Some(mv) = pv.first() {
+                local.root_excluded.push(mv.clone());
+            }
+            new_lines.push((new_score, pv));
+        }
+        if new_lines.len() < num_lines {
+            // Out of time. Normally the last completed iteration is used, but if a root move already
+            // got an exact score better than the last iteration's best, it is trusted over it.
+            if num_lines == 1
+                && let Some(score) = local.root_best
+                && lines.first().is_none_or(|(best, _)| score > *best)
+            {
+                let pv = collect_pv(position, d, global, local);
+                if !pv.is_empty() {
+                    lines = vec![(score, pv)];
+                }
+            }
+            // Without any result (only when stopped during the first iteration), play any move
+            if lines.is_empty()
+                && let Some((mv, _)) = local.root_order.first()
+            {
+                lines.push((position.evaluate(), vec![mv.clone()]));
+            }
+            if let Some(callback) = callback.as_mut()
+                && let Some((score, pv)) = lines.first()
+            {
+                callback(65535, 1, convert_score(*score), None, pv, &global.nodes);
+            }
+            break;
+        }
+
+        // Later lines can score better than earlier ones, e.g. after an aspiration failure
+        new_lines.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+        lines = new_lines;
+        let Some(callback) = callback.as_mut() else {
+            continue;
         };
-        if new_score == -32768 {
-            // out of time
-            callback(65535, convert_score(score), &pv, &global.nodes);
+        for (i, (score, pv)) in lines.iter().enumerate() {
+            callback(d, i + 1, convert_score(*score), None, pv, &global.nodes);
+        }
+        if let Some(refutation_callback) = refutation_callback.as_mut() {
+            // Moves that failed low in one search can still be best in a later (MultiPV) line
+            for (mv, line) in local.refutations.iter() {
+                if !line.is_empty() && !lines.iter().any(|(_, pv)| pv.first() == Some(mv)) {
+                    refutation_callback(mv, line);
+                }
+            }
+        }
+        // go mate: stop once the mate is proven, and the PV shows the whole mating line (a mate found by
+        // qsearch at the horizon is not in it yet)
+        if let Some(n) = mate
+            && lines[0].0 >= mate_within(n)
+            && lines[0].1.len() >= (MATE - lines[0].0) as usize
+        {
+            break;
+        }
+        // With only one move to choose from, there is no point in spending time on the clock
+        if root_move_count == 1
+            && matches!(global.deadline, time::Deadline::Time(..))
+            && !global.control.pondering.load(Relaxed)
+        {
             break;
         }
-        pv = collect_pv(&local);
-        score = new_score;
-        callback(d, convert_score(score), &pv, &global.nodes);
-        if !pv.is_empty()
-            && global.deadline.check_soft(
-                Instant::now(),
-                (global.nodes.count()) as usize,
-                d as usize,
-            )
+        stability.update(lines[0].1.first());
+        if !lines[0].1.is_empty() && global.check_soft(d as usize, stability.scale()) {
+            break;
+        }
+
+        // Do not start an iteration that is unlikely to finish before the soft deadline
+        let now = Instant::now();
+        let iteration_time = now - iteration_start;
+        iteration_start = now;
+        let last = last_iteration_time.replace(iteration_time);
+        if !global.control.pondering.load(Relaxed)
+            && let Some(remaining) = global.deadline.soft_remaining(global.now(), stability.scale())
+            && !next_iteration_fits(iteration_time, last, remaining)
         {
             break;
         }
     }
 
-    (convert_score(score), pv, global.nodes)
+    lines
 }
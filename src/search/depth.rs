@@ -0,0 +1,96 @@
+use std::ops::{Add, AddAssign, Sub, SubAssign};
+
+// Search depth
+// Counted in fractions of a ply, so reductions and extensions do not have to be whole plies. UCI,
+// the search configuration and the tables indexed by depth still count whole plies.
+
+pub const ONE_PLY: i32 = 4;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub struct Depth(i32);
+
+impl Depth {
+    pub const ZERO: Depth = Depth(0);
+    pub const ONE: Depth = Depth(ONE_PLY);
+
+    pub const fn plies(plies: isize) -> Depth {
+        Depth(plies as i32 * ONE_PLY)
+    }
+
+    // Fractional plies, rounded down to a unit
+    pub fn fractional_plies(plies: f64) -> Depth {
+        Depth((plies * ONE_PLY as f64).floor() as i32)
+    }
+
+    // Whole plies, rounded down
+    pub const fn whole_plies(self) -> isize {
+        self.0.div_euclid(ONE_PLY) as isize
+    }
+
+    // The TT stores the depth in a byte, so depths beyond 63 plies are saturated
+    pub fn to_tt(self) -> u8 {
+        self.0.clamp(0, u8::MAX as i32) as u8
+    }
+
+    pub fn from_tt(depth: u8) -> Depth {
+        Depth(depth as i32)
+    }
+}
+
+impl Add for Depth {
+    type Output = Depth;
+    fn add(self, other: Depth) -> Depth {
+        Depth(self.0 + other.0)
+    }
+}
+
+impl Sub for Depth {
+    type Output = Depth;
+    fn sub(self, other: Depth) -> Depth {
+        Depth(self.0 - other.0)
+    }
+}
+
+impl AddAssign for Depth {
+    fn add_assign(&mut self, other: Depth) {
+        self.0 += other.0;
+    }
+}
+
+impl SubAssign for Depth {
+    fn sub_assign(&mut self, other: Depth) {
+        self.0 -= other.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tt_round_trip() {
+        // Every depth the TT can hold, including fractional ones, comes back unchanged
+        for units in 0..=u8::MAX as i32 {
+            let depth = Depth(units);
+            assert_eq!(Depth::from_tt(depth.to_tt()), depth);
+        }
+        assert_eq!(Depth::from_tt(Depth::plies(2).to_tt()), Depth::plies(2));
+        assert_eq!(Depth::from_tt(Depth::fractional_plies(2.5).to_tt()), Depth::plies(2) + Depth(ONE_PLY / 2));
+        // Negative depths (qsearch) are stored as 0, and very deep searches saturate
+        assert_eq!(Depth::from_tt(Depth(-3).to_tt()), Depth::ZERO);
+        assert_eq!(Depth::from_tt(Depth::plies(100).to_tt()), Depth(u8::MAX as i32));
+    }
+
+    #[test]
+    fn whole_plies() {
+        for plies in -3..64 {
+            assert_eq!(Depth::plies(plies).whole_plies(), plies);
+            // Whole ply arithmetic stays whole
+            assert_eq!((Depth::plies(plies) + Depth::ONE - Depth::plies(2)).whole_plies(), plies - 1);
+        }
+        // Fractions round down, also below zero
+        assert_eq!(Depth::fractional_plies(2.9).whole_plies(), 2);
+        assert_eq!((Depth::ZERO - Depth(1)).whole_plies(), -1);
+        assert_eq!(Depth::fractional_plies(1.3), Depth(ONE_PLY + 1));
+    }
+}
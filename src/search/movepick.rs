@@ -0,0 +1,148 @@
+use shakmaty::{Move, Position, Square};
+
+use super::see::{see, see_value};
+use super::{CAPTURE_HISTORY_SCALE, ThreadState, capture_history, quiet_history};
+use crate::eval::eval_piece;
+use crate::util::sort::LazySort;
+
+// Staged move picker
+// Yields moves in the order: TT move, good captures (SEE >= 0) and promotions, killers, quiets by
+// history, bad captures. Each stage is only scored when it is reached, so nodes that cut off early
+// do not pay for ordering the moves they never search.
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Stage {
+    TTMove,
+    GoodCaptures,
+    Killers,
+    Quiets,
+    BadCaptures,
+    Done,
+}
+
+// Note: for all keys, lower is better, as that is how LazySort orders
+type CaptureKey = (i32, i16); // -(victim value + capture history), -aggressor value
+
+pub struct MovePicker<'a> {
+    moves: &'a [Move],
+    tt_move: Option<&'a Move>,
+    stage: Stage,
+    good_captures: Option<LazySort<&'a Move, CaptureKey>>,
+    bad_captures: Vec<(&'a Move, i16)>,
+    killers: [Option<&'a Move>; 2],
+    killer_index: usize,
+    quiets: Option<LazySort<&'a Move, i32>>,
+}
+
+impl<'a> MovePicker<'a> {
+    pub fn new(moves: &'a [Move], tt_move: Option<&'a Move>) -> Self {
+        MovePicker {
+            moves,
+            tt_move,
+            stage: Stage::TTMove,
+            good_captures: None,
+            bad_captures: Vec::new(),
+            killers: [None, None],
+            killer_index: 0,
+            quiets: None,
+        }
+    }
+
+    fn is_tactical(m: &Move) -> bool {
+        m.is_capture() || m.is_promotion()
+    }
+
+    fn capture_key<P: Position>(pos: &P, t: &ThreadState, m: &Move) -> CaptureKey {
+        // for captures, order by MVV-LVA, adjusted by capture history
+        let mut victim_value = 0;
+        if let Some(captured) = m.capture() {
+            let from = m.from().unwrap();
+            let victim_pos = if m.is_en_passant() { Square::from_coords(m.to().file(), from.rank()) } else { m.to() };
+            victim_value += eval_piece(victim_pos, pos.turn().other(), captured) as i32;
+            victim_value += *capture_history(&t.capture_history, pos, m) as i32 / CAPTURE_HISTORY_SCALE;
+        }
+        if let Some(promotion) = m.promotion() {
+            victim_value += see_value(promotion) as i32;
+        }
+        let aggressor_value = eval_piece(m.from().unwrap(), pos.turn(), m.role());
+        (-victim_value, -aggressor_value)
+    }
+
+    // Returns the next move to search, with `ply` (if any) used for killers and continuation history
+    pub fn next<P: Position>(&mut self, pos: &P, t: &ThreadState, ply: Option<usize>) -> Option<&'a Move> {
+        loop {
+            match self.stage {
+                Stage::TTMove => {
+                    self.stage = Stage::GoodCaptures;
+                    if self.tt_move.is_some() {
+                        return self.tt_move;
+                    }
+                }
+                Stage::GoodCaptures => {
+                    let tt_move = self.tt_move;
+                    let bad_captures = &mut self.bad_captures;
+                    let good_captures = self.good_captures.get_or_insert_with(|| {
+                        let captures = self.moves.iter().filter(|m| Self::is_tactical(m) && Some(*m) != tt_move);
+                        let good = captures.filter(|m| {
+                            if m.is_promotion() {
+                                return true;
+                            }
+                            let see = see(pos, m);
+                            if see < 0 {
+                                bad_captures.push((m, see));
+                            }
+                            see >= 0
+                        });
+                        LazySort::new(good, |m| Self::capture_key(pos, t, m))
+                    });
+                    match good_captures.next() {
+                        Some((_i, _key, m)) => return Some(m),
+                        None => self.stage = Stage::Killers,
+                    }
+                }
+                Stage::Killers => {
+                    // Killers are only kept as moves, so find them in the legal moves first
+                    if self.killer_index == 0
+                        && let Some(ply) = ply
+                    {
+                        for (slot, killer) in t.stack[ply].killers.iter().enumerate() {
+                            self.killers[slot] = killer.as_ref().and_then(|k| {
+                                self.moves.iter().find(|m| *m == k && !Self::is_tactical(m) && Some(*m) != self.tt_move)
+                            });
+                        }
+                    }
+                    while self.killer_index < self.killers.len() {
+                        self.killer_index += 1;
+                        if let Some(killer) = self.killers[self.killer_index - 1] {
+                            return Some(killer);
+                        }
+                    }
+                    self.stage = Stage::Quiets;
+                }
+                Stage::Quiets => {
+                    let tt_move = self.tt_move;
+                    let killers = self.killers;
+                    let quiets = self.quiets.get_or_insert_with(|| {
+                        let quiets = self.moves.iter().filter(|m| {
+                            !Self::is_tactical(m) && Some(*m) != tt_move && !killers.contains(&Some(*m))
+                        });
+                        LazySort::new(quiets, |m| -quiet_history(t, pos, ply, m))
+                    });
+                    match quiets.next() {
+                        Some((_i, _key, m)) => return Some(m),
+                        None => self.stage = Stage::BadCaptures,
+                    }
+                }
+                Stage::BadCaptures => {
+                    // the least bad first
+                    let best = self.bad_captures.iter().enumerate().max_by_key(|(_, (_, see))| *see);
+                    match best {
+                        Some((i, _)) => return Some(self.bad_captures.swap_remove(i).0),
+                        None => self.stage = Stage::Done,
+                    }
+                }
+                Stage::Done => return None,
+            }
+        }
+    }
+}
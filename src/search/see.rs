@@ -0,0 +1,130 @@
+use arrayvec::ArrayVec;
+use shakmaty::{Bitboard, Move, Position, Rank, Role, Square, attacks};
+
+// Static Exchange Evaluation
+// Computes the material outcome of the capture sequence on the target square of a move, where both
+// sides always recapture with their least valuable attacker and may stop capturing at any point.
+// Pins are not taken into account: pinned pieces are assumed to be able to capture.
+
+const SEE_VALUES: [i16; 6] = [100, 320, 330, 500, 900, 20000];
+
+pub fn see_value(role: Role) -> i16 {
+    let idx: usize = role.into();
+    SEE_VALUES[idx - 1]
+}
+
+pub fn see<P: Position>(position: &P, m: &Move) -> i16 {
+    let (from, to) = match m {
+        Move::Normal { from, to, .. } | Move::EnPassant { from, to } => (*from, *to),
+        Move::Castle { .. } | Move::Put { .. } => return 0,
+    };
+    let board = position.board();
+
+    let mut occupied = board.occupied() ^ from;
+    let mut gain = match m.capture() {
+        Some(role) => see_value(role),
+        None => 0,
+    };
+    if m.is_en_passant() {
+        occupied ^= Square::from_coords(to.file(), from.rank());
+    }
+
+    // Value of the piece currently standing on the target square
+    let mut on_target = see_value(m.role());
+    if let Some(promotion) = m.promotion() {
+        gain += see_value(promotion) - see_value(Role::Pawn);
+        on_target = see_value(promotion);
+    }
+
+    let mut gains: ArrayVec<i16, 32> = ArrayVec::new();
+    gains.push(gain);
+
+    let rooks = board.rooks_and_queens();
+    let bishops = board.bishops_and_queens();
+    let mut attackers = (board.attacks_to(to, position.turn(), occupied)
+        | board.attacks_to(to, position.turn().other(), occupied))
+        & occupied;
+    let mut side = position.turn().other();
+
+    while !gains.is_full() {
+        let own_attackers = attackers & board.by_color(side);
+        let Some((role, sq)) = least_valuable(board, own_attackers) else {
+            break;
+        };
+
+        // A king can only capture if the square is not defended anymore
+        if role == Role::King && (attackers & board.by_color(side.other())).any() {
+            break;
+        }
+
+        let mut next = on_target - gains.last().unwrap();
+        on_target = see_value(role);
+        if role == Role::Pawn && (to.rank() == Rank::First || to.rank() == Rank::Eighth) {
+            next += see_value(Role::Queen) - see_value(Role::Pawn);
+            on_target = see_value(Role::Queen);
+        }
+        gains.push(next);
+
+        // Remove the attacker, revealing any sliders behind it (x-rays)
+        occupied ^= sq;
+        attackers |= (attacks::rook_attacks(to, occupied) & rooks)
+            | (attacks::bishop_attacks(to, occupied) & bishops);
+        attackers &= occupied;
+        side = side.other();
+    }
+
+    // Each side may choose to not capture, so propagate the best choice back to the first capture
+    while gains.len() > 1 {
+        let last = gains.pop().unwrap();
+        let prev = gains.last_mut().unwrap();
+        *prev = -(-*prev).max(last);
+    }
+    gains[0]
+}
+
+fn least_valuable(board: &shakmaty::Board, attackers: Bitboard) -> Option<(Role, Square)> {
+    Role::ALL
+        .into_iter()
+        .find_map(|role| (attackers & board.by_role(role)).first().map(|sq| (role, sq)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shakmaty::{CastlingMode, Chess, fen::Fen, uci::UciMove};
+
+    #[test]
+    fn exchanges() {
+        for (fen, uci, expected) in [
+            // Undefended and defended pawns
+            ("4k3/8/8/3p4/4P3/8/8/4K3 w - - 0 1", "e4d5", 100),
+            ("4k3/2p5/3p4/8/8/8/3Q4/4K3 w - - 0 1", "d2d6", -800),
+            // A quiet move to an attacked square
+            ("4k3/8/8/8/8/2p5/8/3R1K2 w - - 0 1", "d1d2", -500),
+            // The rook behind the capturing rook recaptures through it (x-ray)
+            ("3rk3/8/3r4/8/8/8/3R4/3RK3 w - - 0 1", "d2d6", 500),
+            ("3rk3/8/3r4/8/8/8/3R4/4K3 w - - 0 1", "d2d6", 0),
+            // The king only recaptures on an undefended square
+            ("8/8/3k4/3p4/8/8/3R4/3RK3 w - - 0 1", "d2d5", 100),
+            ("8/8/3k4/3p4/8/8/3R4/4K3 w - - 0 1", "d2d5", -400),
+            // En passant, undefended and defended
+            ("4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 1", "e5d6", 100),
+            ("4k3/2p5/8/3pP3/8/8/8/4K3 w - d6 0 1", "e5d6", 0),
+            // Promotions, with and without a capture, and recaptured
+            ("4k3/1P6/8/8/8/8/8/4K3 w - - 0 1", "b7b8q", 800),
+            ("r3k3/1P6/8/8/8/8/8/4K3 w - - 0 1", "b7b8q", -100),
+            ("r3k3/1P6/8/8/8/8/8/4K3 w - - 0 1", "b7a8q", 1300),
+            ("r3k3/1P6/8/8/8/8/8/4K3 w - - 0 1", "b7a8n", 720),
+            // A recapture promotes as well
+            ("4k3/8/8/8/8/8/p7/1nR1K3 w - - 0 1", "c1b1", -980),
+            // Pins are ignored: the knight pinned to its king still recaptures
+            ("4k3/4n3/8/3p4/8/8/8/K2QR3 w - - 0 1", "d1d5", -800),
+            // Castling never loses material
+            ("4k3/8/8/8/8/8/8/4K2R w K - 0 1", "e1g1", 0),
+        ] {
+            let pos: Chess = fen.parse::<Fen>().unwrap().into_position(CastlingMode::Standard).unwrap();
+            let m = uci.parse::<UciMove>().unwrap().to_move(&pos).unwrap();
+            assert_eq!(see(&pos, &m), expected, "{fen} {uci}");
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use std::ops::AddAssign;
+
+use super::NodeCount;
+
+// Search statistics
+// Counted per thread in plain integers (the hot path only does an increment), and summed over all
+// threads when the search is done. Only reported in debug mode and by bench, to tune pruning and
+// move ordering.
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct SearchStats {
+    pub tt_probes: u64,
+    pub tt_hits: u64,
+    pub tt_cutoffs: u64,
+    pub tt_collisions: u64, // entries with the right key but an illegal move; only counted in debug mode
+    pub beta_cutoffs: u64,
+    pub first_move_cutoffs: u64, // beta cutoffs caused by the first move searched
+    pub null_move_tries: u64,
+    pub null_move_cutoffs: u64,
+    pub lmr_researches: u64,   // reduced searches that beat alpha and were repeated at full depth
+    pub pvs_researches: u64,   // zero window searches that were repeated with the full window
+    pub lazy_evals: u64,       // qsearch nodes that only used the material and PST eval
+}
+
+impl AddAssign for SearchStats {
+    fn add_assign(&mut self, other: Self) {
+        self.tt_probes += other.tt_probes;
+        self.tt_hits += other.tt_hits;
+        self.tt_cutoffs += other.tt_cutoffs;
+        self.tt_collisions += other.tt_collisions;
+        self.beta_cutoffs += other.beta_cutoffs;
+        self.first_move_cutoffs += other.first_move_cutoffs;
+        self.null_move_tries += other.null_move_tries;
+        self.null_move_cutoffs += other.null_move_cutoffs;
+        self.lmr_researches += other.lmr_researches;
+        self.pvs_researches += other.pvs_researches;
+        self.lazy_evals += other.lazy_evals;
+    }
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 { 0.0 } else { 100.0 * part as f64 / total as f64 }
+}
+
+impl SearchStats {
+    // Human readable summary, one statistic per line
+    pub fn summary(&self, nodes: &NodeCount) -> Vec<String> {
+        vec![
+            format!(
+                "tt probes {} hits {} ({:.1}%) cutoffs {} ({:.1}%) collisions {}",
+                self.tt_probes,
+                self.tt_hits,
+                percent(self.tt_hits, self.tt_probes),
+                self.tt_cutoffs,
+                percent(self.tt_cutoffs, self.tt_probes),
+                self.tt_collisions,
+            ),
+            format!(
+                "beta cutoffs {} on first move {:.1}%",
+                self.beta_cutoffs,
+                percent(self.first_move_cutoffs, self.beta_cutoffs),
+            ),
+            format!(
+                "null move tries {} cutoffs {:.1}%",
+                self.null_move_tries,
+                percent(self.null_move_cutoffs, self.null_move_tries),
+            ),
+            format!(
+                "qsearch nodes {:.1}% lazy evals {:.1}%",
+                percent(nodes.qnodes.load(std::sync::atomic::Ordering::Relaxed), nodes.count()),
+                percent(self.lazy_evals, nodes.qnodes.load(std::sync::atomic::Ordering::Relaxed)),
+            ),
+            format!("re-searches lmr {} pvs {}", self.lmr_researches, self.pvs_researches),
+        ]
+    }
+}
@@ -0,0 +1,813 @@
+use shakmaty::{CastlingMode, Chess, Move, fen::Fen};
+
+use super::*;
+use crate::{Configuration, DEFAULT_CONFIG};
+
+fn position(fen: &str) -> Chess {
+    let fen: Fen = fen.parse().unwrap();
+    fen.into_position(CastlingMode::Standard).unwrap()
+}
+
+// Searches can recurse deeper than the default stack of a test thread allows, certainly in debug builds
+fn with_stack<R: Send>(f: impl FnOnce() -> R + Send) -> R {
+    std::thread::scope(|scope| {
+        std::thread::Builder::new().stack_size(STACK_SIZE).spawn_scoped(scope, f).unwrap().join().unwrap()
+    })
+}
+
+// Searches a position to a fixed depth with a new table, returning the score and the PV
+fn search_depth<P: Searchable>(pos: &P, depth: usize, config: &Configuration) -> (ruci::Score, Vec<Move>) {
+    let tt = TT::new(1 << 16);
+    let (score, pv, _, _) = search(
+        SearchPosition::new(pos.clone()),
+        &[],
+        time::Deadline::Depth(depth),
+        None,
+        &tt,
+        config,
+        &mut SearchTables::default(),
+        &SearchControl::default(),
+        &mut |_, _, _, _, _, _| {},
+        None,
+    );
+    (score, pv)
+}
+
+// Search state for calling alphabeta and the move picker directly
+fn search_state<'a>(config: &'a Configuration, tt: &'a TT, control: &'a SearchControl) -> SearchState<'a> {
+    SearchState {
+        config,
+        tt,
+        nodes: NodeCount {
+            nodes: AtomicU64::new(0),
+            leaves: AtomicU64::new(0),
+            qnodes: AtomicU64::new(0),
+            seldepth: AtomicIsize::new(0),
+        },
+        deadline: time::Deadline::None,
+        control,
+        lmr: lmr_table(config),
+        root_moves: &[],
+        contempt: 0,
+        root_noise: Vec::new(),
+    }
+}
+
+fn uci_move<P: Position>(pos: &P, uci: &str) -> Move {
+    uci.parse::<shakmaty::uci::UciMove>().unwrap().to_move(pos).unwrap()
+}
+
+// Searches a position at ply 1 with a window that any move fails high against, after storing the
+// given move in the TT, so that it is searched first and causes the cutoff
+fn cutoff_by(pos: &Chess, mv: &Move, g: &SearchState, t: &mut ThreadState) {
+    let key = SearchPosition::new(pos.clone()).zobrist();
+    g.tt.write(key, TTEntry { mv: encode_move(mv), value: 0, eval: None, depth: 0, score_type: ScoreType::UpperBound });
+    let beta = -MATE_BOUND;
+    alphabeta(&mut SearchPosition::new(pos.clone()), Depth::ONE, 1, beta - 1, beta, true, g, t);
+}
+
+// TT cutoffs and storing entries
+mod tt_cutoffs {
+    use super::*;
+
+    fn entry(value: i16, score_type: ScoreType) -> TTEntry {
+        TTEntry { mv: 0, value, eval: None, depth: 0, score_type }
+    }
+
+    #[test]
+    fn bounds() {
+        let (alpha, beta) = (-50, 50);
+        for value in [-100, -50, 0, 50, 100] {
+            assert!(tt_cutoff(&entry(value, ScoreType::Exact), alpha, beta));
+            assert_eq!(tt_cutoff(&entry(value, ScoreType::LowerBound), alpha, beta), value >= beta);
+            assert_eq!(tt_cutoff(&entry(value, ScoreType::UpperBound), alpha, beta), value <= alpha);
+        }
+    }
+
+    #[test]
+    fn mate_scores_are_stored_relative_to_the_node() {
+        for score in [MATE - 3, -MATE + 4, 123, -MATE_BOUND] {
+            for ply in [0, 1, 7, 30] {
+                assert_eq!(score_from_tt(score_to_tt(score, ply), ply), score);
+            }
+        }
+        // Mate in 2 from a node at ply 5 is mate in 2 wherever the node is reached
+        assert_eq!(score_from_tt(score_to_tt(MATE - 8, 5), 1), MATE - 4);
+    }
+
+    // The TT only saves work: the selective parts of the search and the correction history depend on
+    // the order of the moves and nodes (which the TT changes), so without them, a fixed depth search
+    // gives the same scores with and without it
+    #[test]
+    fn same_scores_without_tt() {
+        let exact = Configuration {
+            null_move: false,
+            lmr: false,
+            futility: false,
+            lmp: false,
+            see_pruning: false,
+            history_pruning: false,
+            singular: false,
+            iir: false,
+            qsearch_checks: false,
+            lazy_eval: false,
+            max_extensions: 0,
+            aspiration_depth: isize::MAX,
+            correction: false,
+            ..DEFAULT_CONFIG
+        };
+        with_stack(|| {
+            for (fen, _) in crate::bench::POSITIONS {
+                let pos = position(fen);
+                let (with_tt, _) = search_depth(&pos, 3, &exact);
+                let (without_tt, _) = search_depth(&pos, 3, &Configuration { use_tt: false, ..exact });
+                assert_eq!(with_tt, without_tt, "{fen}");
+            }
+        });
+    }
+}
+
+// The TT move is searched first
+mod tt_move_first {
+    use super::*;
+
+    // With a window that any move fails high against, the first move searched is the one stored as
+    // the best move. Seeding the TT with each legal move in turn (too shallow to cut) must therefore
+    // give back that same move, including quiets that would otherwise come after the captures.
+    #[test]
+    fn searched_first() {
+        with_stack(|| {
+            let config = DEFAULT_CONFIG;
+            let control = SearchControl::default();
+            let pos = position("r1bq1rk1/4ppbp/p1pp1np1/1P2n3/2B1PB2/2NP1N1P/1PP2PP1/R2QR1K1 b - - 0 11");
+            let key = SearchPosition::new(pos.clone()).zobrist();
+            for mv in pos.legal_moves() {
+                let tt = TT::new(64);
+                let g = search_state(&config, &tt, &control);
+                let mut t = ThreadState::new();
+                cutoff_by(&pos, &mv, &g, &mut t);
+                assert_eq!(t.stats.first_move_cutoffs, 1);
+                let stored = tt.probe(key).and_then(|tte| tte.best_move(std::slice::from_ref(&mv)).cloned());
+                assert_eq!(stored, Some(mv));
+            }
+        });
+    }
+}
+
+// Killer moves
+mod killers {
+    use super::*;
+
+    #[test]
+    fn rotation() {
+        let pos = Chess::default();
+        let [a, b, c] = ["e2e4", "g1f3", "b2b3"].map(|m| uci_move(&pos, m));
+        let mut killers = [None, None];
+        store_killer(&mut killers, &a);
+        assert_eq!(killers, [Some(a.clone()), None]);
+        store_killer(&mut killers, &b);
+        assert_eq!(killers, [Some(b.clone()), Some(a.clone())]);
+        // Storing the first killer again does not fill both slots with it
+        store_killer(&mut killers, &b);
+        assert_eq!(killers, [Some(b.clone()), Some(a.clone())]);
+        store_killer(&mut killers, &c);
+        assert_eq!(killers, [Some(c.clone()), Some(b.clone())]);
+        // The second killer is promoted to the first slot
+        store_killer(&mut killers, &b);
+        assert_eq!(killers, [Some(b), Some(c)]);
+    }
+
+    #[test]
+    fn only_quiet_moves() {
+        with_stack(|| {
+            let config = DEFAULT_CONFIG;
+            let control = SearchControl::default();
+            let pos = position("r1bq1rk1/4ppbp/p1pp1np1/1P2n3/2B1PB2/2NP1N1P/1PP2PP1/R2QR1K1 b - - 0 11");
+            for (uci, quiet) in [("e5c4", false), ("a6b5", false), ("c8d7", true), ("d8a5", true)] {
+                let mv = uci_move(&pos, uci);
+                let tt = TT::new(64);
+                let g = search_state(&config, &tt, &control);
+                let mut t = ThreadState::new();
+                cutoff_by(&pos, &mv, &g, &mut t);
+                let expected = if quiet { [Some(mv), None] } else { [None, None] };
+                assert_eq!(t.stack[1].killers, expected, "{uci}");
+            }
+        });
+    }
+}
+
+// History gravity and aging
+mod history {
+    use super::*;
+    use crate::util::random::splitmix64;
+
+    fn in_bounds(value: i16) -> bool {
+        (-MAX_HISTORY..=MAX_HISTORY).contains(&(value as i32))
+    }
+
+    #[test]
+    fn saturates() {
+        for bonus in [history_bonus(1), history_bonus(20), MAX_HISTORY, i32::MAX] {
+            let (mut up, mut down) = (0, 0);
+            for _ in 0..10_000 {
+                update_history(&mut up, bonus);
+                update_history(&mut down, -bonus);
+                assert!(in_bounds(up) && in_bounds(down), "{bonus}: {up} {down}");
+            }
+            // Gravity makes repeated bonuses converge to the bound instead of wrapping around
+            assert!(up > MAX_HISTORY as i16 / 2, "{bonus}: {up}");
+            assert!(down < -MAX_HISTORY as i16 / 2, "{bonus}: {down}");
+        }
+    }
+
+    #[test]
+    fn random_updates_stay_in_bounds() {
+        let mut seed = 0;
+        let mut entry = 0;
+        for _ in 0..100_000 {
+            seed = splitmix64(seed);
+            let bonus = history_bonus((seed % 64) as isize);
+            update_history(&mut entry, if seed & 1 << 32 != 0 { bonus } else { -bonus });
+            assert!(in_bounds(entry), "{entry}");
+        }
+    }
+
+    #[test]
+    fn bonus_grows_with_depth_up_to_the_bound() {
+        for depth in 1..200 {
+            assert!(history_bonus(depth) <= history_bonus(depth + 1));
+            assert!(history_bonus(depth) <= MAX_HISTORY);
+        }
+        assert_eq!(history_bonus(1000), MAX_HISTORY);
+    }
+
+    #[test]
+    fn aged_between_searches() {
+        with_stack(|| {
+            let mut t = ThreadState::new();
+            t.butterfly[0][12][28] = 1000;
+            t.butterfly[1][52][36] = -MAX_HISTORY as i16;
+            t.capture_history[0][0][27][1] = 600;
+            t.cont_history[0][1][2][3][4] = -300;
+            t.new_search();
+            assert_eq!(t.butterfly[0][12][28], 500);
+            assert_eq!(t.butterfly[1][52][36], -MAX_HISTORY as i16 / 2);
+            assert_eq!(t.capture_history[0][0][27][1], 300);
+            assert_eq!(t.cont_history[0][1][2][3][4], -150);
+        });
+    }
+}
+
+// Staged move picker
+mod move_picker {
+    use super::*;
+    use shakmaty::{Role, Square};
+
+    const POSITIONS: [&str; 5] = [
+        "r1bq1rk1/4ppbp/p1pp1np1/1P2n3/2B1PB2/2NP1N1P/1PP2PP1/R2QR1K1 b - - 0 11",
+        "2r3r1/3R2pk/p1p1PB2/1pR2P2/2p1PK2/P1P5/8/5b2 w - - 9 19",
+        // Castling both ways, promotions with and without capture, and en passant
+        "r3k2r/1P4pp/8/3pP3/8/8/6PP/R3K2R w KQkq d6 0 1",
+        "r3k2r/8/8/8/8/8/1p4PP/R3K2R b KQkq - 0 1",
+        // In check
+        "4k3/8/8/8/8/8/3q4/R3K2R w KQ - 0 1",
+    ];
+
+    // Every move of the list must be yielded exactly once, whatever the TT move and killers
+    fn check(pos: &Chess, moves: &[Move], tt_move: Option<&Move>, killers: [Option<Move>; 2], t: &mut ThreadState) {
+        for ply in [None, Some(3)] {
+            t.stack[3].killers = killers.clone();
+            let mut picker = MovePicker::new(moves, tt_move);
+            let mut yielded = Vec::new();
+            while let Some(mv) = picker.next(pos, t, ply) {
+                yielded.push(mv.clone());
+            }
+            assert_eq!(yielded.len(), moves.len(), "{tt_move:?} {killers:?} {ply:?}");
+            for mv in moves {
+                assert_eq!(yielded.iter().filter(|m| *m == mv).count(), 1, "{mv:?} {tt_move:?} {killers:?} {ply:?}");
+            }
+            if let Some(tt_move) = tt_move {
+                assert_eq!(yielded.first(), Some(tt_move));
+            }
+        }
+    }
+
+    #[test]
+    fn yields_each_move_once() {
+        with_stack(|| {
+            let mut t = ThreadState::new();
+            // A move that is not legal in any of the positions, as a killer from another position
+            let foreign = Move::Normal { role: Role::Knight, from: Square::B1, capture: None, to: Square::C3, promotion: None };
+            for fen in POSITIONS {
+                let pos = position(fen);
+                let legal = pos.legal_moves();
+                let captures = pos.capture_moves();
+                let quiets: Vec<Move> = legal.iter().filter(|m| !m.is_capture() && !m.is_promotion()).cloned().collect();
+                for moves in [&legal[..], &captures[..]] {
+                    for tt_move in std::iter::once(None).chain(moves.iter().map(Some)) {
+                        let killer_sets = [
+                            [None, None],
+                            [quiets.first().cloned(), quiets.last().cloned()],
+                            [tt_move.cloned(), quiets.get(1).cloned()],
+                            [captures.first().cloned(), Some(foreign.clone())],
+                        ];
+                        // store_killer never puts the same move in both slots
+                        for killers in killer_sets.into_iter().filter(|[a, b]| a.is_none() || a != b) {
+                            check(&pos, moves, tt_move, killers, &mut t);
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
+
+// TT collision counting in debug mode
+mod tt_collisions {
+    use super::*;
+
+    fn entry(mv: &Move) -> TTEntry {
+        TTEntry { mv: encode_move(mv), value: 0, eval: None, depth: 1, score_type: ScoreType::Exact }
+    }
+
+    // All keys of a table of one cluster share its index; the full key still tells them apart
+    #[test]
+    fn same_index_is_no_collision() {
+        let pos = Chess::default();
+        let (a, b) = (uci_move(&pos, "e2e4"), uci_move(&pos, "d2d4"));
+        let tt = TT::new(4);
+        tt.write(1, entry(&a));
+        tt.write(2, entry(&b));
+        assert_eq!(tt.probe(1), Some(entry(&a)));
+        assert_eq!(tt.probe(2), Some(entry(&b)));
+        assert_eq!(tt.probe(3), None);
+    }
+
+    // Two positions with the same key, forced by storing the entry of one under the key of the other:
+    // the foreign move is not legal, so the entry is not used, and in debug mode it is counted
+    #[test]
+    fn foreign_move_is_counted() {
+        with_stack(|| {
+            let control = SearchControl::default();
+            let pos = Chess::default();
+            let other = position("r1bq1rk1/4ppbp/p1pp1np1/1P2n3/2B1PB2/2NP1N1P/1PP2PP1/R2QR1K1 b - - 0 11");
+            let key = SearchPosition::new(pos.clone()).zobrist();
+            let moves = pos.legal_moves();
+            for debug in [false, true] {
+                let config = Configuration { debug, ..DEFAULT_CONFIG };
+                let tt = TT::new(4);
+                let g = search_state(&config, &tt, &control);
+                let mut t = ThreadState::new();
+                tt.write(key, entry(&uci_move(&other, "e5c4")));
+                assert_eq!(g.tt_get(&mut t, &pos, &moves, key), None);
+                assert_eq!(t.stats.tt_collisions, debug as u64);
+                // A legal move is not a collision, also when it is not in the moves searched (qsearch)
+                tt.write(key, entry(&uci_move(&pos, "e2e4")));
+                assert_eq!(g.tt_get(&mut t, &pos, &pos.capture_moves(), key), None);
+                assert!(g.tt_get(&mut t, &pos, &moves, key).is_some());
+                assert_eq!(t.stats.tt_collisions, debug as u64);
+            }
+        });
+    }
+}
+
+// Null move pruning in zugzwang
+mod null_move {
+    use super::*;
+
+    #[test]
+    fn zugzwang_positions() {
+        with_stack(|| {
+            for (fen, depth, best) in [
+                // Rf1 only holds because Black has to move; passing would look much better for White
+                ("8/8/p1p5/1p5p/1P5p/8/PPP2K1p/4R1rk w - - 0 1", 12, Some("e1f1")),
+                // Fine #70: pawns only, so no null moves at all; Kb1 wins, but far beyond this depth
+                ("8/k7/3p4/p2P1p2/P2P1P2/8/8/K7 w - - 0 1", 12, None),
+            ] {
+                let pos = position(fen);
+                let (_, pv) = search_depth(&pos, depth, &DEFAULT_CONFIG);
+                if let Some(best) = best {
+                    assert_eq!(pv[0], uci_move(&pos, best), "{fen}");
+                }
+                // Scores of these positions swing from one iteration to the next, so only the moves
+                // are compared
+                let without = Configuration { null_move: false, ..DEFAULT_CONFIG };
+                let (_, pv_without) = search_depth(&pos, depth, &without);
+                assert_eq!(pv[0], pv_without[0], "{fen}");
+            }
+        });
+    }
+}
+
+// Mate scores
+mod mate_scores {
+    use super::*;
+
+    #[test]
+    fn helpers() {
+        assert_eq!(mate_in(MATE - 1), 1);
+        assert_eq!(mate_in(MATE - 3), 2);
+        assert_eq!(mate_in(-MATE + 2), -1);
+        assert_eq!(mate_in(-MATE + 4), -2);
+        assert!(is_mate_score(MATE - 100) && is_mate_score(-MATE + 100));
+        assert!(!is_mate_score(MATE_BOUND) && !is_mate_score(0));
+    }
+
+    #[test]
+    fn shortest_mate() {
+        with_stack(|| {
+            // Mates in 3 and longer are everywhere, but the mate in 2 is preferred at any depth
+            let pos = position("k7/8/2K5/8/8/8/8/7R w - - 0 1");
+            for depth in [3, 5, 8, 12] {
+                assert_eq!(search_depth(&pos, depth, &DEFAULT_CONFIG).0, ruci::Score::MateIn(2), "depth {depth}");
+            }
+        });
+    }
+
+    // go mate n, with the depth limit the engine uses for it
+    fn search_mate(pos: &Chess, n: usize) -> (ruci::Score, Vec<Move>) {
+        let tt = TT::new(1 << 16);
+        let (score, pv, _, _) = search(
+            SearchPosition::new(pos.clone()),
+            &[],
+            time::Deadline::Depth(4 * n),
+            Some(n),
+            &tt,
+            &DEFAULT_CONFIG,
+            &mut SearchTables::default(),
+            &SearchControl::default(),
+            &mut |_, _, _, _, _, _| {},
+            None,
+        );
+        (score, pv)
+    }
+
+    #[test]
+    fn go_mate() {
+        with_stack(|| {
+            for (fen, n) in [
+                ("6k1/5ppp/8/8/8/8/5PPP/3R2K1 w - - 0 1", 1),
+                ("k7/8/2K5/8/8/8/8/7R w - - 0 1", 2),
+                // King hunt, starting with a queen sacrifice
+                ("r1b1kb1r/pppp1ppp/5q2/4n3/3KP3/2N3PN/PPP4P/R1BQ1B1R b kq - 0 1", 3),
+            ] {
+                let mut pos = position(fen);
+                let (score, pv) = search_mate(&pos, n);
+                assert_eq!(score, ruci::Score::MateIn(n as isize), "{fen}");
+                // The PV is the mating line
+                assert_eq!(pv.len(), 2 * n - 1, "{fen} {pv:?}");
+                for mv in &pv {
+                    pos.play_unchecked(mv);
+                }
+                assert!(pos.is_checkmate(), "{fen} {pv:?}");
+            }
+            // Without a mate in n, the search goes on to its depth limit and reports what it found
+            assert_eq!(search_mate(&position("k7/8/2K5/8/8/8/8/7R w - - 0 1"), 1).0, ruci::Score::MateIn(2));
+            let pos = position("r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3");
+            let (score, pv) = search_mate(&pos, 2);
+            assert!(matches!(score, ruci::Score::Centipawns(_)), "{score:?}");
+            assert!(pos.legal_moves().contains(&pv[0]));
+        });
+    }
+
+    #[test]
+    fn deep_pawn_ending() {
+        with_stack(|| {
+            // Fine #70 at depth 30: a normal score for White, far from the mate scores
+            let (score, pv) = search_depth(&position("8/k7/3p4/p2P1p2/P2P1P2/8/8/K7 w - - 0 1"), 30, &DEFAULT_CONFIG);
+            let ruci::Score::Centipawns(cp) = score else { panic!("{score:?}") };
+            assert!((0..MATE_BOUND as isize).contains(&cp), "{cp}");
+            assert!(!pv.is_empty());
+        });
+    }
+}
+
+// Draws
+mod draws {
+    use super::*;
+
+    #[test]
+    fn root_move_in_drawn_position() {
+        with_stack(|| {
+            // The fifty-move rule applies, but the game goes on until it is claimed
+            let (score, pv) = search_depth(&position("8/8/3k4/8/8/8/8/Q3K3 w - - 100 90"), 3, &DEFAULT_CONFIG);
+            assert_eq!(score, ruci::Score::Centipawns(0));
+            assert!(!pv.is_empty());
+        });
+    }
+
+    // Searches White's move after the knights went back and forth twice, where Nf3-g1 repeats the
+    // position for the third time
+    fn repetition_available(fen: &str, config: &Configuration) -> (ruci::Score, Move) {
+        let mut pos = SearchPosition::new(position(fen));
+        for uci in ["g1f3", "g8f6", "f3g1", "f6g8", "g1f3", "g8f6", "f3g1", "f6g8", "g1f3", "g8f6"] {
+            let mv = uci_move(pos.pos(), uci);
+            pos.play_unchecked(&mv);
+        }
+        let tt = TT::new(1 << 16);
+        let (score, pv, _, _) = search(
+            pos,
+            &[],
+            time::Deadline::Depth(6),
+            None,
+            &tt,
+            config,
+            &mut SearchTables::default(),
+            &SearchControl::default(),
+            &mut |_, _, _, _, _, _| {},
+            None,
+        );
+        (score, pv[0].clone())
+    }
+
+    #[test]
+    fn contempt() {
+        with_stack(|| {
+            let repeat = uci_move(&position("rnbqkbnr/pppppppp/8/8/8/5N2/PPPPPPPP/RNBQKB1R w KQkq - 0 1"), "f3g1");
+            let contempt = |contempt| Configuration { contempt, ..DEFAULT_CONFIG };
+            // Up a pawn, the repetition is avoided
+            let up = "rnbqkbnr/1ppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+            let (score, mv) = repetition_available(up, &contempt(50));
+            assert!(mv != repeat && matches!(score, ruci::Score::Centipawns(cp) if cp > 0), "{score:?} {mv:?}");
+            // Down a pawn, the repetition is welcome, unless the contempt is larger than the deficit
+            let down = "rnbqkbnr/pppppppp/8/8/8/8/1PPPPPPP/RNBQKBNR w KQkq - 0 1";
+            assert_eq!(repetition_available(down, &contempt(0)), (ruci::Score::Centipawns(0), repeat.clone()));
+            assert_eq!(repetition_available(down, &contempt(50)), (ruci::Score::Centipawns(-50), repeat.clone()));
+            let (score, mv) = repetition_available(down, &contempt(100));
+            assert!(mv != repeat && matches!(score, ruci::Score::Centipawns(cp) if cp > -100), "{score:?} {mv:?}");
+            // Analysis shows the objective score
+            let analysis = Configuration { analyse_mode: true, ..contempt(100) };
+            assert_eq!(repetition_available(down, &analysis), (ruci::Score::Centipawns(0), repeat));
+        });
+    }
+
+    #[test]
+    fn insufficient_material() {
+        with_stack(|| {
+            // Bishops of the same color, a lone knight, bare kings
+            for fen in ["8/8/3k4/2b5/8/4B3/3K4/8 w - - 0 1", "8/8/3k4/8/8/4N3/3K4/8 w - - 0 1", "8/8/3k4/8/8/8/3K4/8 w - - 0 1"] {
+                assert_eq!(search_depth(&position(fen), 6, &DEFAULT_CONFIG).0, ruci::Score::Centipawns(0), "{fen}");
+            }
+            // Bishop against knight can still be mated
+            assert_eq!(search_depth(&position("6nk/8/6KB/8/8/8/8/8 w - - 0 1"), 3, &DEFAULT_CONFIG).0, ruci::Score::MateIn(1));
+        });
+    }
+}
+
+// Variants
+mod variants {
+    use super::*;
+    use shakmaty::{Color, Outcome, variant::{Variant, VariantPosition}};
+
+    fn variant_position(variant: Variant, fen: &str) -> VariantPosition {
+        let fen: Fen = fen.parse().unwrap();
+        VariantPosition::from_setup(variant, fen.into_setup(), CastlingMode::Standard).unwrap()
+    }
+
+    fn fen(pos: &VariantPosition) -> Fen {
+        Fen::from_position(pos.clone(), shakmaty::EnPassantMode::Legal)
+    }
+
+    fn after(pos: &VariantPosition, mv: &Move) -> VariantPosition {
+        let mut pos = pos.clone();
+        pos.play_unchecked(mv);
+        pos
+    }
+
+    // Plays a game of the engine against itself from the given opening move, checking every move
+    // it plays, until the game ends or the ply limit is reached; returns the final position
+    fn self_play(start: VariantPosition, opening: &str, plies: usize, check: impl Fn(&VariantPosition, &Move)) -> VariantPosition {
+        let mut pos = after(&start, &uci_move(&start, opening));
+        for _ in 0..plies {
+            if pos.is_variant_end() || pos.legal_moves().is_empty() || pos.halfmoves() >= 100 {
+                break;
+            }
+            let (_, pv) = search_depth(&pos, 2, &DEFAULT_CONFIG);
+            let mv = pv.first().unwrap_or_else(|| panic!("no move in {}", fen(&pos)));
+            assert!(pos.legal_moves().contains(mv), "{mv:?} in {}", fen(&pos));
+            check(&pos, mv);
+            pos.play_unchecked(mv);
+        }
+        pos
+    }
+
+    #[test]
+    fn three_check_ends_on_the_third_check() {
+        with_stack(|| {
+            // White has given two checks, and can give the third one
+            let pos = variant_position(Variant::ThreeCheck, "rnbqkbnr/ppp2ppp/8/3pp3/4P3/5Q2/PPPP1PPP/RNB1KBNR w KQkq - 1+3 0 3");
+            let (score, pv) = search_depth(&pos, 3, &DEFAULT_CONFIG);
+            assert_eq!(score, ruci::Score::MateIn(1));
+            assert_eq!(after(&pos, &pv[0]).variant_outcome(), Some(Outcome::Decisive { winner: Color::White }));
+
+            let wins = |pos: &VariantPosition, mv: &Move| after(pos, mv).variant_outcome() == Some(Outcome::Decisive { winner: pos.turn() });
+            // start with a single check to go, so that the games are decided by it quickly
+            for opening in ["e2e4", "d2d4"] {
+                let start = variant_position(Variant::ThreeCheck, "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 1+1 0 1");
+                let end = self_play(start, opening, 120, |pos, mv| {
+                    // a third check wins on the spot, so the engine must take it when it can
+                    if pos.legal_moves().iter().any(|m| wins(pos, m)) {
+                        assert!(wins(pos, mv), "{mv:?} in {}", fen(pos));
+                    }
+                });
+                let Some(Outcome::Decisive { winner }) = end.variant_outcome() else {
+                    panic!("not decided by checks: {}", fen(&end));
+                };
+                assert!(end.remaining_checks().unwrap().get(winner).is_zero(), "{}", fen(&end));
+            }
+        });
+    }
+
+    #[test]
+    fn antichess_captures_are_forced() {
+        with_stack(|| {
+            for opening in ["e2e3", "b2b4"] {
+                self_play(VariantPosition::new(Variant::Antichess), opening, 120, |pos, mv| {
+                    if pos.legal_moves().iter().any(|m| m.is_capture()) {
+                        assert!(mv.is_capture(), "{mv:?} in {}", fen(pos));
+                    }
+                });
+            }
+        });
+    }
+}
+
+// go nodes
+mod node_limits {
+    use super::*;
+
+    fn search_nodes(pos: &Chess, nodes: usize) -> (ruci::Score, Vec<Move>, u64) {
+        let tt = TT::new(1 << 16);
+        let (score, pv, count, _) = search(
+            SearchPosition::new(pos.clone()),
+            &[],
+            time::Deadline::Nodes(nodes),
+            None,
+            &tt,
+            &DEFAULT_CONFIG,
+            &mut SearchTables::default(),
+            &SearchControl::default(),
+            &mut |_, _, _, _, _, _| {},
+            None,
+        );
+        (score, pv, count.count())
+    }
+
+    #[test]
+    fn stops_at_the_limit_and_is_reproducible() {
+        with_stack(|| {
+            for fen in ["rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", "r1bq1rk1/4ppbp/p1pp1np1/1P2n3/2B1PB2/2NP1N1P/1PP2PP1/R2QR1K1 b - - 0 11"] {
+                let pos = position(fen);
+                let first = search_nodes(&pos, 20_000);
+                assert!((20_000..20_000 + 100).contains(&first.2), "{fen}: {}", first.2);
+                assert_eq!(search_nodes(&pos, 20_000), first, "{fen}");
+            }
+        });
+    }
+}
+
+// Time management, on a simulated clock
+mod time_management {
+    use super::*;
+    use std::time::Duration;
+
+    // Iterations end every 10 ms, with a soft deadline of 100 ms, and the given best move per
+    // iteration; returns when the search stops
+    fn stop_time(best_move: impl Fn(usize) -> &'static str) -> Duration {
+        let pos = Chess::default();
+        let start = Instant::now();
+        let deadline = time::Deadline::Time(start, start + Duration::from_millis(100), start + Duration::from_secs(1));
+        let mut stability = Stability::default();
+        for iteration in 1..100 {
+            let elapsed = Duration::from_millis(10 * iteration as u64);
+            stability.update(Some(&uci_move(&pos, best_move(iteration))));
+            if deadline.check_soft(start + elapsed, 0, iteration, stability.scale()) {
+                return elapsed;
+            }
+        }
+        panic!("the search did not stop");
+    }
+
+    #[test]
+    fn unstable_best_move_takes_longer() {
+        let stable = stop_time(|_| "e2e4");
+        let unstable = stop_time(|iteration| if iteration % 2 == 0 { "e2e4" } else { "d2d4" });
+        assert!(stable < Duration::from_millis(100), "{stable:?}");
+        assert!(unstable > Duration::from_millis(100), "{unstable:?}");
+        // A best move that changed once, late, gets more time too
+        let changed = stop_time(|iteration| if iteration < 7 { "e2e4" } else { "d2d4" });
+        assert!(stable < changed && changed < unstable, "{stable:?} {changed:?} {unstable:?}");
+    }
+
+    #[test]
+    fn next_iteration_estimate() {
+        let ms = Duration::from_millis;
+        // The last iteration took 4 times as long as the one before, so the next one takes 400 ms
+        assert!(next_iteration_fits(ms(100), Some(ms(25)), ms(400)));
+        assert!(!next_iteration_fits(ms(100), Some(ms(25)), ms(399)));
+        // Without an earlier iteration, or with a larger growth, at most 4 times as long
+        assert!(!next_iteration_fits(ms(100), None, ms(399)));
+        assert!(next_iteration_fits(ms(100), Some(ms(1)), ms(400)));
+        // An iteration is never expected to be faster than the last one
+        assert!(!next_iteration_fits(ms(100), Some(ms(200)), ms(99)));
+    }
+
+    // With a budget of one second and iterations taking twice as long as the one before, the search
+    // starts no iteration it cannot finish, but also does not stop earlier than it has to
+    #[test]
+    fn one_second_budget() {
+        let budget = Duration::from_secs(1);
+        let (mut elapsed, mut last) = (Duration::ZERO, None);
+        let mut iteration_time = Duration::from_millis(1);
+        loop {
+            elapsed += iteration_time;
+            if !next_iteration_fits(iteration_time, last, budget - elapsed) {
+                break;
+            }
+            last = Some(iteration_time);
+            iteration_time *= 2;
+        }
+        assert!(elapsed <= budget && elapsed + 2 * iteration_time > budget, "{elapsed:?}");
+        assert_eq!(elapsed, Duration::from_millis(511));
+    }
+}
+
+// Mop-up: the engine mates a bare king within the 50 move rule
+mod mop_up {
+    use super::*;
+    use crate::util::random::splitmix64;
+    use shakmaty::{Board, Color, FromSetup, Setup};
+
+    // A legal position with White to move from random squares for the white king and rook and the
+    // black king
+    fn random_krk(seed: u64) -> Chess {
+        let mut seed = seed;
+        loop {
+            let mut board = Board::empty();
+            for piece in [Color::White.king(), Color::White.rook(), Color::Black.king()] {
+                seed = splitmix64(seed);
+                board.set_piece_at(Square::new(seed as u32 % 64), piece);
+            }
+            if board.occupied().count() < 3 {
+                continue;
+            }
+            let setup = Setup { board, ..Setup::empty() };
+            if let Ok(pos) = Chess::from_setup(setup, CastlingMode::Standard)
+                && !pos.legal_moves().is_empty()
+            {
+                return pos;
+            }
+        }
+    }
+
+    #[test]
+    fn rook_mates() {
+        with_stack(|| {
+            for seed in 0..4 {
+                let start = random_krk(seed);
+                let fen = Fen::from_position(start.clone(), shakmaty::EnPassantMode::Legal);
+                let tt = TT::new(1 << 16);
+                let mut tables = SearchTables::default();
+                let mut game = SearchPosition::new(start);
+                while !game.pos().is_checkmate() {
+                    assert!(game.pos().halfmoves() < 100 && !game.pos().is_game_over(), "{fen}: not mated");
+                    let (_, pv, _, _) = search(
+                        game.clone(),
+                        &[],
+                        time::Deadline::Depth(5),
+                        None,
+                        &tt,
+                        &DEFAULT_CONFIG,
+                        &mut tables,
+                        &SearchControl::default(),
+                        &mut |_, _, _, _, _, _| {},
+                        None,
+                    );
+                    game.play_unchecked(&pv[0]);
+                }
+                assert_eq!(game.pos().turn(), Color::Black, "{fen}");
+            }
+        });
+    }
+}
+
+// Static evaluation in the search: the learned correction and the 50 move rule
+mod static_eval {
+    use super::*;
+
+    // Closer to the 50 move rule, the same advantage is worth less; from the other side, so is the
+    // disadvantage
+    #[test]
+    fn scaled_by_the_halfmove_clock() {
+        let t = ThreadState::new();
+        for (fen, side) in [("4k3/8/8/8/8/8/8/R3K3 w - - {} 60", 1), ("4k3/8/8/8/8/8/8/R3K3 b - - {} 60", -1)] {
+            let eval = |halfmoves: u32| {
+                let pos = position(&fen.replace("{}", &halfmoves.to_string()));
+                correct_eval(&pos, &t, pos.evaluate())
+            };
+            let (fresh, late) = (eval(0), eval(90));
+            assert!(side * fresh > side * late && side * late > 0, "{fen}: {fresh} {late}");
+            assert_eq!(late, (fresh as i32 * 110 / 200) as i16, "{fen}");
+        }
+    }
+}
@@ -1,94 +1,476 @@
-use std::sync::atomic::{AtomicU64, AtomicUsize};
+use std::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
 
-use shakmaty::Move;
+use shakmaty::zobrist::{Zobrist64, ZobristHash};
+use shakmaty::{EnPassantMode, Move, Position};
 
 // Transposition table
-// TT-Entry bitmap:
-// 24 bits: high part of zobrist key (useful up to 2^40 entries, after that some bits become redundant with index; maybe use buckets at some point?)
-// 8 bits: search depth
+// Each entry is two 64-bit words: the data, and the zobrist key XORed with the data. An entry is only
+// used if XORing the words gives back the key, so entries torn by concurrent writes are rejected
+// without any locking.
+// Data bitmap:
 // 16 bits: score
-// 6 bits: from square
-// 6 bits: to square
+// 16 bits: static eval (i16::MIN if in check)
+// 16 bits: best move (6 bits from square, 6 bits to square, 4 bits promotion role)
+// 8 bits: search depth
 // 2 bits: score type
-// 2 bits: free!
+// 1 bit: occupied (always set, so that no entry packs to 0, which marks an empty slot)
+// 5 bits: generation (of the search that wrote the entry, modulo 32)
+// Entries without a best move (e.g. when standing pat in qsearch) have move 0
 
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum ScoreType {
     Exact = 0,
     LowerBound = 1,
     UpperBound = 2,
 }
 
-pub fn move_match_tt(m: &Move, tte: &TTEntry) -> bool {
-    (m.from().unwrap() as u8 == tte.from) && (m.to() as u8 == tte.to)
+// Castling is stored as shakmaty represents it, king takes own rook (e.g. e1h1), which is the same in
+// Chess960. Promotions include the role, so underpromotions get their own encoding.
+pub fn encode_move(m: &Move) -> u16 {
+    m.from().unwrap() as u16 | (m.to() as u16) << 6 | (m.promotion().map_or(0, |r| r as u16)) << 12
 }
 
+fn move_match_tt(m: &Move, tte: &TTEntry) -> bool {
+    encode_move(m) == tte.mv
+}
 
-#[derive(Clone, Copy)]
-pub struct TTEntry {
-    // basic best-move info
-    pub from: u8,
-    pub to: u8,
 
-    // TODO: value info etc
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TTEntry {
+    pub mv: u16, // best move, see encode_move; 0 if none
     pub value: i16,
+    pub eval: Option<i16>, // static eval, without correction
     pub depth: u8,
     pub score_type: ScoreType,
 }
 
+impl TTEntry {
+    // The stored move is only ever used after finding it among the legal moves, so an entry that
+    // slipped through the key check (or a key collision) can never make us play an illegal move
+    pub fn best_move<'a>(&self, moves: &'a [Move]) -> Option<&'a Move> {
+        if self.mv == 0 {
+            return None;
+        }
+        moves.iter().find(|m| move_match_tt(m, self))
+    }
+
+    // Whether the stored move (if any) is one of the legal moves
+    pub fn is_legal(&self, moves: &[Move]) -> bool {
+        self.mv == 0 || self.best_move(moves).is_some()
+    }
+}
+
+const GENERATION_MASK: u8 = 0x1F;
+const OCCUPIED: u64 = 1 << 5;
+
+fn pack(data: &TTEntry, generation: u8) -> u64 {
+    (data.value.cast_unsigned() as u64) << 48
+        | (data.eval.unwrap_or(i16::MIN).cast_unsigned() as u64) << 32
+        | (data.mv as u64) << 16
+        | (data.depth as u64) << 8
+        | (data.score_type as u64) << 6
+        | OCCUPIED
+        | (generation & GENERATION_MASK) as u64
+}
+
+fn unpack(data: u64) -> TTEntry {
+    let value = (data >> 48) as u16 as i16;
+    let eval = (data >> 32) as u16 as i16;
+    let score_type = match (data >> 6) & 0x3 {
+        0 => ScoreType::Exact,
+        1 => ScoreType::LowerBound,
+        2 => ScoreType::UpperBound,
+        _ => unreachable!(),
+    };
+    TTEntry {
+        mv: (data >> 16) as u16,
+        value,
+        eval: (eval != i16::MIN).then_some(eval),
+        depth: (data >> 8) as u8,
+        score_type,
+    }
+}
+
+// Entries are grouped in clusters of one cache line. A position can be stored in any entry of its
+// cluster, so two positions with the same index do not have to keep evicting each other.
+const CLUSTER_SIZE: usize = 4;
+
+struct Slot {
+    key: AtomicU64, // zobrist key ^ data
+    data: AtomicU64,
+}
+
+#[repr(align(64))]
+struct Cluster([Slot; CLUSTER_SIZE]);
+
 pub struct TT{
-    tt: Vec<AtomicU64>,
+    tt: Vec<Cluster>,
     full: AtomicUsize,
+    generation: AtomicU8, // incremented for every search, to tell old entries from current ones
 }
 
 impl TT {
+    // Table with the largest number of entries fitting in the given number of megabytes
+    pub fn with_megabytes(megabytes: usize) -> Self {
+        Self::new((megabytes * 1024 * 1024 / std::mem::size_of::<Slot>()).max(1))
+    }
+
+    // Table with room for (about) the given number of entries
     pub fn new(size: usize) -> Self {
         let mut v = Vec::new();
-        v.resize_with(size, || AtomicU64::new(0));
-        TT{ tt: v, full: AtomicUsize::new(0) }
+        v.resize_with(size.div_ceil(CLUSTER_SIZE), || {
+            Cluster(std::array::from_fn(|_| Slot { key: AtomicU64::new(0), data: AtomicU64::new(0) }))
+        });
+        TT{ tt: v, full: AtomicUsize::new(0), generation: AtomicU8::new(0) }
+    }
+
+    fn cluster(&self, key: u64) -> &[Slot; CLUSTER_SIZE] {
+        &self.tt[(key % self.tt.len() as u64) as usize].0
+    }
+
+    // Starts loading the cluster of a position into the cache, so a later probe of it does not have
+    // to wait on memory. Only a hint: does nothing on architectures without a prefetch instruction.
+    pub fn prefetch(&self, key: u64) {
+        #[cfg(target_arch = "x86_64")]
+        {
+            use std::arch::x86_64::{_MM_HINT_T0, _mm_prefetch};
+            // SAFETY: SSE is part of the x86_64 baseline, and prefetching never faults, whatever the address
+            unsafe { _mm_prefetch::<_MM_HINT_T0>(self.cluster(key).as_ptr() as *const i8) };
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        let _ = key;
+    }
+
+    // Called at the start of every search
+    pub fn new_search(&self) {
+        self.generation.fetch_add(1, Ordering::Relaxed);
     }
 
     pub fn get(&self, moves: &[Move], key: u64) -> Option<TTEntry> {
-        let index = (key % self.tt.len() as u64) as usize;
-        let entry = self.tt[index].load(std::sync::atomic::Ordering::Relaxed);
-        if entry >> 40 == (key >> 40) {
-            let depth = ((entry >> 32) & 0xFF) as u8;
-            let value = ((entry >> 16) & 0xFFFF) as i16;
-            let from = ((entry >> 10) & 0x3F) as u8;
-            let to = ((entry >> 4) & 0x3F) as u8;
-            let score_type = match (entry >> 2) & 0x3 {
-                0 => ScoreType::Exact,
-                1 => ScoreType::LowerBound,
-                2 => ScoreType::UpperBound,
-                _ => unreachable!(),
-            };
-            let entry = TTEntry { from, to, value, depth, score_type };
-            if moves.iter().any(|m| move_match_tt(m, &entry)) { Some(entry) } else { None }
-        } else {
-            None
+        self.probe(key).filter(|entry| entry.is_legal(moves))
+    }
+
+    // Looks up a position without checking that its move is legal; a stored move that is not legal
+    // means two positions share the same 64-bit key
+    pub fn probe(&self, key: u64) -> Option<TTEntry> {
+        for slot in self.cluster(key) {
+            let data = slot.data.load(Ordering::Relaxed);
+            if data != 0 && slot.key.load(Ordering::Relaxed) ^ data == key {
+                // A torn entry (its words written by different threads) fails the key check above
+                return Some(unpack(data));
+            }
         }
+        None
     }
 
-    pub fn write(&self, key: u64, data: TTEntry) {
-        let index = (key % self.tt.len() as u64) as usize;
-        let entry = (key & 0xFFFFFF0000000000) // basically << 40 but keeping the high part
-            | ((data.depth as u64) << 32)
-            | ((data.value.cast_unsigned() as u64) << 16)
-            | ((data.from as u64) << 10)
-            | ((data.to as u64) << 4)
-            | ((data.score_type as u64) << 2);
-        let oldentry = self.tt[index].load(std::sync::atomic::Ordering::Acquire);
-        if oldentry & 0xFFFFFF0000000000 == key & 0xFFFFFF0000000000 && data.depth < ((entry >> 32) & 0xFF) as u8 {
-            return;
+    // Replaces the entry of the same position if there is one, and otherwise the least valuable entry
+    // of the cluster: the shallowest, where entries of earlier searches count as less deep, and
+    // bounds count as a little less deep than exact scores
+    pub fn write(&self, key: u64, mut data: TTEntry) {
+        let generation = self.generation.load(Ordering::Relaxed) & GENERATION_MASK;
+        let value = |old: u64| -> i32 {
+            if old == 0 {
+                return i32::MIN;
+            }
+            let age = generation.wrapping_sub(old as u8) & GENERATION_MASK;
+            let entry = unpack(old);
+            2 * entry.depth as i32 + (entry.score_type == ScoreType::Exact) as i32 - 64 * age as i32
+        };
+        let cluster = self.cluster(key);
+        let mut victim = &cluster[0];
+        let mut victim_value = i32::MAX;
+        for slot in cluster {
+            let old = slot.data.load(Ordering::Relaxed);
+            if old != 0 && slot.key.load(Ordering::Relaxed) ^ old == key {
+                let old = unpack(old);
+                // Never replace a main search entry by a qsearch entry of the same position
+                if data.depth == 0 && old.depth > 0 {
+                    return;
+                }
+                // A search without a best move (e.g. failing low) keeps the move found earlier
+                if data.mv == 0 {
+                    data.mv = old.mv;
+                }
+                victim = slot;
+                break;
+            }
+            if value(old) < victim_value {
+                victim = slot;
+                victim_value = value(old);
+            }
         }
-        self.tt[index].store(entry, std::sync::atomic::Ordering::Release);
-        if oldentry == 0 {
-            self.full.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let new = pack(&data, generation);
+        let old = victim.data.swap(new, Ordering::Relaxed);
+        victim.key.store(key ^ new, Ordering::Relaxed);
+        if old == 0 {
+            self.full.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Empties the table, split over the given number of threads, as large tables take a while
+    pub fn clear(&self, threads: usize) {
+        let chunk_size = self.tt.len().div_ceil(threads.max(1));
+        std::thread::scope(|scope| {
+            for chunk in self.tt.chunks(chunk_size) {
+                scope.spawn(move || {
+                    for slot in chunk.iter().flat_map(|cluster| cluster.0.iter()) {
+                        slot.key.store(0, Ordering::Relaxed);
+                        slot.data.store(0, Ordering::Relaxed);
+                    }
+                });
+            }
+        });
+        self.full.store(0, Ordering::Relaxed);
+        self.generation.store(0, Ordering::Relaxed);
+    }
+
+    // Follows the best moves stored in the table from a position, e.g. to lengthen a PV that was cut
+    // short. Stops when a position repeats, as the stored moves can form a cycle.
+    pub fn extract_pv<P: Position + Clone>(&self, position: &P, max_length: usize) -> Vec<Move> {
+        let mut line = Vec::new();
+        let mut seen = Vec::new();
+        let mut pos = position.clone();
+        while line.len() < max_length {
+            let zob: Zobrist64 = pos.zobrist_hash(EnPassantMode::Legal);
+            if seen.contains(&zob.0) {
+                break;
+            }
+            seen.push(zob.0);
+            let moves = pos.legal_moves();
+            let Some(mv) = self.get(&moves, zob.0).and_then(|tte| tte.best_move(&moves)) else {
+                break;
+            };
+            pos.play_unchecked(mv);
+            line.push(mv.clone());
         }
+        line
     }
+
     pub fn hashfull(&self) -> usize {
-        self.full.load(std::sync::atomic::Ordering::Relaxed) * 1000 / self.tt.len()
+        self.full.load(Ordering::Relaxed) * 1000 / (self.tt.len() * CLUSTER_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::random::splitmix64;
+
+    fn random_entry(seed: u64) -> TTEntry {
+        let (a, b) = (splitmix64(seed), splitmix64(seed ^ 1));
+        let eval = (a >> 16) as u16 as i16;
+        TTEntry {
+            mv: a as u16,
+            value: (a >> 32) as u16 as i16,
+            eval: (eval != i16::MIN && a & 1 << 63 == 0).then_some(eval),
+            depth: b as u8,
+            score_type: [ScoreType::Exact, ScoreType::LowerBound, ScoreType::UpperBound][(b >> 8) as usize % 3],
+        }
+    }
+
+    #[test]
+    fn pack_round_trip() {
+        for seed in 0..100_000 {
+            let entry = random_entry(seed);
+            let generation = (seed % 256) as u8;
+            let packed = pack(&entry, generation);
+            assert_ne!(packed, 0);
+            assert_eq!(unpack(packed), entry);
+            assert_eq!(packed as u8 & GENERATION_MASK, generation & GENERATION_MASK);
+        }
+    }
+
+    // An entry of all zeroes, written by the first search after clearing the table, is still stored
+    #[test]
+    fn zero_entry_is_stored() {
+        let tt = TT::new(CLUSTER_SIZE);
+        let entry = TTEntry { mv: 0, value: 0, eval: Some(0), depth: 0, score_type: ScoreType::Exact };
+        tt.clear(1);
+        tt.write(12345, entry);
+        assert_eq!(tt.probe(12345), Some(entry));
+        assert_eq!(tt.hashfull(), 1000 / CLUSTER_SIZE);
     }
 
-}
\ No newline at end of file
+    // Keys of the same cluster
+    fn cluster_keys(tt: &TT, count: u64) -> Vec<u64> {
+        (0..count).map(|i| 1 + i * tt.tt.len() as u64).collect()
+    }
+
+    fn entry(depth: u8, score_type: ScoreType) -> TTEntry {
+        TTEntry { mv: 1, value: 0, eval: None, depth, score_type }
+    }
+
+    // A full cluster loses its shallowest entry, where old entries count as shallower and bounds as a
+    // little shallower than exact scores
+    #[test]
+    fn replacement_order() {
+        // Depth, score type and whether an earlier search wrote it, and the entry to be replaced
+        type Slots = [(u8, ScoreType, bool); CLUSTER_SIZE];
+        let cases: [(Slots, usize); 4] = [
+            // Depth
+            ([(5, ScoreType::Exact, false), (3, ScoreType::Exact, false), (7, ScoreType::Exact, false), (9, ScoreType::Exact, false)], 1),
+            // Age: a deep entry of an earlier search goes before a shallow one of this search
+            ([(5, ScoreType::Exact, false), (20, ScoreType::Exact, true), (7, ScoreType::Exact, false), (9, ScoreType::Exact, false)], 1),
+            // Bound: at the same depth, a bound goes before an exact score
+            ([(5, ScoreType::Exact, false), (5, ScoreType::LowerBound, false), (5, ScoreType::Exact, false), (9, ScoreType::Exact, false)], 1),
+            ([(5, ScoreType::UpperBound, false), (5, ScoreType::Exact, false), (6, ScoreType::LowerBound, false), (9, ScoreType::Exact, false)], 0),
+        ];
+        for (entries, replaced) in cases {
+            let tt = TT::new(16 * CLUSTER_SIZE);
+            let keys = cluster_keys(&tt, CLUSTER_SIZE as u64 + 1);
+            // Entries of an earlier search first
+            for earlier in [true, false] {
+                for (key, (depth, score_type, _)) in keys.iter().zip(entries).filter(|(_, e)| e.2 == earlier) {
+                    tt.write(*key, entry(depth, score_type));
+                }
+                if earlier {
+                    tt.new_search();
+                }
+            }
+            tt.write(keys[CLUSTER_SIZE], entry(1, ScoreType::Exact));
+            for (i, key) in keys.iter().enumerate() {
+                assert_eq!(tt.probe(*key).is_none(), i == replaced, "{entries:?}: entry {i}");
+            }
+        }
+    }
+
+    // Writing the same position again replaces its entry, but keeps the best move if the new entry
+    // has none, and a qsearch entry never replaces a main search entry
+    #[test]
+    fn same_position() {
+        let tt = TT::new(CLUSTER_SIZE);
+        tt.write(1, TTEntry { mv: 123, value: 10, eval: Some(5), depth: 4, score_type: ScoreType::Exact });
+        tt.write(1, TTEntry { mv: 0, value: -20, eval: Some(5), depth: 6, score_type: ScoreType::UpperBound });
+        assert_eq!(tt.probe(1), Some(TTEntry { mv: 123, value: -20, eval: Some(5), depth: 6, score_type: ScoreType::UpperBound }));
+        tt.write(1, TTEntry { mv: 456, value: 30, eval: Some(5), depth: 7, score_type: ScoreType::LowerBound });
+        assert_eq!(tt.probe(1).unwrap().mv, 456);
+        tt.write(1, TTEntry { mv: 0, value: 0, eval: Some(5), depth: 0, score_type: ScoreType::Exact });
+        assert_eq!(tt.probe(1).unwrap().depth, 7);
+        assert_eq!(tt.hashfull(), 1000 / CLUSTER_SIZE);
+    }
+
+    #[test]
+    fn clear() {
+        let tt = TT::new(1 << 12);
+        for key in 1..=1 << 12 {
+            tt.write(splitmix64(key), random_entry(key));
+        }
+        assert!(tt.hashfull() > 500);
+        tt.clear(4);
+        assert_eq!(tt.hashfull(), 0);
+        assert!((1..=1 << 12).all(|key| tt.probe(splitmix64(key)).is_none()));
+    }
+
+    // Threads writing and probing the same few slots: an entry torn by concurrent writes must never
+    // be returned. Every field of an entry is derived from its key and depth, so any mix of two
+    // entries is caught.
+    #[test]
+    fn concurrent_access() {
+        fn expected(key: u64, depth: u8) -> TTEntry {
+            let hash = splitmix64(key);
+            TTEntry {
+                mv: hash as u16,
+                value: (hash >> 16) as i16 ^ depth as i16,
+                eval: Some((hash >> 32) as i16 & 0x3FFF ^ depth as i16),
+                depth,
+                score_type: [ScoreType::Exact, ScoreType::LowerBound, ScoreType::UpperBound][depth as usize % 3],
+            }
+        }
+        let tt = TT::new(CLUSTER_SIZE);
+        let hits = AtomicUsize::new(0);
+        std::thread::scope(|scope| {
+            for thread in 0..8u64 {
+                let (tt, hits) = (&tt, &hits);
+                scope.spawn(move || {
+                    let mut seed = thread;
+                    for _ in 0..200_000 {
+                        seed = splitmix64(seed);
+                        let key = 1 + seed % 16;
+                        if seed & 1 << 40 != 0 {
+                            tt.write(key, expected(key, (seed >> 48) as u8));
+                        } else if let Some(entry) = tt.probe(key) {
+                            assert_eq!(entry, expected(key, entry.depth));
+                            hits.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                });
+            }
+        });
+        assert!(hits.load(Ordering::Relaxed) > 0);
+    }
+
+    // The Hash option is in megabytes, and the table fills them exactly
+    #[test]
+    fn size_in_megabytes() {
+        assert_eq!(std::mem::size_of::<Cluster>(), CLUSTER_SIZE * std::mem::size_of::<Slot>());
+        for megabytes in [1, 256] {
+            let tt = TT::with_megabytes(megabytes);
+            assert_eq!(tt.tt.len() * std::mem::size_of::<Cluster>(), megabytes << 20);
+        }
+    }
+
+    // Knights going out and back form a cycle of best moves, which the PV follows only once. A stored
+    // move that is not legal ends the PV.
+    #[test]
+    fn extract_pv_cycle() {
+        let start = shakmaty::Chess::default();
+        let tt = TT::new(1 << 10);
+        let mut pos = start.clone();
+        for uci in ["g1f3", "g8f6", "f3g1", "f6g8"] {
+            let mv = uci.parse::<shakmaty::uci::UciMove>().unwrap().to_move(&pos).unwrap();
+            let key = pos.zobrist_hash::<Zobrist64>(EnPassantMode::Legal).0;
+            tt.write(key, TTEntry { mv: encode_move(&mv), value: 0, eval: None, depth: 1, score_type: ScoreType::Exact });
+            pos.play_unchecked(&mv);
+        }
+        let uci = |line: Vec<Move>| line.iter().map(|m| m.to_uci(shakmaty::CastlingMode::Standard).to_string()).collect::<Vec<_>>();
+        assert_eq!(uci(tt.extract_pv(&start, 100)), ["g1f3", "g8f6", "f3g1", "f6g8"]);
+        assert_eq!(tt.extract_pv(&start, 3).len(), 3);
+        // e1e8 is not legal after g1f3
+        let first = "g1f3".parse::<shakmaty::uci::UciMove>().unwrap().to_move(&start).unwrap();
+        let key = start.clone().play(&first).unwrap().zobrist_hash::<Zobrist64>(EnPassantMode::Legal).0;
+        tt.write(key, TTEntry { mv: 4 | 60 << 6, value: 0, eval: None, depth: 2, score_type: ScoreType::Exact });
+        assert_eq!(uci(tt.extract_pv(&start, 100)), ["g1f3"]);
+    }
+
+    // A move stored in the TT is found again among the legal moves, and no other move matches it
+    fn check_moves(fen: &str, mode: shakmaty::CastlingMode, stored: &[&str]) {
+        let fen: shakmaty::fen::Fen = fen.parse().unwrap();
+        let pos: shakmaty::Chess = fen.into_position(mode).unwrap();
+        let moves = pos.legal_moves();
+        for (i, a) in moves.iter().enumerate() {
+            for b in &moves[i + 1..] {
+                assert_ne!(encode_move(a), encode_move(b), "{a:?} {b:?}");
+            }
+        }
+        for uci in stored {
+            let mv = uci.parse::<shakmaty::uci::UciMove>().unwrap().to_move(&pos).expect(uci);
+            let tt = TT::new(CLUSTER_SIZE);
+            let entry = TTEntry { mv: encode_move(&mv), value: 0, eval: None, depth: 1, score_type: ScoreType::Exact };
+            tt.write(1, entry);
+            assert_eq!(tt.get(&moves, 1).and_then(|tte| tte.best_move(&moves)), Some(&mv), "{uci}");
+            assert_eq!(moves.iter().filter(|m| move_match_tt(m, &entry)).count(), 1, "{uci}");
+        }
+    }
+
+    #[test]
+    fn promotions() {
+        let moves = ["a7a8q", "a7a8r", "a7a8b", "a7a8n", "a7b8q", "a7b8r", "a7b8b", "a7b8n"];
+        check_moves("1r5k/P7/8/8/8/8/8/K7 w - - 0 1", shakmaty::CastlingMode::Standard, &moves);
+        let moves = ["h2h1q", "h2h1r", "h2h1b", "h2h1n", "h2g1q", "h2g1r", "h2g1b", "h2g1n"];
+        check_moves("k7/8/8/8/8/8/7p/K5R1 b - - 0 1", shakmaty::CastlingMode::Standard, &moves);
+    }
+
+    #[test]
+    fn castling() {
+        let standard = shakmaty::CastlingMode::Standard;
+        check_moves("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1", standard, &["e1g1", "e1c1"]);
+        check_moves("r3k2r/8/8/8/8/8/8/R3K2R b KQkq - 0 1", standard, &["e8g8", "e8c8"]);
+        // In Chess960, castling is sent as the king taking its rook, and the king may not move at all
+        let chess960 = shakmaty::CastlingMode::Chess960;
+        check_moves("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1", chess960, &["e1h1", "e1a1"]);
+        check_moves("2k5/8/8/8/8/8/8/1RK3R1 w KQ - 0 1", chess960, &["c1g1", "c1b1"]);
+        check_moves("1rk3r1/8/8/8/8/8/8/2K5 b kq - 0 1", chess960, &["c8g8", "c8b8"]);
+        check_moves("4k3/8/8/8/8/8/8/RK4R1 w KQ - 0 1", chess960, &["b1g1", "b1a1"]);
+    }
+}
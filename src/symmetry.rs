@@ -0,0 +1,96 @@
+use shakmaty::{CastlingMode, Chess, EnPassantMode, Position, fen::Fen};
+
+use crate::{
+    bench::POSITIONS,
+    eval::{eval, lazy_eval, nnue},
+    util::random::splitmix64,
+};
+
+// Evaluation symmetry check
+// Mirroring a position (flipping the board vertically and swapping the colors and the side to move)
+// gives a position that is the same for the other side, so it must get exactly the same evaluation.
+// A term that differs is a sign or indexing bug. Checked on random games from the bench positions.
+// Given a network file, the NNUE evaluation is checked instead, and its output layer is also
+// compared with the scalar reference implementation.
+
+const GAMES_PER_POSITION: u64 = 200;
+const MAX_GAME_PLIES: usize = 120;
+
+fn mirrored(position: &Chess) -> Chess {
+    let setup = position.clone().into_setup(EnPassantMode::Legal).into_mirrored();
+    setup.position(CastlingMode::Standard).expect("a mirrored legal position is legal")
+}
+
+// Checks a position, printing it if its mirror is evaluated differently; returns whether it passed
+fn check(position: &Chess) -> bool {
+    let mirror = mirrored(position);
+    let (score, mirror_score) = (eval(position), eval(&mirror));
+    let (lazy, mirror_lazy) = (lazy_eval(position), lazy_eval(&mirror));
+    let scalar = nnue::evaluate_scalar(position);
+    if score == mirror_score && lazy == mirror_lazy && scalar.is_none_or(|scalar| scalar == score) {
+        return true;
+    }
+    println!(
+        "{}: eval {} mirrored {}, lazy eval {} mirrored {}, scalar NNUE {:?}",
+        Fen::from_position(position.clone(), EnPassantMode::Legal),
+        score,
+        mirror_score,
+        lazy,
+        mirror_lazy,
+        scalar
+    );
+    false
+}
+
+// Checks the positions of random games from each bench position; returns the number of positions
+// checked and the number that failed
+fn check_games(games_per_position: u64) -> (u64, u64) {
+    let mut checked = 0u64;
+    let mut failed = 0u64;
+    let mut seed = 0;
+    for (fen, _) in POSITIONS {
+        let fen: Fen = fen.parse().unwrap();
+        let start: Chess = fen.into_position(CastlingMode::Standard).unwrap();
+        for _ in 0..games_per_position {
+            let mut position = start.clone();
+            for _ in 0..MAX_GAME_PLIES {
+                checked += 1;
+                failed += !check(&position) as u64;
+                let moves = position.legal_moves();
+                if moves.is_empty() {
+                    break;
+                }
+                seed = splitmix64(seed);
+                position.play_unchecked(&moves[(seed % moves.len() as u64) as usize]);
+            }
+        }
+    }
+    (checked, failed)
+}
+
+// The extended check, for the `symmetry` command; the tests only play a few games per position
+pub fn symmetry(network: Option<&str>) {
+    if let Some(path) = network
+        && let Err(e) = nnue::load(path)
+    {
+        println!("could not load {path}: {e}");
+        std::process::exit(1);
+    }
+    let (checked, failed) = check_games(GAMES_PER_POSITION);
+    println!("Checked {} positions, {} asymmetric", checked, failed);
+    if failed > 0 {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classical_eval_is_symmetric() {
+        let (checked, failed) = check_games(20);
+        assert!(checked > 20 * POSITIONS.len() as u64);
+        assert_eq!(failed, 0, "{failed} of {checked} positions are evaluated differently when mirrored");
+    }
+}
@@ -35,7 +35,9 @@ impl TimeControl {
                 moves_to_go: tc.moves_to_go.map(|x| x.get()),
             })
         } else {
-            None
+            // A mate in N takes 2N - 1 plies; leave room for reductions along the mating line. The
+            // search stops early once the mate is found.
+            tc.mate.map(|mate| TimeControl::FixedDepth(4 * mate))
         }
     }
 }
@@ -43,7 +45,7 @@ impl TimeControl {
 pub enum Deadline {
     Depth(usize),
     Nodes(usize),
-    Time(Instant, Instant), // soft & hard deadlines
+    Time(Instant, Instant, Instant), // start, soft & hard deadlines
     None,
 }
 
@@ -55,7 +57,7 @@ impl Deadline {
             TimeControl::FixedTime(t) => {
                 let soft = start + std::time::Duration::from_millis((*t as u64).max(100) - 100);
                 let hard = start + std::time::Duration::from_millis((*t as u64).max(25) - 25);
-                Deadline::Time(soft, hard)
+                Deadline::Time(start, soft, hard)
             },
             TimeControl::Infinite => Deadline::None,
             TimeControl::Clock { time_ms, increment_ms, moves_to_go } => {
@@ -64,23 +66,33 @@ impl Deadline {
                 let time_hard= time_ms / (moves / 2 + 1) + increment_ms;
                 let soft_time = start + std::time::Duration::from_millis(time_soft as u64);
                 let hard_time = start + std::time::Duration::from_millis(time_hard as u64);
-                Deadline::Time(soft_time, hard_time)
+                Deadline::Time(start, soft_time, hard_time)
             },
         }
     }
-    pub fn check_soft(&self, now: Instant, nodes_searched: usize, depth_searched: usize) -> bool {
+    // The soft deadline can be scaled by the search, e.g. to think longer when unsure about the best move
+    pub fn check_soft(&self, now: Instant, nodes_searched: usize, depth_searched: usize, scale: f64) -> bool {
         match self {
             Deadline::Depth(d) => depth_searched >= *d,
             Deadline::Nodes(n) => nodes_searched >= *n,
-            Deadline::Time(soft, _) => now >= *soft,
+            Deadline::Time(start, soft, _) => now >= *start + soft.duration_since(*start).mul_f64(scale),
             Deadline::None => false,
         }
     }
+    // Time left until the (scaled) soft deadline, if there is one
+    pub fn soft_remaining(&self, now: Instant, scale: f64) -> Option<std::time::Duration> {
+        match self {
+            Deadline::Time(start, soft, _) => {
+                Some((*start + soft.duration_since(*start).mul_f64(scale)).saturating_duration_since(now))
+            }
+            _ => None,
+        }
+    }
     pub fn check_hard(&self, now: Instant, nodes_searched: usize) -> bool {
         match self {
             Deadline::Depth(_) => false, // depth limit is a soft deadline
             Deadline::Nodes(n) => nodes_searched >= *n,
-            Deadline::Time(_, hard) => now >= *hard,
+            Deadline::Time(_, _, hard) => now >= *hard,
             Deadline::None => false,
         }
     }
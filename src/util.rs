@@ -1 +1,2 @@
-pub mod sort;
\ No newline at end of file
+pub mod random;
+pub mod sort;
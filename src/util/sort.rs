@@ -1,48 +1,42 @@
-pub struct LazySort<'a, T, C> {
-    data: &'a [T],
-    keys: Vec<C>,
-    seen: Vec<bool>,
+use arrayvec::ArrayVec;
+
+// Lazy selection sort: finds the next best item only when asked for it, which is cheaper than
+// a full sort when only the first few items end up being used (e.g. after a beta cutoff)
+pub struct LazySort<T, C> {
+    items: ArrayVec<(T, C), 256>,
+    seen: usize,
 }
 
-impl <'a, T, C: Ord> LazySort<'a, T, C> {
-    pub fn new<F>(data: &'a [T], mut key_fn: F) -> Self
+impl<T, C: Ord> LazySort<T, C> {
+    pub fn new<I, F>(data: I, mut key_fn: F) -> Self
     where
+        I: IntoIterator<Item = T>,
         F: FnMut(&T) -> C,
     {
-        let keys = data.iter().map(|item| key_fn(item)).collect();
-        let seen = vec![false; data.len()];
-        Self { data, keys, seen}
-    }
-
-    pub fn seen(&self) -> impl Iterator<Item=&'a T> {
-        self.data.iter().zip(self.seen.iter()).filter_map(|(item, &s)| if s { Some(item) } else { None })
+        let items = data.into_iter().map(|item| {
+            let key = key_fn(&item);
+            (item, key)
+        }).collect();
+        Self { items, seen: 0 }
     }
 }
 
-impl<'a, T, C> Iterator for LazySort<'a, T, C> where C: PartialOrd + Copy {
-    type Item = (usize, C, &'a T);
+impl<T, C> Iterator for LazySort<T, C> where C: PartialOrd + Copy {
+    type Item = (usize, C, T);
 
     fn next(&mut self) -> Option<Self::Item> {
         let mut best: Option<usize> = None;
-        let mut index = 0;
-        for i in 0..self.data.len() {
-            if self.seen[i] {
-                index += 1;
-                continue;
-            }
+        for i in 0..self.items.len() {
             if let Some(best_idx) = best {
-                if self.keys[i] < self.keys[best_idx] {
+                if self.items[i].1 < self.items[best_idx].1 {
                     best = Some(i);
                 }
             } else {
                 best = Some(i);
             }
         }
-        if let Some(best_idx) = best {
-            self.seen[best_idx] = true;
-            Some((index, self.keys[best_idx], &self.data[best_idx]))
-        } else {
-            None
-        }
+        let (item, key) = self.items.swap_remove(best?);
+        self.seen += 1;
+        Some((self.seen - 1, key, item))
     }
-}
\ No newline at end of file
+}